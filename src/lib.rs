@@ -0,0 +1,11 @@
+//! Onset detection on live audio and light services that react to it.
+//!
+//! The binary wires these together from a config file,
+//! the re-exports below are what is needed to embed the detection in another application.
+
+/// Internals of the binary, not part of the stable API
+#[doc(hidden)]
+pub mod utils;
+
+pub use utils::audioprocessing::{Buffer, Onset, OnsetDetector, ProcessingSettings};
+pub use utils::lights::{color, envelope, LightService};
@@ -1,19 +1,139 @@
-mod utils;
-
 use std::error::Error;
 
-use crate::utils::audiodevices::{create_monitor_stream, get_output_devices};
-use crate::utils::config::{Config, ConfigError};
-use log::{debug, error, info, warn};
+use clap::Parser;
+use music_sync::utils::audiodevices::{
+    create_monitor_stream, get_monitor_sources, get_output_devices,
+};
+use music_sync::utils::benchmark;
+use music_sync::utils::config::{Config, ConfigError, LogFormat, OnsetDetector};
+use music_sync::utils::keyboard::Controls;
+use music_sync::utils::learned;
+use music_sync::utils::lights::{serialize, LightService};
+use music_sync::utils::plot;
+use music_sync::utils::simulation;
+use tokio::sync::{mpsc, watch};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Write a config template with all default values to FILE and exit
+    #[arg(long, value_name = "FILE")]
+    generate_config: Option<String>,
+
+    /// Print all available audio devices and exit
+    #[arg(long)]
+    list_devices: bool,
+
+    /// Play back onsets saved with `serialize_onsets` to the configured light services
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+
+    /// Run the detection on a synthetic click track instead of an audio device
+    #[arg(long)]
+    simulate: bool,
+
+    /// Draw onsets saved with `serialize_onsets` to an image and exit
+    #[arg(long, num_args = 2, value_names = ["ONSETS", "IMAGE"])]
+    plot: Option<Vec<String>>,
+
+    /// Convert onsets saved with `serialize_onsets` to CSV, or to JAMS if OUTPUT ends with .jams, and exit
+    #[arg(long, num_args = 2, value_names = ["ONSETS", "OUTPUT"])]
+    export: Option<Vec<String>>,
+
+    /// Milliseconds from the start of the capture that are plotted
+    #[arg(long, value_name = "MS", default_value_t = plot::DEFAULT_TIME_WINDOW)]
+    time_window: u128,
+
+    /// Suggest SpecFlux thresholds for an audio FILE and print them as TOML
+    #[arg(long, value_name = "FILE")]
+    calibrate: Option<String>,
+
+    /// Onsets per second every band should detect when calibrating
+    #[arg(long, value_name = "RATE", default_value_t = 2.0)]
+    onset_rate: f32,
+
+    /// Measure how long the configured onset detector takes per frame of an audio FILE
+    #[arg(long, value_name = "FILE")]
+    benchmark: Option<String>,
+}
+
+/// Logs to stderr, so the console output and the monitor keep stdout to themselves
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::WARN.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::formatted_builder()
-        .filter_level(log::LevelFilter::Warn)
-        .parse_default_env()
-        .init();
+    // Loaded before the arguments are handled, the log format has to be known for their errors
+    let config = Config::load("./config.toml");
+    init_logging(
+        config
+            .as_ref()
+            .map(|config| config.log_format)
+            .unwrap_or_default(),
+    );
+
+    let args = Args::parse();
+
+    if let Some(path) = args.generate_config {
+        match Config::generate_template(&path) {
+            Ok(()) => println!("Config template written to {path}"),
+            Err(e) => error!("Error writing config template to {path}: {e}"),
+        }
+        return;
+    }
+
+    if let Some([onsets, image]) = args.plot.as_deref() {
+        let result = serialize::OnsetContainer::load(onsets)
+            .and_then(|container| plot::plot_container(&container, args.time_window, image));
+        match result {
+            Ok(()) => println!("Plot written to {image}"),
+            Err(e) => error!("Error plotting {onsets}: {e}"),
+        }
+        return;
+    }
+
+    if let Some([onsets, output]) = args.export.as_deref() {
+        let result = serialize::OnsetContainer::load(onsets).and_then(|container| {
+            if output.ends_with(".jams") {
+                container.export_jams(output)
+            } else {
+                Ok(container.export_csv(output)?)
+            }
+        });
+        match result {
+            Ok(()) => println!("Onsets exported to {output}"),
+            Err(e) => error!("Error exporting {onsets}: {e}"),
+        }
+        return;
+    }
 
-    let config = match Config::load("./config.toml") {
+    if args.list_devices {
+        let outputs = get_output_devices();
+        for name in &outputs {
+            println!("{name}");
+        }
+        for name in get_monitor_sources() {
+            if !outputs.contains(&name) {
+                println!("{name}");
+            }
+        }
+        return;
+    }
+
+    let config = match config {
         Ok(loaded_config) => loaded_config,
         Err(e) => {
             error!("Error loading config");
@@ -30,7 +150,48 @@ async fn main() {
         }
     };
 
-    let lightservices = match config.initialize_lightservices().await {
+    if let Some(path) = args.benchmark {
+        match benchmark::benchmark_file(&path, &config) {
+            Ok(report) => println!("{report}"),
+            Err(e) => error!("Error benchmarking with {path}: {e}"),
+        }
+        return;
+    }
+
+    if let Some(path) = args.calibrate {
+        let settings = match &config.onset_detector {
+            OnsetDetector::SpecFlux(settings) => settings.clone(),
+            _ => {
+                warn!("Calibration only supports SpecFlux, starting from its default settings");
+                Default::default()
+            }
+        };
+        let thresholds = match benchmark::calibrate_file(
+            &path,
+            config.audio_processing,
+            settings,
+            args.onset_rate,
+        ) {
+            Ok(thresholds) => thresholds,
+            Err(e) => {
+                error!("Error calibrating with {path}: {e}");
+                return;
+            }
+        };
+        match benchmark::toml_snippet(thresholds) {
+            Ok(snippet) => println!("{snippet}"),
+            Err(e) => error!("{e}"),
+        }
+        // The first device uses the calibrated thresholds until the next calibration
+        let device = &config.audio_device.names()[0];
+        if let Err(e) = learned::save_thresholds(device, thresholds) {
+            error!("Error saving the calibrated thresholds: {e}");
+        }
+        return;
+    }
+
+    let controls = config.keyboard_control.then(Controls::default);
+    let lightservices = match config.initialize_lightservices(controls.as_ref()).await {
         Ok(vec) => vec,
         Err(e) => {
             error!("{e}");
@@ -41,42 +202,96 @@ async fn main() {
         }
     };
 
-    let onset_detector = config.initialize_onset_detector();
+    if let Some(path) = args.replay {
+        let mut lightservices: Vec<_> = lightservices.into_iter().flatten().collect();
+        if let Err(e) = serialize::replay(&path, &mut lightservices).await {
+            error!("Error replaying {path}: {e}");
+        }
+        lightservices.shutdown();
+        return;
+    }
 
-    let stream = match create_monitor_stream(
-        &config.audio_device,
-        config.audio_processing,
-        onset_detector,
-        lightservices,
-    ) {
-        Ok(stream) => stream,
-        Err(e) => {
-            match e {
-                cpal::BuildStreamError::DeviceNotAvailable => {
-                    error!("Device not found: {}", config.audio_device);
-                    warn!("Available devices:");
-                    for name in get_output_devices() {
-                        warn!("{name}");
+    if args.simulate {
+        let lightservices: Vec<_> = lightservices.into_iter().flatten().collect();
+        println!("Stop simulation with CTRL-C");
+        tokio::select! {
+            _ = simulation::simulate(
+                config.simulation,
+                config.audio_processing,
+                || config.initialize_onset_detector(),
+                lightservices,
+            ) => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        return;
+    }
+
+    let mut streams = Vec::new();
+    let mut profile_senders = Vec::new();
+    for (device_name, lightservices) in config.audio_device.names().iter().zip(lightservices) {
+        let device_config = config.with_learned_thresholds(device_name);
+        let profiles = (!config.profile.is_empty()).then(|| {
+            let (sender, receiver) = watch::channel(None);
+            profile_senders.push((device_name.clone(), sender));
+            receiver
+        });
+        let stream = match create_monitor_stream(
+            device_name,
+            config.audio_processing,
+            || device_config.initialize_onset_detector(),
+            lightservices,
+            profiles,
+        ) {
+            Ok(stream) => stream,
+            Err(e) => {
+                match e {
+                    cpal::BuildStreamError::DeviceNotAvailable => {
+                        error!("Device not found: {}", device_name);
+                        warn!("Available devices:");
+                        for name in get_output_devices() {
+                            warn!("{name}");
+                        }
                     }
-                }
-                _ => {
-                    error!("{e}");
-                    if let Some(e) = e.source() {
-                        debug!("{e}");
+                    _ => {
+                        error!("{e}");
+                        if let Some(e) = e.source() {
+                            debug!("{e}");
+                        }
                     }
-                }
-            };
-            return;
-        }
-    };
+                };
+                return;
+            }
+        };
+        streams.push(stream);
+    }
 
-    println!("Stop sync with CTRL-C");
+    let (next_profile, profile_requests) = mpsc::unbounded_channel();
+    let mut keyboard = controls.and_then(|controls| match controls.listen(next_profile.clone()) {
+        Ok(keyboard) => Some(keyboard),
+        Err(e) => {
+            warn!("Keyboard control is not used: {e}");
+            None
+        }
+    });
+    if keyboard.is_none() {
+        println!("Stop sync with CTRL-C");
+    }
 
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Error setting Ctrl-C handler");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Error setting Ctrl-C handler"),
+        _ = async {
+            match &mut keyboard {
+                Some(keyboard) => keyboard.quit().await,
+                None => std::future::pending().await,
+            }
+        } => {}
+        _ = config.follow_profiles(profile_senders, profile_requests) => {}
+    }
+    drop(keyboard);
 
     info!("Shutting down");
-    drop(stream);
+    for stream in streams {
+        stream.stop().await;
+    }
     info!("Shutdown complete");
 }
@@ -4,6 +4,9 @@ pub mod audioprocessing;
 #[allow(dead_code)]
 pub mod benchmark;
 pub mod config;
+pub mod keyboard;
+pub mod learned;
 pub mod lights;
 #[allow(dead_code)]
 pub mod plot;
+pub mod simulation;
@@ -1,29 +1,241 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::utils::audioprocessing::{Buffer, ProcessingSettings};
-use crate::utils::lights::LightService;
+use crate::utils::audioprocessing::{Buffer, Onset, ProcessingSettings};
+use crate::utils::learned;
+use crate::utils::lights::{LightService, Reactions};
 use cpal::traits::StreamTrait;
 use cpal::{
     self,
     traits::{DeviceTrait, HostTrait},
-    BuildStreamError, StreamConfig,
+    BuildStreamError, StreamConfig, StreamError,
+};
+use rtrb::{Consumer, RingBuffer};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, MissedTickBehavior},
 };
-use log::{debug, error, trace};
+use tracing::{debug, error, info, trace, trace_span, warn, warn_span};
 
 use crate::utils::audioprocessing::OnsetDetector;
 
-pub fn create_monitor_stream(
+/// Seconds of audio the ring buffer between the device and the detection can hold
+const RING_BUFFER_SECONDS: f32 = 0.5;
+/// Wait before reopening a failed audio device, doubled after every failed attempt
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+/// Time the loudness estimate of [`NormalizedOutput`] takes to follow a change in volume
+const LOUDNESS_TIME_CONSTANT: Duration = Duration::from_secs(10);
+/// Loudness the onset strengths are normalized to
+const TARGET_LOUDNESS: f32 = 0.2;
+/// Frames quieter than this don't update the loudness, so pauses don't boost the next song
+const LOUDNESS_FLOOR: f32 = 0.005;
+
+/// Settings of a profile the running detection switches to
+#[derive(Clone)]
+pub struct ActiveProfile {
+    pub onset_detector: Arc<dyn Fn() -> Box<dyn OnsetDetector + Send> + Send + Sync>,
+    /// Replaces the reactions of the light services, `None` restores the configured ones
+    pub reactions: Option<Reactions>,
+}
+
+pub type ProfileReceiver = watch::Receiver<Option<ActiveProfile>>;
+
+/// Capture of an audio device with the detection running in a separate tokio task.
+/// The cpal stream lives on its own thread, which rebuilds it when the device fails.
+pub struct MonitorStream {
+    events: mpsc::Sender<CaptureEvent>,
+    capture: Option<thread::JoinHandle<()>>,
+    detection: Option<JoinHandle<()>>,
+}
+
+impl MonitorStream {
+    /// Stops the capture and waits until the light services are shut down
+    pub async fn stop(mut self) {
+        let _ = self.events.send(CaptureEvent::Stop);
+        if let Some(capture) = self.capture.take() {
+            let _ = tokio::task::spawn_blocking(move || capture.join()).await;
+        }
+        if let Some(detection) = self.detection.take() {
+            let _ = detection.await;
+        }
+    }
+}
+
+impl Drop for MonitorStream {
+    fn drop(&mut self) {
+        let _ = self.events.send(CaptureEvent::Stop);
+    }
+}
+
+enum CaptureEvent {
+    Failed(StreamError),
+    Stop,
+}
+
+/// Starts capturing `device_name`, the detection runs until the stream is stopped or dropped.
+/// If the device fails it is reopened with increasing backoff, an empty `device_name`
+/// picks the default device again, which may have changed in the meantime.
+pub fn create_monitor_stream<D: OnsetDetector + Send + 'static>(
     device_name: &str,
     processing_settings: ProcessingSettings,
-    onset_detector: impl OnsetDetector + Send + 'static,
+    onset_detector: impl Fn() -> D,
     lightservices: Vec<Box<dyn LightService + Send>>,
-) -> Result<cpal::Stream, BuildStreamError> {
+    profiles: Option<ProfileReceiver>,
+) -> Result<MonitorStream, BuildStreamError> {
+    let configured_name = device_name.to_owned();
+    let sample_rate = processing_settings.sample_rate;
+
+    let (events, event_receiver) = mpsc::channel();
+    let (consumers, mut consumer_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let (started, started_receiver) = mpsc::channel();
+    let capture = {
+        let device_name = configured_name.clone();
+        let events = events.clone();
+        thread::spawn(move || {
+            run_capture(
+                &device_name,
+                sample_rate,
+                (events, event_receiver),
+                consumers,
+                started,
+            )
+        })
+    };
+    let channels = started_receiver
+        .recv()
+        .unwrap_or(Err(BuildStreamError::DeviceNotAvailable))?;
+
+    let mut process = monitor_callback(
+        channels,
+        processing_settings,
+        onset_detector,
+        lightservices,
+        Some(configured_name.clone()),
+        profiles,
+    );
+
+    let hop_duration = Duration::from_secs_f64(
+        processing_settings.hop_size as f64 / processing_settings.sample_rate as f64,
+    );
+    let detection = tokio::spawn(async move {
+        let mut timer = interval(hop_duration);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Every rebuilt stream comes with a new ring buffer
+        while let Some(mut consumer) = consumer_receiver.recv().await {
+            while !consumer.is_abandoned() {
+                timer.tick().await;
+                let Ok(chunk) = consumer.read_chunk(consumer.slots()) else {
+                    continue;
+                };
+                let (first, second) = chunk.as_slices();
+                process(first);
+                process(second);
+                chunk.commit_all();
+            }
+        }
+        // Shutting down the light services waits for their pollers, which run on the runtime
+        let _ = tokio::task::spawn_blocking(move || drop(process)).await;
+        debug!("Detection for {configured_name} stopped");
+    });
+
+    Ok(MonitorStream {
+        events,
+        capture: Some(capture),
+        detection: Some(detection),
+    })
+}
+
+/// Owns the cpal stream, which can't be moved between threads on every platform.
+/// Sends the channel count of the first stream to `started`
+/// and the ring buffer of every stream to `consumers`.
+fn run_capture(
+    device_name: &str,
+    sample_rate: u32,
+    (event_sender, events): (mpsc::Sender<CaptureEvent>, mpsc::Receiver<CaptureEvent>),
+    consumers: tokio::sync::mpsc::UnboundedSender<Consumer<f32>>,
+    started: mpsc::Sender<Result<u16, BuildStreamError>>,
+) {
+    let display_name = if device_name.trim().is_empty() {
+        "the default audio device"
+    } else {
+        device_name
+    };
+
+    let opened = open_device(device_name, sample_rate, None).and_then(|(device, config)| {
+        let (stream, consumer) = start_capture(&device, &config, &event_sender)?;
+        Ok((stream, consumer, config.channels))
+    });
+    let (mut stream, channels) = match opened {
+        Ok((stream, consumer, channels)) => {
+            let _ = consumers.send(consumer);
+            let _ = started.send(Ok(channels));
+            (Some(stream), channels)
+        }
+        Err(e) => {
+            let _ = started.send(Err(e));
+            return;
+        }
+    };
+
+    loop {
+        match events.recv() {
+            Ok(CaptureEvent::Failed(e)) => error!("Audio stream of {display_name} failed: {e}"),
+            Ok(CaptureEvent::Stop) | Err(_) => return,
+        }
+        drop(stream.take());
+
+        let mut backoff = RECONNECT_BACKOFF;
+        let mut attempt = 1;
+        while stream.is_none() {
+            match events.recv_timeout(backoff) {
+                Ok(CaptureEvent::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                // Errors the old stream reported before it was dropped
+                Ok(CaptureEvent::Failed(_)) => continue,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            info!("Reconnecting to {display_name}, attempt {attempt}");
+            // The ring buffer must match the channel count the detection was built for
+            let reopened = open_device(device_name, sample_rate, Some(channels))
+                .and_then(|(device, config)| start_capture(&device, &config, &event_sender));
+            match reopened {
+                Ok((new_stream, consumer)) => {
+                    warn!("Reconnected to {display_name}");
+                    let _ = consumers.send(consumer);
+                    stream = Some(new_stream);
+                }
+                Err(e) => {
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    warn!(
+                        "Reconnecting to {display_name} failed: {e}, retrying in {}s",
+                        backoff.as_secs()
+                    );
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Device named `device_name`, or the default device for an empty name,
+/// with the stream config to capture it with `channels` or its own channel count
+fn open_device(
+    device_name: &str,
+    sample_rate: u32,
+    channels: Option<u16>,
+) -> Result<(cpal::Device, StreamConfig), BuildStreamError> {
     let device_name = if device_name.trim().is_empty() {
-        cpal::default_host()
-            .default_output_device()
-            .ok_or(BuildStreamError::DeviceNotAvailable)?
-            .name()
-            .map_err(|_| BuildStreamError::DeviceNotAvailable)?
+        default_device_name()?
     } else {
         device_name.to_owned()
     };
@@ -36,71 +248,376 @@ pub fn create_monitor_stream(
         })
         .ok_or(BuildStreamError::DeviceNotAvailable)?;
 
+    // Monitor sources are input devices without an output config
     let audio_cfg = out
         .default_output_config()
-        .expect("No default output config found");
+        .or_else(|_| out.default_input_config())
+        .map_err(|_| BuildStreamError::StreamConfigNotSupported)?;
 
-    let channels = audio_cfg.channels();
+    debug!(
+        "Default output device: {:?}",
+        out.name().unwrap_or_default()
+    );
+    debug!(
+        "Default output sample format: {:?}",
+        audio_cfg.sample_format()
+    );
+    debug!("Default output buffer size: {:?}", audio_cfg.buffer_size());
+    debug!("Default output sample rate: {:?}", audio_cfg.sample_rate());
+    debug!("Default output channels: {:?}", audio_cfg.channels());
 
     let config = StreamConfig {
-        channels,
-        sample_rate: cpal::SampleRate(processing_settings.sample_rate),
+        channels: channels.unwrap_or(audio_cfg.channels()),
+        sample_rate: cpal::SampleRate(sample_rate),
         buffer_size: cpal::BufferSize::Default,
     };
+    Ok((out, config))
+}
 
-    let mut onset_detector = onset_detector;
-    let mut lightservices = lightservices;
+/// Starts a stream that copies the samples into a new ring buffer and reports its errors to `events`
+fn start_capture(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    events: &mpsc::Sender<CaptureEvent>,
+) -> Result<(cpal::Stream, Consumer<f32>), BuildStreamError> {
+    // The audio callback only copies into the ring buffer, framing and detection
+    // run at the hop rate independent of the buffer sizes the device delivers
+    let capacity =
+        (config.sample_rate.0 as f32 * RING_BUFFER_SECONDS) as usize * config.channels as usize;
+    let (mut producer, consumer) = RingBuffer::<f32>::new(capacity);
+
+    let events = events.clone();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[f32], _| {
+            // Samples that don't fit are dropped, the detection can't keep up anyway
+            let n = data.len().min(producer.slots());
+            if let Ok(mut chunk) = producer.write_chunk(n) {
+                let (first, second) = chunk.as_mut_slices();
+                let (head, tail) = data[..n].split_at(first.len());
+                first.copy_from_slice(head);
+                second.copy_from_slice(tail);
+                chunk.commit_all();
+            }
+        },
+        move |err| {
+            let _ = events.send(CaptureEvent::Failed(err));
+        },
+        None,
+    )?;
+    stream
+        .play()
+        .map_err(|_| BuildStreamError::StreamConfigNotSupported)?;
+    Ok((stream, consumer))
+}
+
+/// Shuts the light services down once the detection stops
+struct ShutdownOnDrop(Vec<Box<dyn LightService + Send>>);
+
+impl Deref for ShutdownOnDrop {
+    type Target = [Box<dyn LightService + Send>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ShutdownOnDrop {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// Holds back onsets until the latency offset after their detection has passed
+struct OnsetDelay {
+    delay: Duration,
+    /// Time each batch is due, in detection order, with the input channel of per channel onsets
+    queue: VecDeque<(Instant, Option<usize>, Vec<Onset>)>,
+}
+
+impl OnsetDelay {
+    fn init(delay: Duration) -> OnsetDelay {
+        OnsetDelay {
+            delay,
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn push(&mut self, channel: Option<usize>, onsets: Vec<Onset>) {
+        self.queue
+            .push_back((Instant::now() + self.delay, channel, onsets));
+    }
+
+    /// Passes all onsets that are due to the light services
+    fn dispatch(&mut self, lightservices: &mut [Box<dyn LightService + Send>]) {
+        let now = Instant::now();
+        while self.queue.front().is_some_and(|(due, _, _)| *due <= now) {
+            let Some((_, channel, onsets)) = self.queue.pop_front() else {
+                break;
+            };
+            match channel {
+                Some(channel) => lightservices.process_channel_onsets(channel, &onsets),
+                None => lightservices.process_onsets(&onsets),
+            }
+        }
+    }
+}
+
+/// Splits the incoming interleaved samples into hops and runs
+/// the onset detection and light services on every hop.
+/// The samples can arrive in chunks of any size, the frame is kept in a preallocated buffer.
+/// The noise floor learned for `device` is restored and saved between runs.
+/// Profiles sent through `profiles` replace the detectors between two hops.
+pub fn monitor_callback<D: OnsetDetector + Send + 'static>(
+    channels: u16,
+    processing_settings: ProcessingSettings,
+    onset_detector: impl Fn() -> D,
+    lightservices: Vec<Box<dyn LightService + Send>>,
+    device: Option<String>,
+    mut profiles: Option<ProfileReceiver>,
+) -> impl FnMut(&[f32]) + Send + 'static {
+    let boxed = || Box::new(onset_detector()) as Box<dyn OnsetDetector + Send>;
+    let mut channel_detectors: Vec<_> = if processing_settings.per_channel {
+        (0..channels).map(|_| boxed()).collect()
+    } else {
+        Vec::new()
+    };
+    let mut onset_detector = boxed();
+    let mut lightservices = ShutdownOnDrop(lightservices);
+    let mut delay = (!processing_settings.latency_offset.is_zero())
+        .then(|| OnsetDelay::init(processing_settings.latency_offset));
 
     let mut detection_buffer = Buffer::init(channels, &processing_settings);
 
+    // Enabled at the default level like the spans of the lights, so warnings name their device
+    let stream_span = warn_span!("audio", device = device.as_deref().unwrap_or_default());
+
+    let mut unsaved_device = None;
+    if let (Some(device), true) = (device, processing_settings.adaptive_silence) {
+        match learned::load(&device).noise_floor {
+            Some(noise_floor) => {
+                info!("Using the noise floor learned in a previous run");
+                detection_buffer.restore_noise_floor(noise_floor);
+            }
+            None => unsaved_device = Some(device),
+        }
+    }
+
     let buffer_size = processing_settings.buffer_size * channels as usize;
     let hop_size = processing_settings.hop_size * channels as usize;
 
-    let mut buffer: VecDeque<f32> = VecDeque::new();
+    let mut frame = vec![0.0; buffer_size];
+    let mut filled = 0;
 
-    let outstream = out.build_input_stream(
-        &config,
-        move |data: &[f32], _| {
-            buffer.extend(data);
-            let n = (buffer.len() + hop_size).saturating_sub(buffer_size) / hop_size;
+    move |mut data: &[f32]| {
+        let _callback =
+            trace_span!(parent: &stream_span, "audio_callback", samples = data.len()).entered();
+        while !data.is_empty() {
+            let n = (buffer_size - filled).min(data.len());
+            frame[filled..filled + n].copy_from_slice(&data[..n]);
+            filled += n;
+            data = &data[n..];
+            if filled < buffer_size {
+                break;
+            }
 
-            (0..n).for_each(|_| {
-                detection_buffer.process_raw(&buffer.make_contiguous()[0..buffer_size]);
-                trace!(
-                    "RMS: {:.3}\t Peak: {:.3}",
-                    detection_buffer.rms,
-                    detection_buffer.peak
-                );
+            if let Some(profiles) = &mut profiles {
+                if profiles.has_changed().unwrap_or(false) {
+                    if let Some(profile) = profiles.borrow_and_update().clone() {
+                        onset_detector = (profile.onset_detector)();
+                        for detector in channel_detectors.iter_mut() {
+                            *detector = (profile.onset_detector)();
+                        }
+                        lightservices.set_reactions(profile.reactions);
+                    }
+                }
+            }
 
-                let onsets = onset_detector.detect(
+            detection_buffer.process_raw(&frame);
+            trace!(
+                "RMS: {:.3}\t Peak: {:.3}",
+                detection_buffer.rms,
+                detection_buffer.peak
+            );
+
+            let mut onsets = trace_span!("detection").in_scope(|| {
+                onset_detector.detect_complex(
+                    &detection_buffer.complex_bins,
                     &detection_buffer.freq_bins,
                     detection_buffer.peak,
                     detection_buffer.rms,
-                );
-                lightservices.process_onsets(&onsets);
-                lightservices.process_spectrum(&detection_buffer.freq_bins);
-                lightservices.process_samples(&detection_buffer.mono_samples);
-                lightservices.update();
-
-                buffer.drain(0..hop_size);
-            })
-        },
-        |err| error!("an error occurred on stream: {}", err),
-        None,
-    );
-    debug!("Default output device: {:?}", out.name().unwrap());
-    debug!(
-        "Default output sample format: {:?}",
-        audio_cfg.sample_format()
-    );
-    debug!("Default output buffer size: {:?}", audio_cfg.buffer_size());
-    debug!("Default output sample rate: {:?}", audio_cfg.sample_rate());
-    debug!("Default output channels: {:?}", audio_cfg.channels());
-    let stream = outstream?;
-    stream
-        .play()
-        .map_err(|_| BuildStreamError::StreamConfigNotSupported)?;
-    Ok(stream)
+                )
+            });
+            if onsets.iter().any(Onset::is_transient) {
+                if let Some(pan) = detection_buffer.pan() {
+                    onsets.push(Onset::Pan(pan));
+                }
+            }
+            match &mut delay {
+                Some(delay) => delay.push(None, onsets),
+                None => lightservices.process_onsets(&onsets),
+            }
+            for (channel, detector) in channel_detectors.iter_mut().enumerate() {
+                let onsets = trace_span!("detection", channel).in_scope(|| {
+                    detector.detect(
+                        &detection_buffer.channel_bins[channel],
+                        detection_buffer.peak,
+                        detection_buffer.channel_rms[channel],
+                    )
+                });
+                match &mut delay {
+                    Some(delay) => delay.push(Some(channel), onsets),
+                    None => lightservices.process_channel_onsets(channel, &onsets),
+                }
+            }
+            if let Some(delay) = &mut delay {
+                delay.dispatch(&mut lightservices);
+            }
+            lightservices.process_spectrum(detection_buffer.smoothed_spectrum());
+            lightservices.process_samples(&detection_buffer.mono_samples);
+            lightservices.update();
+
+            if let Some(noise_floor) = detection_buffer.learned_noise_floor() {
+                if let Some(device) = unsaved_device.take() {
+                    // Keep file access out of the audio thread
+                    std::thread::spawn(move || {
+                        if let Err(e) = learned::save_noise_floor(&device, noise_floor) {
+                            warn!("Saving the noise floor failed: {e}");
+                        }
+                    });
+                }
+            }
+
+            // Keep the overlap with the next frame
+            let keep = buffer_size.saturating_sub(hop_size);
+            frame.copy_within(buffer_size - keep.., 0);
+            filled = keep;
+        }
+    }
+}
+
+/// Scales the onset strengths by a slowly following loudness estimate,
+/// so quiet and loud songs light up about the same
+pub struct NormalizedOutput {
+    lightservices: Vec<Box<dyn LightService + Send>>,
+    loudness: f32,
+    smoothing: f32,
+    scaled: Vec<Onset>,
+}
+
+impl NormalizedOutput {
+    pub fn init(
+        lightservices: Vec<Box<dyn LightService + Send>>,
+        frame_period: Duration,
+    ) -> NormalizedOutput {
+        NormalizedOutput {
+            lightservices,
+            loudness: TARGET_LOUDNESS,
+            smoothing: (frame_period.as_secs_f32() / LOUDNESS_TIME_CONSTANT.as_secs_f32()).min(1.0),
+            scaled: Vec::new(),
+        }
+    }
+
+    /// Writes the onsets with the strengths scaled to the target loudness to `scaled`,
+    /// the detection values of `Onset::Raw` are passed on unchanged
+    fn scale(&mut self, onsets: &[Onset]) {
+        let gain = TARGET_LOUDNESS / self.loudness;
+        let scale = |strength: f32| (strength * gain).min(1.0);
+        self.scaled.clear();
+        self.scaled.extend(onsets.iter().map(|&onset| match onset {
+            Onset::Raw(_) => onset,
+            onset => onset.map_strength(scale),
+        }));
+    }
+}
+
+impl LightService for NormalizedOutput {
+    fn process_onset(&mut self, event: Onset) {
+        self.process_onsets(&[event]);
+    }
+
+    fn process_onsets(&mut self, onsets: &[Onset]) {
+        self.scale(onsets);
+        self.lightservices.process_onsets(&self.scaled);
+    }
+
+    fn process_channel_onsets(&mut self, channel: usize, onsets: &[Onset]) {
+        self.scale(onsets);
+        self.lightservices
+            .process_channel_onsets(channel, &self.scaled);
+    }
+
+    fn process_spectrum(&mut self, freq_bins: &[f32]) {
+        self.lightservices.process_spectrum(freq_bins);
+    }
+
+    fn process_samples(&mut self, samples: &[f32]) {
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+        if rms > LOUDNESS_FLOOR {
+            self.loudness += (rms - self.loudness) * self.smoothing;
+        }
+        self.lightservices.process_samples(samples);
+    }
+
+    fn update(&mut self) {
+        self.lightservices.update();
+    }
+
+    fn shutdown(&mut self) {
+        self.lightservices.shutdown();
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.lightservices.set_reactions(reactions);
+    }
+
+    fn dim(&mut self, gain: f32) {
+        self.lightservices.dim(gain);
+    }
+}
+
+/// Device captured when no `audio_device` is configured.
+/// On Linux the monitor source of the default output is preferred, because capturing
+/// an ALSA output device directly only works for a few drivers.
+fn default_device_name() -> Result<String, BuildStreamError> {
+    let output = cpal::default_host()
+        .default_output_device()
+        .ok_or(BuildStreamError::DeviceNotAvailable)?
+        .name()
+        .map_err(|_| BuildStreamError::DeviceNotAvailable)?;
+
+    if cfg!(target_os = "linux") {
+        let monitors = get_monitor_sources();
+        let monitor = monitors
+            .iter()
+            .find(|name| name.to_lowercase().contains(&output.to_lowercase()))
+            .or(monitors.first());
+        if let Some(monitor) = monitor {
+            info!("Capturing the monitor source {monitor}");
+            return Ok(monitor.clone());
+        }
+    }
+
+    Ok(output)
+}
+
+/// Input devices that capture what the sound server plays, e.g. the `.monitor` sources of PulseAudio and PipeWire.
+/// cpal only lists them on Linux when the ALSA configuration exposes them.
+/// On Windows the output devices themselves are captured through WASAPI loopback and none are listed.
+pub fn get_monitor_sources() -> Vec<String> {
+    let Ok(devices) = cpal::default_host().input_devices() else {
+        return Vec::new();
+    };
+    devices
+        .filter_map(|d| d.name().ok())
+        .filter(|name| name.to_lowercase().contains("monitor"))
+        .collect()
 }
 
 pub fn get_output_devices() -> Vec<String> {
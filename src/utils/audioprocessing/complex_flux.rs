@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    spectral_flux::ThresholdBankSettings,
+    threshold::{Advanced, DEFAULT_FRAME_PERIOD},
+    Onset, OnsetDetector,
+};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct ComplexFluxSettings {
+    #[serde(rename = "drum_crossover")]
+    pub drum_crossover: f32,
+    #[serde(rename = "hihat_crossover")]
+    pub hihat_crossover: f32,
+    pub threshold_bank_settings: ThresholdBankSettings,
+}
+
+impl Default for ComplexFluxSettings {
+    fn default() -> Self {
+        Self {
+            drum_crossover: 150.0,
+            hihat_crossover: 5_000.0,
+            threshold_bank_settings: ThresholdBankSettings::default(),
+        }
+    }
+}
+
+/// Complex domain onset detection.
+/// Every bin is expected to keep its magnitude and continue rotating with the phase advance
+/// of the previous frame, the distance to the observed bin is the onset weight.
+/// Catches soft tonal onsets whose magnitude barely changes.
+pub struct ComplexFlux {
+    /// Magnitudes are log compressed, the phase is kept
+    spectrum: Vec<Complex<f32>>,
+    old_spectrum: Vec<Complex<f32>>,
+    /// Phase of the frame before `old_spectrum`
+    older_phase: Vec<f32>,
+    deviation: Vec<f32>,
+    bin_resolution: f32,
+    drum_bins: usize,
+    hihat_bins: usize,
+    drum: Advanced,
+    hihat: Advanced,
+    note: Advanced,
+    full: Advanced,
+}
+
+impl ComplexFlux {
+    pub fn init(sample_rate: u32, fft_size: u32) -> Self {
+        let hop_size = (DEFAULT_FRAME_PERIOD.as_secs_f64() * sample_rate as f64) as usize;
+        Self::with_settings(
+            sample_rate,
+            fft_size,
+            hop_size,
+            ComplexFluxSettings::default(),
+        )
+    }
+
+    pub fn with_settings(
+        sample_rate: u32,
+        fft_size: u32,
+        hop_size: usize,
+        settings: ComplexFluxSettings,
+    ) -> Self {
+        let bins = fft_size as usize / 2 + 1;
+        let bin_resolution = sample_rate as f32 / fft_size as f32;
+        let drum_bins = ((settings.drum_crossover / bin_resolution) as usize).min(bins);
+        let hihat_bins =
+            ((settings.hihat_crossover / bin_resolution) as usize).clamp(drum_bins, bins);
+        let thresholds = settings.threshold_bank_settings;
+        let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
+
+        Self {
+            spectrum: vec![Complex::default(); bins],
+            old_spectrum: vec![Complex::default(); bins],
+            older_phase: vec![0.0; bins],
+            deviation: vec![0.0; bins],
+            bin_resolution,
+            drum_bins,
+            hihat_bins,
+            drum: Advanced::with_settings_timed(thresholds.drum, frame_period),
+            hihat: Advanced::with_settings_timed(thresholds.hihat, frame_period),
+            note: Advanced::with_settings_timed(thresholds.note, frame_period),
+            full: Advanced::with_settings_timed(thresholds.full, frame_period),
+        }
+    }
+
+    pub fn detect(&mut self, complex_bins: &[Complex<f32>], peak: f32, rms: f32) -> Vec<Onset> {
+        let lambda = 0.1;
+
+        std::mem::swap(&mut self.spectrum, &mut self.old_spectrum);
+
+        for (i, (x, bin)) in self.spectrum.iter_mut().zip(complex_bins).enumerate() {
+            let (magnitude, phase) = bin.to_polar();
+            *x = Complex::from_polar((magnitude * lambda).ln_1p(), phase);
+
+            let old = self.old_spectrum[i];
+            let (old_magnitude, old_phase) = old.to_polar();
+            let expected_phase = 2.0 * old_phase - self.older_phase[i];
+            let expected = Complex::from_polar(old_magnitude, expected_phase);
+
+            // Only rising bins count, otherwise note offsets would look like onsets
+            self.deviation[i] = if x.norm() >= old_magnitude {
+                (*x - expected).norm()
+            } else {
+                0.0
+            };
+            self.older_phase[i] = old_phase;
+        }
+
+        let weight: f32 = self.deviation.iter().sum();
+        let drum_weight: f32 = self.deviation[..self.drum_bins].iter().sum();
+        let note_weight: f32 = self.deviation[self.drum_bins..self.hihat_bins].iter().sum();
+        let hihat_weight: f32 = self.deviation[self.hihat_bins..].iter().sum();
+
+        let index_of_max = self.deviation[self.drum_bins..self.hihat_bins]
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(i, _)| i + self.drum_bins);
+        let frequency_of_max = index_of_max as f32 * self.bin_resolution;
+
+        let mut onsets = vec![Onset::Raw(weight)];
+
+        if self.full.is_above(weight) {
+            onsets.push(Onset::Full(rms));
+        }
+
+        if self.drum.is_above(drum_weight) {
+            onsets.push(Onset::Drum(rms));
+        }
+
+        if self.hihat.is_above(hihat_weight) {
+            onsets.push(Onset::Hihat(peak));
+        }
+
+        if self.note.is_above(note_weight) {
+            onsets.push(Onset::Note(rms, frequency_of_max as u16));
+        }
+
+        onsets
+    }
+}
+
+impl OnsetDetector for ComplexFlux {
+    /// Without the phase every bin is assumed to be stationary,
+    /// which leaves the rectified spectral flux of the magnitudes
+    fn detect(&mut self, freq_bins: &[f32], peak: f32, rms: f32) -> Vec<Onset> {
+        let complex_bins: Vec<Complex<f32>> =
+            freq_bins.iter().map(|&f| Complex::new(f, 0.0)).collect();
+        self.detect(&complex_bins, peak, rms)
+    }
+
+    fn detect_complex(
+        &mut self,
+        complex_bins: &[Complex<f32>],
+        _freq_bins: &[f32],
+        peak: f32,
+        rms: f32,
+    ) -> Vec<Onset> {
+        self.detect(complex_bins, peak, rms)
+    }
+}
@@ -0,0 +1,315 @@
+use std::{f32::consts::PI, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{
+    spectral_flux::ThresholdBankSettings,
+    threshold::{Advanced, DEFAULT_FRAME_PERIOD},
+    Onset, OnsetDetector,
+};
+
+/// Log-spaced spectrum calculated from the linear FFT bins with a precomputed sparse kernel
+pub struct ConstantQ {
+    kernel: Vec<(usize, Vec<f32>)>,
+    pub frequencies: Vec<f32>,
+    pub bins_per_octave: usize,
+    pub sample_rate: u32,
+    pub fft_size: u32,
+}
+
+impl ConstantQ {
+    pub fn init(
+        sample_rate: u32,
+        fft_size: u32,
+        bins_per_octave: usize,
+        min_frequency: f32,
+        max_frequency: f32,
+    ) -> ConstantQ {
+        let nyquist = sample_rate as f32 / 2.0;
+        let max_frequency = max_frequency.min(nyquist);
+        assert!(
+            min_frequency > 0.0 && min_frequency < max_frequency,
+            "min_frequency must be positive and less than max_frequency"
+        );
+
+        let bin_res = sample_rate as f32 / fft_size as f32;
+        let fft_bins = fft_size as usize / 2 + 1;
+        let q = 1.0 / (2.0_f32.powf(1.0 / bins_per_octave as f32) - 1.0);
+
+        let frequencies: Vec<f32> = (0..)
+            .map(|k| min_frequency * 2.0_f32.powf(k as f32 / bins_per_octave as f32))
+            .take_while(|&f| f <= max_frequency)
+            .collect();
+
+        let kernel = frequencies
+            .iter()
+            .map(|&f| {
+                let center = f / bin_res;
+                let width = f / q / bin_res;
+
+                // Bandwidth smaller than a FFT bin, interpolate between the neighbouring bins
+                if width < 1.0 {
+                    let start = (center as usize).min(fft_bins - 2);
+                    let t = (center - start as f32).clamp(0.0, 1.0);
+                    return (start, vec![1.0 - t, t]);
+                }
+
+                let start = (center - width / 2.0).ceil().max(0.0) as usize;
+                let end = ((center + width / 2.0).floor() as usize).min(fft_bins - 1);
+                let mut weights: Vec<f32> = (start..=end)
+                    .map(|j| 0.5 * (1.0 + (2.0 * PI * (j as f32 - center) / width).cos()))
+                    .collect();
+                let sum = weights.iter().sum::<f32>().max(f32::EPSILON);
+                weights.iter_mut().for_each(|w| *w /= sum);
+
+                (start, weights)
+            })
+            .collect();
+
+        ConstantQ {
+            kernel,
+            frequencies,
+            bins_per_octave,
+            sample_rate,
+            fft_size,
+        }
+    }
+
+    pub fn with_settings(sample_rate: u32, fft_size: u32, settings: &CqtSettings) -> ConstantQ {
+        ConstantQ::init(
+            sample_rate,
+            fft_size,
+            settings.bins_per_octave,
+            settings.min_frequency,
+            settings.max_frequency,
+        )
+    }
+
+    pub fn bins(&self) -> usize {
+        self.frequencies.len()
+    }
+
+    pub fn transform(&self, freq_bins: &[f32], out: &mut [f32]) {
+        self.kernel
+            .iter()
+            .zip(out)
+            .for_each(|((start, weights), x)| {
+                *x = freq_bins[*start..(start + weights.len())]
+                    .iter()
+                    .zip(weights)
+                    .map(|(&f, &w)| f * w)
+                    .sum::<f32>();
+            });
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct CqtSettings {
+    #[serde(rename = "bins_per_octave")]
+    pub bins_per_octave: usize,
+    #[serde(rename = "min_frequency")]
+    pub min_frequency: f32,
+    #[serde(rename = "max_frequency")]
+    pub max_frequency: f32,
+    #[serde(rename = "drum_crossover")]
+    pub drum_crossover: f32,
+    #[serde(rename = "hihat_crossover")]
+    pub hihat_crossover: f32,
+    pub threshold_bank_settings: ThresholdBankSettings,
+}
+
+impl CqtSettings {
+    /// Replaces a frequency range or resolution the kernel can't be built from by the defaults
+    fn validated(mut self, sample_rate: u32) -> Self {
+        let default = CqtSettings::default();
+        if self.bins_per_octave == 0 {
+            warn!(
+                "bins_per_octave must be at least 1, using {} instead",
+                default.bins_per_octave
+            );
+            self.bins_per_octave = default.bins_per_octave;
+        }
+        let max_frequency = self.max_frequency.min(sample_rate as f32 / 2.0);
+        if !(0.0 < self.min_frequency && self.min_frequency < max_frequency) {
+            warn!(
+                "Frequency range {}Hz to {max_frequency}Hz must be positive and increasing, \
+                using {}Hz to {}Hz instead",
+                self.min_frequency, default.min_frequency, default.max_frequency
+            );
+            self.min_frequency = default.min_frequency;
+            self.max_frequency = default.max_frequency;
+        }
+        self
+    }
+}
+
+impl Default for CqtSettings {
+    fn default() -> Self {
+        Self {
+            bins_per_octave: 12,
+            min_frequency: 32.7, // C1
+            max_frequency: 16_000.0,
+            drum_crossover: 150.0,
+            hihat_crossover: 5_000.0,
+            threshold_bank_settings: ThresholdBankSettings::default(),
+        }
+    }
+}
+
+/// Spectral flux on a constant-Q spectrum for better resolution of closely spaced bass notes
+pub struct Cqt {
+    constant_q: ConstantQ,
+    old_spectrum: Vec<f32>,
+    spectrum: Vec<f32>,
+    flux: Vec<f32>,
+    drum_bins: usize,
+    hihat_bins: usize,
+    drum: Advanced,
+    hihat: Advanced,
+    note: Advanced,
+    full: Advanced,
+}
+
+impl Cqt {
+    pub fn init(sample_rate: u32, fft_size: u32) -> Self {
+        let hop_size = (DEFAULT_FRAME_PERIOD.as_secs_f64() * sample_rate as f64) as usize;
+        Self::with_settings(sample_rate, fft_size, hop_size, CqtSettings::default())
+    }
+
+    pub fn with_settings(
+        sample_rate: u32,
+        fft_size: u32,
+        hop_size: usize,
+        settings: CqtSettings,
+    ) -> Self {
+        let settings = settings.validated(sample_rate);
+        let constant_q = ConstantQ::with_settings(sample_rate, fft_size, &settings);
+        let bins = constant_q.bins();
+        let drum_bins = constant_q
+            .frequencies
+            .iter()
+            .take_while(|&&f| f < settings.drum_crossover)
+            .count();
+        let hihat_bins = constant_q
+            .frequencies
+            .iter()
+            .take_while(|&&f| f < settings.hihat_crossover)
+            .count()
+            .max(drum_bins);
+        let thresholds = settings.threshold_bank_settings;
+        let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
+
+        Self {
+            constant_q,
+            old_spectrum: vec![0.0; bins],
+            spectrum: vec![0.0; bins],
+            flux: Vec::with_capacity(bins),
+            drum_bins,
+            hihat_bins,
+            drum: Advanced::with_settings_timed(thresholds.drum, frame_period),
+            hihat: Advanced::with_settings_timed(thresholds.hihat, frame_period),
+            note: Advanced::with_settings_timed(thresholds.note, frame_period),
+            full: Advanced::with_settings_timed(thresholds.full, frame_period),
+        }
+    }
+
+    pub fn detect(&mut self, freq_bins: &[f32], peak: f32, rms: f32) -> Vec<Onset> {
+        self.old_spectrum.clone_from(&self.spectrum);
+
+        let lambda = 0.1;
+
+        self.constant_q.transform(freq_bins, &mut self.spectrum);
+
+        self.spectrum
+            .iter_mut()
+            .for_each(|x| *x = (*x * lambda).ln_1p());
+
+        self.flux.clear();
+        self.flux.extend(
+            self.old_spectrum
+                .iter()
+                .zip(&self.spectrum)
+                .map(|(&a, &b)| (b - a).max(0.0)),
+        );
+        let flux = &self.flux;
+
+        let weight: f32 = flux.iter().sum();
+        let drum_weight: f32 = flux[..self.drum_bins].iter().sum();
+        let note_weight: f32 = flux[self.drum_bins..self.hihat_bins].iter().sum();
+        let hihat_weight: f32 = flux[self.hihat_bins..].iter().sum();
+
+        let index_of_max = self
+            .spectrum
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(i, _)| i);
+        let frequency_of_max = self
+            .constant_q
+            .frequencies
+            .get(index_of_max)
+            .copied()
+            .unwrap_or_default();
+
+        let mut onsets = vec![Onset::Raw(weight)];
+
+        if self.full.is_above(weight) {
+            onsets.push(Onset::Full(rms));
+        }
+
+        if self.drum.is_above(drum_weight) {
+            onsets.push(Onset::Drum(rms));
+        }
+
+        if self.hihat.is_above(hihat_weight) {
+            onsets.push(Onset::Hihat(peak));
+        }
+
+        if self.note.is_above(note_weight) {
+            onsets.push(Onset::Note(rms, frequency_of_max as u16));
+        }
+
+        onsets
+    }
+}
+
+impl OnsetDetector for Cqt {
+    fn detect(&mut self, freq_bins: &[f32], peak: f32, rms: f32) -> Vec<Onset> {
+        self.detect(freq_bins, peak, rms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_settings_fall_back_to_the_defaults() {
+        for settings in [
+            CqtSettings {
+                min_frequency: 0.0,
+                ..Default::default()
+            },
+            CqtSettings {
+                min_frequency: 1_000.0,
+                max_frequency: 500.0,
+                ..Default::default()
+            },
+            CqtSettings {
+                min_frequency: 30_000.0,
+                max_frequency: 40_000.0,
+                ..Default::default()
+            },
+            CqtSettings {
+                bins_per_octave: 0,
+                ..Default::default()
+            },
+        ] {
+            let mut cqt = Cqt::with_settings(48000, 2048, 480, settings);
+            assert!(cqt.constant_q.bins() > 0);
+            cqt.detect(&[0.0; 1025], 0.0, 0.0);
+        }
+    }
+}
@@ -1,12 +1,17 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
-use super::Onset;
+use super::{compensated_sum, frequency_to_midi, Onset};
 
 use super::{
-    threshold::{Dynamic, DynamicSettings},
+    threshold::{CooldownBank, CooldownSettings, Dynamic, DynamicSettings},
     OnsetDetector,
 };
 
+/// The loudest mid bin has to be this much louder than the average mid bin to be taken as a pitch
+const PITCH_PEAK_RATIO: f32 = 8.0;
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct DetectionWeights {
@@ -33,36 +38,90 @@ impl Default for DetectionWeights {
 
 pub struct Hfc {
     threshold: ThresholdBank,
+    cooldown: CooldownBank,
     detection_weights: DetectionWeights,
     bin_resolution: f32,
+    /// Gain of every frequency bin, empty for a flat curve
+    gains: Vec<f32>,
+    weighted_bins: Vec<f32>,
 }
 
-#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default, rename_all = "PascalCase")]
 pub struct HfcSettings {
+    /// Breakpoints of (frequency, gain) that are linearly interpolated across the spectrum
+    /// before the weights are computed. Gains outside of the breakpoints are held constant.
+    #[serde(rename = "gain_curve")]
+    pub gain_curve: Vec<(f32, f32)>,
     pub detection_weights: DetectionWeights,
     pub threshold: ThresholdBankSettings,
+    /// Time after an onset in which the same band can't trigger again
+    pub min_interval: CooldownSettings,
+}
+
+/// Gain of every frequency bin interpolated from the `(frequency, gain)` breakpoints
+fn bin_gains(curve: &[(f32, f32)], bins: usize, bin_resolution: f32) -> Vec<f32> {
+    let mut curve: Vec<(f32, f32)> = curve
+        .iter()
+        .copied()
+        .filter(|(frequency, gain)| frequency.is_finite() && gain.is_finite())
+        .collect();
+    curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let (Some(&first), Some(&last)) = (curve.first(), curve.last()) else {
+        return Vec::new();
+    };
+
+    (0..bins)
+        .map(|k| {
+            let frequency = k as f32 * bin_resolution;
+            if frequency <= first.0 {
+                return first.1;
+            }
+            if frequency >= last.0 {
+                return last.1;
+            }
+            let upper = curve.partition_point(|(f, _)| *f < frequency);
+            let (f0, g0) = curve[upper - 1];
+            let (f1, g1) = curve[upper];
+            g0 + (g1 - g0) * (frequency - f0) / (f1 - f0)
+        })
+        .collect()
 }
 
 impl Hfc {
-    pub fn init(sample_rate: usize, fft_size: usize) -> Self {
+    pub fn init(sample_rate: usize, fft_size: usize, hop_size: usize) -> Self {
         let threshold = ThresholdBank::default();
         let detection_weights = DetectionWeights::default();
         let bin_resolution = sample_rate as f32 / fft_size as f32;
+        let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
         Self {
             threshold,
+            cooldown: CooldownBank::with_settings(CooldownSettings::default(), frame_period),
             detection_weights,
             bin_resolution,
+            gains: Vec::new(),
+            weighted_bins: Vec::new(),
         }
     }
 
-    pub fn with_settings(sample_rate: usize, fft_size: usize, settings: HfcSettings) -> Self {
+    pub fn with_settings(
+        sample_rate: usize,
+        fft_size: usize,
+        hop_size: usize,
+        settings: HfcSettings,
+    ) -> Self {
         let threshold = ThresholdBank::with_settings(settings.threshold);
         let bin_resolution = sample_rate as f32 / fft_size as f32;
+        let gains = bin_gains(&settings.gain_curve, fft_size / 2 + 1, bin_resolution);
+        let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
         Self {
             threshold,
+            cooldown: CooldownBank::with_settings(settings.min_interval, frame_period),
             detection_weights: settings.detection_weights,
             bin_resolution,
+            gains,
+            weighted_bins: Vec::new(),
         }
     }
 
@@ -73,6 +132,19 @@ impl Hfc {
             return vec![];
         }
 
+        let freq_bins = if self.gains.is_empty() {
+            freq_bins
+        } else {
+            self.weighted_bins.clear();
+            self.weighted_bins.extend(
+                freq_bins
+                    .iter()
+                    .zip(self.gains.iter().chain(std::iter::repeat(&1.0)))
+                    .map(|(bin, gain)| bin * gain),
+            );
+            &self.weighted_bins
+        };
+
         let DetectionWeights {
             low_end_weight_cutoff,
             high_end_weight_cutoff,
@@ -88,37 +160,44 @@ impl Hfc {
         let mids_weight_high_cutoff =
             (mids_weight_high_cutoff as f32 / self.bin_resolution) as usize;
 
-        let weight: f32 = freq_bins
-            .iter()
-            .enumerate()
-            .map(|(k, freq)| k as f32 * self.bin_resolution * freq)
-            .sum();
+        let weight = compensated_sum(
+            freq_bins
+                .iter()
+                .enumerate()
+                .map(|(k, freq)| k as f32 * self.bin_resolution * freq),
+        );
 
-        let low_end_weight: &f32 = &freq_bins[0..low_end_weight_cutoff]
-            .iter()
-            .enumerate()
-            .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq))
-            .sum::<f32>();
+        let low_end_weight: &f32 = &compensated_sum(
+            freq_bins[0..low_end_weight_cutoff]
+                .iter()
+                .enumerate()
+                .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq)),
+        );
 
-        let high_end_weight: &f32 = &freq_bins[high_end_weight_cutoff..]
-            .iter()
-            .enumerate()
-            .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq))
-            .sum::<f32>();
+        let high_end_weight: &f32 = &compensated_sum(
+            freq_bins[high_end_weight_cutoff..]
+                .iter()
+                .enumerate()
+                .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq)),
+        );
 
-        let mids_weight: &f32 = &freq_bins[mids_weight_low_cutoff..mids_weight_high_cutoff]
-            .iter()
-            .enumerate()
-            .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq))
-            .sum::<f32>();
+        let mids_weight: &f32 = &compensated_sum(
+            freq_bins[mids_weight_low_cutoff..mids_weight_high_cutoff]
+                .iter()
+                .enumerate()
+                .map(|(k, freq)| (k as f32 * self.bin_resolution * *freq)),
+        );
 
-        let index_of_max_mid = (freq_bins[mids_weight_low_cutoff..mids_weight_high_cutoff]
+        let mids = &freq_bins[mids_weight_low_cutoff..mids_weight_high_cutoff];
+        let (max_mid, max_mid_value) = mids
             .iter()
             .enumerate()
             .max_by(|(_, &a), (_, &b)| a.total_cmp(&b))
-            .unwrap()
-            .0 as f32
-            * self.bin_resolution) as usize;
+            .unwrap();
+        let index_of_max_mid =
+            ((mids_weight_low_cutoff + max_mid) as f32 * self.bin_resolution) as usize;
+        let mids_mean = compensated_sum(mids.iter().copied()) / mids.len() as f32;
+        let clear_peak = *max_mid_value > PITCH_PEAK_RATIO * mids_mean;
 
         let index_of_max = (freq_bins
             .iter()
@@ -130,7 +209,11 @@ impl Hfc {
 
         let mut onsets: Vec<Onset> = Vec::new();
 
-        if self.threshold.fullband.is_above(weight) {
+        if self
+            .cooldown
+            .full
+            .gate(self.threshold.fullband.is_above(weight))
+        {
             onsets.push(Onset::Full(rms));
         } else {
             onsets.push(Onset::Atmosphere(rms, index_of_max as u16));
@@ -139,16 +222,34 @@ impl Hfc {
         onsets.push(Onset::Raw(weight));
 
         let drums_weight = low_end_weight * drum_click_weight * high_end_weight;
-        if self.threshold.drums.is_above(drums_weight) {
+        if self
+            .cooldown
+            .drum
+            .gate(self.threshold.drums.is_above(drums_weight))
+        {
             onsets.push(Onset::Drum(rms));
         }
 
         let notes_weight = mids_weight + note_click_weight * high_end_weight;
-        if self.threshold.notes.is_above(notes_weight) {
+        if self
+            .cooldown
+            .note
+            .gate(self.threshold.notes.is_above(notes_weight))
+        {
             onsets.push(Onset::Note(rms, index_of_max_mid as u16));
+            if let Some(note) = clear_peak
+                .then(|| frequency_to_midi(index_of_max_mid as f32))
+                .flatten()
+            {
+                onsets.push(Onset::Pitch(note));
+            }
         }
 
-        if self.threshold.hihat.is_above(*high_end_weight) {
+        if self
+            .cooldown
+            .hihat
+            .gate(self.threshold.hihat.is_above(*high_end_weight))
+        {
             onsets.push(Onset::Hihat(peak));
         }
         onsets
@@ -1,12 +1,24 @@
+pub mod complex_flux;
+pub mod cqt;
 pub mod hfc;
 pub mod spectral_flux;
 pub mod threshold;
 
-use std::{f32::consts::PI, sync::Arc};
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
 
 use realfft::{RealFftPlanner, RealToComplex};
 use rustfft::num_complex::Complex;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Number of cepstral coefficients carried by [`Onset::Timbre`]
+pub const MFCC_COEFFICIENTS: usize = 13;
+/// Smallest mel energy the log is taken of, keeps silent bands from dominating the coefficients
+const MFCC_FLOOR: f32 = 1e-10;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 #[serde(untagged)]
@@ -17,6 +29,47 @@ pub enum Onset {
     Drum(f32),
     Hihat(f32),
     Raw(f32),
+    Timbre([f32; MFCC_COEFFICIENTS]),
+    /// MIDI note number of the dominant pitch of a note onset
+    Pitch(u8),
+    /// Position of a transient between left (-1.0) and right (1.0), sent along with the onsets
+    Pan(f32),
+}
+
+impl Onset {
+    /// Drum, hihat, note and fullband onsets
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Onset::Full(_) | Onset::Note(_, _) | Onset::Drum(_) | Onset::Hihat(_)
+        )
+    }
+
+    /// Same onset with the strength of drum, hihat, note, fullband and atmosphere onsets mapped by `f`
+    pub fn map_strength(self, f: impl FnOnce(f32) -> f32) -> Onset {
+        match self {
+            Onset::Full(strength) => Onset::Full(f(strength)),
+            Onset::Atmosphere(strength, frequency) => Onset::Atmosphere(f(strength), frequency),
+            Onset::Note(strength, frequency) => Onset::Note(f(strength), frequency),
+            Onset::Drum(strength) => Onset::Drum(f(strength)),
+            Onset::Hihat(strength) => Onset::Hihat(f(strength)),
+            onset => onset,
+        }
+    }
+}
+
+/// Nearest MIDI note number of `frequency`, A4 at 440Hz is note 69
+pub fn frequency_to_midi(frequency: f32) -> Option<u8> {
+    if frequency.is_nan() || frequency <= 0.0 {
+        return None;
+    }
+    let note = (69.0 + 12.0 * (frequency / 440.0).log2()).round();
+    (0.0..=127.0).contains(&note).then_some(note as u8)
+}
+
+/// Pitch class of a MIDI note, 0 is C and 11 is B
+pub fn pitch_class(note: u8) -> u8 {
+    note % 12
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
@@ -27,6 +80,18 @@ pub struct ProcessingSettings {
     pub buffer_size: usize,
     pub fft_size: usize,
     pub window_type: WindowType,
+    pub silence_threshold: f32,
+    pub adaptive_silence: bool,
+    pub per_channel: bool,
+    /// Coefficient `a` of the pre-emphasis filter `y[n] = x[n] - a * x[n-1]`, 0.0 disables it.
+    /// HFC already weights every bin by its frequency, so with both enabled the highs are boosted twice.
+    pub pre_emphasis: f32,
+    /// Onsets are passed to the light services this long after their detection,
+    /// so lights closer to the listener than the speakers don't run ahead of the sound
+    pub latency_offset: Duration,
+    /// Share of the previous spectrum kept in the spectrum passed to the light services, 0.0 disables it.
+    /// Only smooths visualizations, the onset detection always uses the unsmoothed spectrum.
+    pub spectrum_smoothing: f32,
 }
 
 impl Default for ProcessingSettings {
@@ -37,20 +102,109 @@ impl Default for ProcessingSettings {
             buffer_size: 1024,
             fft_size: 2048,
             window_type: WindowType::Hann,
+            silence_threshold: 0.0,
+            adaptive_silence: false,
+            per_channel: false,
+            pre_emphasis: 0.0,
+            latency_offset: Duration::ZERO,
+            spectrum_smoothing: 0.0,
+        }
+    }
+}
+
+/// Time after startup in which the adaptive noise gate learns the noise floor
+const NOISE_FLOOR_LEARNING_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone)]
+struct NoiseGate {
+    threshold: f32,
+    learning_frames: usize,
+    learned_frames: usize,
+    noise_floor: f32,
+}
+
+impl NoiseGate {
+    fn init(settings: &ProcessingSettings) -> NoiseGate {
+        let learning_frames = if settings.adaptive_silence {
+            (NOISE_FLOOR_LEARNING_TIME.as_secs_f32() * settings.sample_rate as f32
+                / settings.hop_size as f32) as usize
+        } else {
+            0
+        };
+        NoiseGate {
+            threshold: settings.silence_threshold,
+            learning_frames,
+            learned_frames: 0,
+            noise_floor: f32::MAX,
+        }
+    }
+
+    fn is_silent(&mut self, rms: f32) -> bool {
+        if self.learning_frames == 0 {
+            return rms < self.threshold;
         }
+
+        if self.learned_frames < self.learning_frames {
+            // Quietest frame with 6dB of headroom for fluctuations of the noise
+            self.noise_floor = self.noise_floor.min(rms * 2.0);
+            self.learned_frames += 1;
+            return rms < self.threshold;
+        }
+
+        rms < self.threshold.max(self.noise_floor)
+    }
+
+    /// Skips the learning with a noise floor learned before
+    fn restore(&mut self, noise_floor: f32) {
+        if self.learning_frames > 0 {
+            self.noise_floor = noise_floor;
+            self.learned_frames = self.learning_frames;
+        }
+    }
+
+    /// Noise floor once the learning is done
+    fn learned(&self) -> Option<f32> {
+        (self.learning_frames > 0 && self.learned_frames == self.learning_frames)
+            .then_some(self.noise_floor)
     }
 }
 
+/// Forward FFT of `fft_size` samples.
+/// Plans are shared between all buffers, so every device and channel detector
+/// with the same size reuses the twiddle factors.
+pub fn plan_fft(fft_size: usize) -> Arc<dyn RealToComplex<f32>> {
+    static PLANNER: OnceLock<Mutex<RealFftPlanner<f32>>> = OnceLock::new();
+    PLANNER
+        .get_or_init(|| Mutex::new(RealFftPlanner::new()))
+        .lock()
+        .unwrap()
+        .plan_fft_forward(fft_size)
+}
+
 pub struct Buffer {
+    /// Per channel FFT input, always `fft_size` long with the frame at the start
     f32_samples: Vec<Vec<f32>>,
+    /// Samples per channel in one frame
+    frame_size: usize,
     pub mono_samples: Vec<f32>,
     fft_output: Vec<Vec<Complex<f32>>>,
     fft_window: Vec<f32>,
     pub freq_bins: Vec<f32>,
+    /// Magnitude spectrum of every channel, `freq_bins` is their average
+    pub channel_bins: Vec<Vec<f32>>,
+    /// Complex spectrum averaged over all channels and scaled like `freq_bins`,
+    /// keeps the phase for phase based detection
+    pub complex_bins: Vec<Complex<f32>>,
+    /// Exponential moving average of `freq_bins`, only updated with `spectrum_smoothing`
+    smoothed_bins: Vec<f32>,
+    spectrum_smoothing: f32,
     fft_planner: Arc<dyn RealToComplex<f32>>,
     pub peak: f32,
     pub rms: f32,
+    pub channel_rms: Vec<f32>,
     pub channels: u16,
+    noise_gate: NoiseGate,
+    pre_emphasis: f32,
 }
 
 impl Buffer {
@@ -61,26 +215,66 @@ impl Buffer {
         }
         let mono_samples: Vec<f32> = vec![0.0; settings.buffer_size];
 
-        let fft_planner = RealFftPlanner::<f32>::new().plan_fft_forward(settings.fft_size);
+        let fft_planner = plan_fft(settings.fft_size);
         let fft_output: Vec<Vec<Complex<f32>>> = (0..channels)
             .map(|_| fft_planner.make_output_vec())
             .collect();
         let freq_bins: Vec<f32> = vec![0.0; fft_output[0].capacity()];
+        let channel_bins: Vec<Vec<f32>> = (0..channels).map(|_| freq_bins.clone()).collect();
+        let complex_bins = fft_planner.make_output_vec();
+        let smoothed_bins = freq_bins.clone();
         let fft_window = window(settings.buffer_size, settings.window_type);
 
         Buffer {
             f32_samples,
+            frame_size: settings.buffer_size.min(settings.fft_size),
             mono_samples,
             fft_output,
             fft_window,
             freq_bins,
+            channel_bins,
+            complex_bins,
+            smoothed_bins,
+            spectrum_smoothing: settings.spectrum_smoothing.clamp(0.0, 1.0),
             fft_planner,
             peak: 0.0,
             rms: 0.0,
+            channel_rms: vec![0.0; channels.into()],
             channels,
+            noise_gate: NoiseGate::init(settings),
+            pre_emphasis: settings.pre_emphasis,
+        }
+    }
+
+    /// Spectrum for visualizations, smoothed over time with `spectrum_smoothing`
+    pub fn smoothed_spectrum(&self) -> &[f32] {
+        if self.spectrum_smoothing > 0.0 {
+            &self.smoothed_bins
+        } else {
+            &self.freq_bins
         }
     }
 
+    /// Uses a noise floor learned in a previous run instead of learning it again
+    pub fn restore_noise_floor(&mut self, noise_floor: f32) {
+        self.noise_gate.restore(noise_floor);
+    }
+
+    /// Level difference between the first two channels, -1.0 is only left and 1.0 only right.
+    /// `None` for mono or silence.
+    pub fn pan(&self) -> Option<f32> {
+        let [left, right, ..] = self.channel_rms[..] else {
+            return None;
+        };
+        let sum = left + right;
+        (sum > f32::EPSILON).then(|| (right - left) / sum)
+    }
+
+    /// Noise floor learned by the adaptive noise gate, `None` while learning or if disabled
+    pub fn learned_noise_floor(&self) -> Option<f32> {
+        self.noise_gate.learned()
+    }
+
     pub fn process_raw(&mut self, data: &[f32]) {
         //Check for silence and abort if present
         let sound = data.iter().any(|i| *i != 0.0);
@@ -93,29 +287,51 @@ impl Buffer {
 
         self.collapse_mono();
 
-        self.rms = self.rms();
+        self.channel_rms();
+        self.rms = self.channel_rms.iter().sum::<f32>() / self.channels as f32;
         self.peak = self.peak();
 
+        if self.noise_gate.is_silent(self.rms) {
+            self.zeros();
+            return;
+        }
+
+        // Only the spectrum is emphasized, RMS, peak and the mono samples keep the original signal
+        if self.pre_emphasis != 0.0 {
+            self.pre_emphasis();
+        }
+
         self.fft();
     }
 
-    fn rms(&self) -> f32 {
-        self.f32_samples
-            .iter()
-            .map(|c| (c.iter().fold(0.0, |acc, e| acc + e * e) / c.len() as f32).sqrt())
-            .sum::<f32>()
-            / self.channels as f32
+    fn pre_emphasis(&mut self) {
+        let a = self.pre_emphasis;
+        for channel in self.f32_samples.iter_mut() {
+            let frame = &mut channel[..self.frame_size];
+            // Backwards so every sample still sees its unfiltered predecessor
+            for n in (1..frame.len()).rev() {
+                frame[n] -= a * frame[n - 1];
+            }
+        }
+    }
+
+    fn channel_rms(&mut self) {
+        let frame_size = self.frame_size;
+        for (rms, channel) in self.channel_rms.iter_mut().zip(&self.f32_samples) {
+            let frame = &channel[..frame_size];
+            *rms = (compensated_sum(frame.iter().map(|e| e * e)) / frame_size as f32).sqrt();
+        }
     }
 
     fn peak(&self) -> f32 {
         self.f32_samples
             .iter()
             .map(|c| {
-                c.iter()
-                    .fold(0.0, |max, f| if f.abs() > max { f.abs() } else { max })
+                c[..self.frame_size]
+                    .iter()
+                    .fold(0.0, |max, f| f.abs().max(max))
             })
-            .reduce(f32::max)
-            .unwrap()
+            .fold(0.0, f32::max)
     }
 
     fn zeros(&mut self) {
@@ -123,51 +339,54 @@ impl Buffer {
             f32_samples,
             mono_samples,
             freq_bins,
+            channel_bins,
+            complex_bins,
             peak,
             rms,
+            channel_rms,
+            smoothed_bins,
+            spectrum_smoothing,
             ..
         } = self;
 
-        for channel in &mut *f32_samples {
-            channel.clear();
-            channel.extend(std::iter::repeat(0.0).take(channel.capacity()));
-        }
-
-        mono_samples.clear();
-        mono_samples.extend(std::iter::repeat(0.0).take(mono_samples.capacity()));
-
-        freq_bins.clear();
-        freq_bins.extend(std::iter::repeat(0.0).take(freq_bins.capacity()));
+        f32_samples.iter_mut().for_each(|c| c.fill(0.0));
+        mono_samples.fill(0.0);
+        freq_bins.fill(0.0);
+        channel_bins.iter_mut().for_each(|c| c.fill(0.0));
+        complex_bins.fill(Complex::default());
         *peak = 0.0;
         *rms = 0.0;
+        channel_rms.fill(0.0);
+        // Fade the smoothed spectrum out like `fft` would on a silent frame
+        smoothed_bins
+            .iter_mut()
+            .for_each(|smoothed| *smoothed *= *spectrum_smoothing);
     }
 
     fn split_channels(&mut self, data: &[f32]) {
+        let channels = self.channels as usize;
         for (i, channel) in self.f32_samples.iter_mut().enumerate() {
-            channel.clear();
-            channel.extend(data.iter().enumerate().filter_map(|(index, f)| {
-                if index % self.channels as usize == i {
-                    Some(f)
-                } else {
-                    None
-                }
-            }));
+            let (frame, padding) = channel.split_at_mut(self.frame_size);
+            for (sample, frame) in data[i..].iter().step_by(channels).zip(frame) {
+                *frame = *sample;
+            }
+            padding.fill(0.0);
         }
     }
 
     fn collapse_mono(&mut self) {
         let channels = self.channels as f32;
-        // Clear
-        self.mono_samples.clear();
-        self.mono_samples
-            .extend(std::iter::repeat(0.0).take(self.mono_samples.capacity()));
+        self.mono_samples.fill(0.0);
 
         // Average channels
         for channel in self.f32_samples.iter() {
-            self.mono_samples
+            for (m, s) in self
+                .mono_samples
                 .iter_mut()
-                .zip(channel.iter())
-                .for_each(|(m, &s)| *m += s / channels)
+                .zip(&channel[..self.frame_size])
+            {
+                *m += s / channels;
+            }
         }
     }
 
@@ -176,20 +395,20 @@ impl Buffer {
             f32_samples,
             fft_output,
             freq_bins,
+            channel_bins,
+            complex_bins,
+            smoothed_bins,
+            spectrum_smoothing,
             fft_window,
             fft_planner,
             ..
         } = self;
-        let channels = f32_samples.len();
+        let channels = f32_samples.len() as f32;
 
         // Could only apply window to collapsed mono signal
+        // The padding is already zeroed by split_channels
         apply_window(f32_samples, fft_window);
 
-        // Pad end with zeros
-        for channel in f32_samples.iter_mut() {
-            channel.extend(std::iter::repeat(0.0).take(channel.capacity() - channel.len()))
-        }
-
         // Calculate FFT for each channel
         for (samples, output) in f32_samples.iter_mut().zip(fft_output.iter_mut()) {
             match fft_planner.process(samples, output) {
@@ -197,26 +416,39 @@ impl Buffer {
                 Err(e) => println!("Error: {e:?}"),
             }
         }
-        // Save per channel power spectrum in f32_samples as it has been scrambled already by fft
-        for (i, out) in fft_output.iter().enumerate() {
-            let n = f32_samples[i].len() as f32;
-            f32_samples[i].clear();
-            f32_samples[i].extend(out.iter().map(|s| ((s.re * s.re + s.im * s.im) / n).sqrt()));
-        }
 
-        // Clear out bins
+        // Magnitude spectrum per channel and averaged over all channels
+        let n = fft_planner.len() as f32;
         freq_bins.fill(0.0);
+        for (out, bins) in fft_output.iter().zip(channel_bins.iter_mut()) {
+            for ((bin, average), s) in bins.iter_mut().zip(freq_bins.iter_mut()).zip(out) {
+                *bin = ((s.re * s.re + s.im * s.im) / n).sqrt();
+                *average += *bin / channels;
+            }
+        }
 
-        for channel in f32_samples.iter() {
-            freq_bins.iter_mut().zip(channel).for_each(|(bin, s)| {
-                *bin += s / channels as f32;
-            });
+        // The FFT is linear, so this equals the spectrum of the mono signal
+        let scale = 1.0 / (channels * n.sqrt());
+        complex_bins.fill(Complex::default());
+        for out in fft_output.iter() {
+            for (average, s) in complex_bins.iter_mut().zip(out) {
+                *average += s * scale;
+            }
+        }
+
+        if *spectrum_smoothing > 0.0 {
+            let decay = *spectrum_smoothing;
+            for (smoothed, bin) in smoothed_bins.iter_mut().zip(freq_bins.iter()) {
+                *smoothed = decay * *smoothed + (1.0 - decay) * bin;
+            }
         }
     }
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Default, Copy, Deserialize, Serialize, PartialEq, PartialOrd, Eq, Hash, Ord)]
+#[derive(
+    Debug, Clone, Default, Copy, Deserialize, Serialize, PartialEq, PartialOrd, Eq, Hash, Ord,
+)]
 pub enum WindowType {
     #[default]
     Hann,
@@ -258,8 +490,66 @@ fn apply_window_mono(samples: &mut [f32], window: &[f32]) {
     samples.iter_mut().zip(window).for_each(|(x, w)| *x *= w);
 }
 
+/// Below this many bands splitting the work across threads costs more than it saves
+#[cfg(feature = "rayon")]
+const PARALLEL_BANDS: usize = 256;
+
+/// Sum with Neumaier's compensation, the rounding error stays bounded
+/// instead of growing with the number of values like in a naive `f32` sum
+pub fn compensated_sum(values: impl IntoIterator<Item = f32>) -> f32 {
+    let mut sum = 0.0_f32;
+    let mut compensation = 0.0_f32;
+    for value in values {
+        let t = sum + value;
+        // The low bits of the smaller summand are lost in `t`
+        compensation += if sum.abs() >= value.abs() {
+            (sum - t) + value
+        } else {
+            (value - t) + sum
+        };
+        sum = t;
+    }
+    sum + compensation
+}
+
+/// Dot product over the shorter of both slices.
+/// Independent partial sums let the compiler vectorize the loop.
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    const LANES: usize = 8;
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+
+    let mut sums = [0.0; LANES];
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let tail = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for i in 0..LANES {
+            sums[i] += a[i] * b[i];
+        }
+    }
+    sums.iter().sum::<f32>() + tail
+}
+
+/// Scaling applied to the triangular filters of the [`MelFilterBank`]
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, PartialOrd, Eq)]
+pub enum MelNormalization {
+    /// All filters have a peak of 1, wider high frequency bands collect more energy
+    #[default]
+    None,
+    /// Every filter has the same area, as done by Slaney's Auditory Toolbox
+    Slaney,
+}
+
 pub struct MelFilterBank {
     filter: Vec<Vec<f32>>,
+    /// First FFT bin of every band
+    starts: Vec<usize>,
     points: Vec<f32>,
     pub fft_size: u32,
     pub bands: usize,
@@ -274,6 +564,7 @@ pub struct MelFilterBankSettings {
     pub bands: usize,
     pub min_frequency: f32,
     pub max_frequency: f32,
+    pub normalization: MelNormalization,
 }
 
 impl Default for MelFilterBankSettings {
@@ -282,6 +573,7 @@ impl Default for MelFilterBankSettings {
             bands: 82,
             min_frequency: 20.0,
             max_frequency: 20_000.0,
+            normalization: MelNormalization::None,
         }
     }
 }
@@ -293,8 +585,12 @@ impl MelFilterBank {
         bands: usize,
         min_frequency: f32,
         max_frequency: f32,
+        normalization: MelNormalization,
     ) -> MelFilterBank {
-        assert!(min_frequency < max_frequency, "min_frequency must be less than max_frequency");
+        assert!(
+            min_frequency < max_frequency,
+            "min_frequency must be less than max_frequency"
+        );
         let num_points = bands + 2;
         let mel_min = Self::hertz_to_mel(min_frequency);
         let mel_max = Self::hertz_to_mel(max_frequency);
@@ -306,14 +602,25 @@ impl MelFilterBank {
             .collect::<Vec<f32>>();
 
         let bin_res = sample_rate as f32 / fft_size as f32;
+        let bins = fft_size as usize / 2 + 1;
 
         let mut filter: Vec<Vec<f32>> = Vec::new();
+        let mut starts: Vec<usize> = Vec::new();
+        let mut widened = 0;
 
         for m in 1..=bands {
             let start = (mel[m - 1] / bin_res) as usize;
             let mid = (mel[m] / bin_res) as usize;
             let end = (mel[m + 1] / bin_res) as usize;
 
+            // Bands narrower than a bin would never see any energy,
+            // so every slope spans at least one bin
+            if mid <= start || end <= mid {
+                widened += 1;
+            }
+            let mid = mid.max(start + 1);
+            let end = end.max(mid + 1);
+
             let mut band: Vec<f32> = Vec::new();
 
             for k in start..mid {
@@ -323,11 +630,35 @@ impl MelFilterBank {
                 band.push((end - k) as f32 / (end - mid) as f32);
             }
 
+            if normalization == MelNormalization::Slaney {
+                let scale = 2.0 / (mel[m + 1] - mel[m - 1]);
+                band.iter_mut().for_each(|w| *w *= scale);
+            }
+
+            // Bands above the nyquist frequency are cut off
+            band.truncate(bins.saturating_sub(start));
+
             filter.push(band);
+            starts.push(start.min(bins));
+        }
+
+        if widened > 0 {
+            warn!(
+                "{widened} of {bands} mel bands are narrower than one FFT bin and were widened, \
+                consider using fewer bands or a larger fft_size"
+            );
+        }
+        if max_frequency > sample_rate as f32 / 2.0 {
+            warn!(
+                "max_frequency {max_frequency} is above the nyquist frequency {}, \
+                the highest bands are cut off",
+                sample_rate as f32 / 2.0
+            );
         }
 
         MelFilterBank {
             filter,
+            starts,
             points: mel,
             fft_size,
             bands,
@@ -348,25 +679,55 @@ impl MelFilterBank {
             settings.bands,
             settings.min_frequency,
             settings.max_frequency,
+            settings.normalization,
         )
     }
 
     pub fn filter(&self, freq_bins: &[f32], out: &mut [f32]) {
-        let bin_res = self.sample_rate as f32 / self.fft_size as f32;
+        let band = |(band, &start): (&Vec<f32>, &usize)| {
+            dot(&freq_bins[start.min(freq_bins.len())..], band)
+        };
+
+        #[cfg(feature = "rayon")]
+        if self.filter.len() >= PARALLEL_BANDS {
+            use rayon::prelude::*;
+
+            self.filter
+                .par_iter()
+                .zip(self.starts.par_iter())
+                .zip(out.par_iter_mut())
+                .for_each(|(band_start, x)| *x = band(band_start));
+            return;
+        }
 
-        self.filter
-            .iter()
-            .zip(out)
+        for (band_start, x) in self.filter.iter().zip(&self.starts).zip(out) {
+            *x = band(band_start);
+        }
+    }
+
+    /// Mel frequency cepstral coefficients of the mel energies calculated by [`MelFilterBank::filter`].
+    /// Takes the log of the energies and applies an orthonormal DCT-II,
+    /// writing the first `n_coeffs` coefficients to `out`.
+    pub fn mfcc(&self, mel_energies: &[f32], n_coeffs: usize, out: &mut [f32]) {
+        let bands = mel_energies.len() as f32;
+
+        out.iter_mut()
+            .take(n_coeffs)
             .enumerate()
-            .for_each(|(m, (band, x))| {
-                let start = (self.points[m] / bin_res) as usize;
-                let sum = freq_bins[start..(start + band.len())]
+            .for_each(|(k, c)| {
+                let sum = mel_energies
                     .iter()
-                    .zip(band)
-                    .map(|(&f, &w)| f * w)
+                    .enumerate()
+                    .map(|(m, &e)| {
+                        e.max(MFCC_FLOOR).ln() * (PI * k as f32 * (m as f32 + 0.5) / bands).cos()
+                    })
                     .sum::<f32>();
-
-                *x = sum;
+                let scale = if k == 0 {
+                    (1.0 / bands).sqrt()
+                } else {
+                    (2.0 / bands).sqrt()
+                };
+                *c = sum * scale;
             });
     }
 
@@ -381,10 +742,110 @@ impl MelFilterBank {
 
 pub trait OnsetDetector {
     fn detect(&mut self, freq_bins: &[f32], peak: f32, rms: f32) -> Vec<Onset>;
+
+    /// Detection with the phase of the spectrum, only phase based detectors need to override it
+    fn detect_complex(
+        &mut self,
+        _complex_bins: &[Complex<f32>],
+        freq_bins: &[f32],
+        peak: f32,
+        rms: f32,
+    ) -> Vec<Onset> {
+        self.detect(freq_bins, peak, rms)
+    }
 }
 
 impl OnsetDetector for Box<dyn OnsetDetector + Send> {
     fn detect(&mut self, freq_bins: &[f32], peak: f32, rms: f32) -> Vec<Onset> {
         self.as_mut().detect(freq_bins, peak, rms)
     }
+
+    fn detect_complex(
+        &mut self,
+        complex_bins: &[Complex<f32>],
+        freq_bins: &[f32],
+        peak: f32,
+        rms: f32,
+    ) -> Vec<Onset> {
+        self.as_mut()
+            .detect_complex(complex_bins, freq_bins, peak, rms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrow_mel_bands_are_widened() {
+        for (sample_rate, fft_size, bands, max_frequency) in [
+            (48000, 256, 82, 20_000.0),
+            (8000, 512, 128, 4_000.0),
+            (44100, 64, 40, 22_050.0),
+        ] {
+            let bank = MelFilterBank::init(
+                sample_rate,
+                fft_size,
+                bands,
+                20.0,
+                max_frequency,
+                MelNormalization::None,
+            );
+            assert_eq!(bank.filter.len(), bands);
+            for band in &bank.filter {
+                assert!(band
+                    .iter()
+                    .all(|w| w.is_finite() && (0.0..=1.0).contains(w)));
+                assert!(band.iter().any(|&w| w > 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn mfcc_of_a_pure_tone() {
+        let bank = MelFilterBank::init(32000, 2048, 40, 20.0, 16_000.0, MelNormalization::None);
+        let mut spectrum = vec![0.0; 2048 / 2 + 1];
+        // 1 kHz falls exactly onto bin 64
+        spectrum[64] = 1.0;
+        let mut energies = vec![0.0; 40];
+        bank.filter(&spectrum, &mut energies);
+
+        let mut coeffs = vec![0.0; 40];
+        bank.mfcc(&energies, MFCC_COEFFICIENTS, &mut coeffs);
+
+        // Calculated in f64 with a separate implementation of the same filter bank
+        // and an orthonormal DCT-II with a floor of 1e-10
+        let expected = [
+            -138.5763, 6.4686, -1.5642, -8.4515, -9.3626, -3.7226, 4.4263, 9.3406, 7.6527, 0.6947,
+            -6.5461, -9.0253, -5.1811,
+        ];
+        for (c, e) in coeffs.iter().zip(expected) {
+            assert!((c - e).abs() < 1e-3, "{c} != {e}");
+        }
+        assert!(coeffs[MFCC_COEFFICIENTS..].iter().all(|&c| c == 0.0));
+
+        // A flat spectrum only has the first coefficient
+        bank.mfcc(&[2.0; 40], 40, &mut coeffs);
+        assert!((coeffs[0] - 40.0_f32.sqrt() * 2.0_f32.ln()).abs() < 1e-4);
+        assert!(coeffs[1..].iter().all(|c| c.abs() < 1e-4));
+    }
+
+    #[test]
+    fn mel_filters_overlap_to_one() {
+        let bank = MelFilterBank::init(48000, 4096, 40, 20.0, 20_000.0, MelNormalization::None);
+        let bins = 4096 / 2 + 1;
+        let mut sum = vec![0.0; bins];
+        for (band, &start) in bank.filter.iter().zip(&bank.starts) {
+            for (k, w) in band.iter().enumerate() {
+                sum[start + k] += w;
+            }
+        }
+
+        let bin_res = 48000.0 / 4096.0;
+        let first_peak = (bank.points[1] / bin_res) as usize;
+        let last_peak = (bank.points[40] / bin_res) as usize;
+        for &s in &sum[first_peak..last_peak] {
+            assert!((s - 1.0).abs() < 1e-5);
+        }
+    }
 }
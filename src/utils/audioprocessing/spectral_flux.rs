@@ -1,9 +1,14 @@
+use std::{error::Error, fs, time::Duration};
+
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use super::Onset;
+use super::{compensated_sum, Onset, MFCC_COEFFICIENTS};
 
 use super::{
-    threshold::{Advanced, AdvancedSettings},
+    threshold::{
+        Advanced, AdvancedSettings, CooldownBank, CooldownSettings, FrameSpan, DEFAULT_FRAME_PERIOD,
+    },
     MelFilterBank, MelFilterBankSettings, OnsetDetector,
 };
 
@@ -266,14 +271,176 @@ pub struct SpecFlux {
     filter_bank: MelFilterBank,
     old_spectrum: Vec<f32>,
     spectrum: Vec<f32>,
+    flux: Vec<f32>,
+    masks: Masks,
     threshold: ThresholdBank,
+    cooldown: CooldownBank,
+    timbre: bool,
+    whiten_decay: Option<f32>,
+    peak_memory: Vec<f32>,
+    weights: BandWeights,
+    strength: OnsetStrength,
+    max_filter_bands: usize,
+    max_filtered: Vec<f32>,
+}
+
+/// What the strength of an onset is taken from
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd)]
+pub enum OnsetStrength {
+    /// RMS or peak of the audio buffer
+    #[default]
+    Level,
+    /// How far the band crossed its threshold, so barely detected onsets are weak
+    Threshold,
 }
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, PartialOrd)]
+/// Ratio of weight to threshold at which an onset gets the full strength
+const FULL_STRENGTH_RATIO: f32 = 4.0;
+
+/// Weighted flux of every band the thresholds are applied to
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub struct BandWeights {
+    pub drum: f32,
+    pub hihat: f32,
+    pub note: f32,
+    pub full: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
 #[serde(default, rename_all = "PascalCase")]
 pub struct SpecFluxSettings {
     pub filter_bank_settings: MelFilterBankSettings,
     pub threshold_bank_settings: ThresholdBankSettings,
+    pub masks: MaskSettings,
+    /// Time after an onset in which the same band can't trigger again
+    pub min_interval: CooldownSettings,
+    /// Emit the MFCCs of every frame as [`Onset::Timbre`]
+    #[serde(rename = "timbre")]
+    pub timbre: bool,
+    /// Divide every band by its decaying peak before computing the flux,
+    /// so consistently loud bands don't dominate
+    #[serde(rename = "whiten")]
+    pub whiten: bool,
+    /// Factor the peak of each band decays by every frame
+    #[serde(rename = "whiten_decay")]
+    pub whiten_decay: f32,
+    #[serde(rename = "strength")]
+    pub strength: OnsetStrength,
+    /// Width in bands of the maximum filter applied to the previous spectrum (SuperFlux),
+    /// suppresses vibrato and pitch glides, 0 disables it
+    #[serde(rename = "max_filter_bands")]
+    pub max_filter_bands: usize,
+}
+
+impl Default for SpecFluxSettings {
+    fn default() -> Self {
+        Self {
+            filter_bank_settings: MelFilterBankSettings::default(),
+            threshold_bank_settings: ThresholdBankSettings::default(),
+            masks: MaskSettings::default(),
+            min_interval: CooldownSettings::default(),
+            timbre: false,
+            whiten: false,
+            whiten_decay: 0.997,
+            strength: OnsetStrength::Level,
+            max_filter_bands: 0,
+        }
+    }
+}
+
+/// Lower bound of the whitening peak so silent bands aren't amplified
+const WHITENING_FLOOR: f32 = 1e-3;
+
+/// Weighting of the mel bands used to tell drums, notes and hihats apart.
+/// Either given inline or as path to a file with comma or whitespace separated values.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[serde(untagged)]
+pub enum Mask {
+    Values(Vec<f32>),
+    File(String),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct MaskSettings {
+    pub drum: Option<Mask>,
+    pub hihat: Option<Mask>,
+    pub note: Option<Mask>,
+}
+
+struct Masks {
+    drum: Vec<f32>,
+    hihat: Vec<f32>,
+    note: Vec<f32>,
+}
+
+impl Masks {
+    fn with_settings(settings: &MaskSettings, bands: usize) -> Self {
+        Self {
+            drum: load_mask(settings.drum.as_ref(), KICK_MASK, bands, "Drum"),
+            hihat: load_mask(settings.hihat.as_ref(), HIHAT_MASK, bands, "Hihat"),
+            note: load_mask(settings.note.as_ref(), SNARE_MASK, bands, "Note"),
+        }
+    }
+}
+
+fn read_mask(path: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    let values = contents
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<f32>, _>>()?;
+    Ok(values)
+}
+
+fn load_mask(mask: Option<&Mask>, default: &[f32], bands: usize, name: &str) -> Vec<f32> {
+    let values = match mask {
+        None => default.to_vec(),
+        Some(Mask::Values(values)) => values.clone(),
+        Some(Mask::File(path)) => match read_mask(path) {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Could not load {name} mask from {path}: {e}");
+                warn!("Using the default {name} mask instead");
+                default.to_vec()
+            }
+        },
+    };
+
+    let values = if values.is_empty() {
+        warn!("{name} mask is empty, using the default mask instead");
+        default.to_vec()
+    } else {
+        values
+    };
+
+    if values.len() != bands {
+        warn!(
+            "{name} mask has {} values but {bands} bands are used, resampling the mask",
+            values.len()
+        );
+        warn!("Detection may be degraded, consider providing a mask matching the band count");
+        return resample_mask(&values, bands);
+    }
+
+    values
+}
+
+/// Linearly interpolates the mask to `bands` values
+fn resample_mask(mask: &[f32], bands: usize) -> Vec<f32> {
+    if mask.len() == 1 || bands == 1 {
+        return vec![mask[0]; bands];
+    }
+    let step = (mask.len() - 1) as f32 / (bands - 1) as f32;
+    (0..bands)
+        .map(|i| {
+            let position = i as f32 * step;
+            let index = (position as usize).min(mask.len() - 2);
+            let t = position - index as f32;
+            mask[index] * (1.0 - t) + mask[index + 1] * t
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
@@ -291,13 +458,13 @@ impl Default for ThresholdBankSettings {
             drum: AdvancedSettings {
                 fixed_threshold: 0.2,
                 dynamic_threshold: 0.4,
-                mean_range: 5,
+                mean_range: FrameSpan::Frames(5),
                 ..Default::default()
             },
             hihat: AdvancedSettings {
                 fixed_threshold: 0.5,
                 dynamic_threshold: 0.55,
-                mean_range: 3,
+                mean_range: FrameSpan::Frames(3),
                 ..Default::default()
             },
             note: AdvancedSettings {
@@ -318,19 +485,19 @@ struct ThresholdBank {
 }
 
 impl ThresholdBank {
-    pub fn with_settings(settings: ThresholdBankSettings) -> Self {
+    pub fn with_settings(settings: ThresholdBankSettings, frame_period: Duration) -> Self {
         Self {
-            drum: Advanced::with_settings(settings.drum),
-            hihat: Advanced::with_settings(settings.hihat),
-            note: Advanced::with_settings(settings.note),
-            full: Advanced::with_settings(settings.full),
+            drum: Advanced::with_settings_timed(settings.drum, frame_period),
+            hihat: Advanced::with_settings_timed(settings.hihat, frame_period),
+            note: Advanced::with_settings_timed(settings.note, frame_period),
+            full: Advanced::with_settings_timed(settings.full, frame_period),
         }
     }
 }
 
 impl Default for ThresholdBank {
     fn default() -> Self {
-        ThresholdBank::with_settings(ThresholdBankSettings::default())
+        ThresholdBank::with_settings(ThresholdBankSettings::default(), DEFAULT_FRAME_PERIOD)
     }
 }
 
@@ -342,25 +509,56 @@ impl SpecFlux {
         let threshold = ThresholdBank::default();
         let spectrum = vec![0.0; bands];
         let old_spectrum = vec![0.0; bands];
+        let flux = vec![0.0; bands];
+        let masks = Masks::with_settings(&MaskSettings::default(), bands);
         Self {
             filter_bank: bank,
             spectrum,
             old_spectrum,
+            flux,
+            masks,
             threshold,
+            cooldown: CooldownBank::default(),
+            timbre: false,
+            whiten_decay: None,
+            peak_memory: vec![WHITENING_FLOOR; bands],
+            weights: BandWeights::default(),
+            strength: OnsetStrength::Level,
+            max_filter_bands: 0,
+            max_filtered: vec![0.0; bands],
         }
     }
 
-    pub fn with_settings(sample_rate: u32, fft_size: u32, settings: SpecFluxSettings) -> Self {
+    pub fn with_settings(
+        sample_rate: u32,
+        fft_size: u32,
+        hop_size: usize,
+        settings: SpecFluxSettings,
+    ) -> Self {
         let bank =
             MelFilterBank::with_settings(sample_rate, fft_size, settings.filter_bank_settings);
-        let threshold = ThresholdBank::with_settings(settings.threshold_bank_settings);
+        let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
+        let threshold =
+            ThresholdBank::with_settings(settings.threshold_bank_settings, frame_period);
         let spectrum = vec![0.0; settings.filter_bank_settings.bands];
         let old_spectrum = vec![0.0; settings.filter_bank_settings.bands];
+        let flux = vec![0.0; settings.filter_bank_settings.bands];
+        let masks = Masks::with_settings(&settings.masks, settings.filter_bank_settings.bands);
         Self {
             filter_bank: bank,
             old_spectrum,
             spectrum,
+            flux,
+            masks,
             threshold,
+            cooldown: CooldownBank::with_settings(settings.min_interval, frame_period),
+            timbre: settings.timbre,
+            whiten_decay: settings.whiten.then_some(settings.whiten_decay),
+            peak_memory: vec![WHITENING_FLOOR; settings.filter_bank_settings.bands],
+            weights: BandWeights::default(),
+            strength: settings.strength,
+            max_filter_bands: settings.max_filter_bands,
+            max_filtered: vec![0.0; settings.filter_bank_settings.bands],
         }
     }
 
@@ -371,25 +569,67 @@ impl SpecFlux {
 
         self.filter_bank.filter(freq_bins, &mut self.spectrum);
 
+        let timbre = self.timbre.then(|| {
+            let mut coefficients = [0.0; MFCC_COEFFICIENTS];
+            self.filter_bank
+                .mfcc(&self.spectrum, MFCC_COEFFICIENTS, &mut coefficients);
+            coefficients
+        });
+
         self.spectrum
             .iter_mut()
             .for_each(|x| *x = (*x * lambda).ln_1p());
 
-        let flux = self
-            .old_spectrum
-            .iter()
-            .zip(&self.spectrum)
-            .map(|(&a, &b)| ((b - a).max(0.0)));
+        if let Some(decay) = self.whiten_decay {
+            self.spectrum
+                .iter_mut()
+                .zip(self.peak_memory.iter_mut())
+                .for_each(|(x, peak)| {
+                    *peak = x.max(*peak * decay).max(WHITENING_FLOOR);
+                    *x /= *peak;
+                });
+        }
+
+        // A pitch glide moves energy into a neighbouring band,
+        // which stays below the maximum of the previous frame around it
+        let old_spectrum = if self.max_filter_bands > 1 {
+            let radius = self.max_filter_bands / 2;
+            let bands = self.old_spectrum.len();
+            for (i, x) in self.max_filtered.iter_mut().enumerate() {
+                let window =
+                    &self.old_spectrum[i.saturating_sub(radius)..(i + radius + 1).min(bands)];
+                *x = window.iter().fold(0.0, |max, &v| v.max(max));
+            }
+            &self.max_filtered
+        } else {
+            &self.old_spectrum
+        };
+
+        self.flux
+            .iter_mut()
+            .zip(old_spectrum.iter().zip(&self.spectrum))
+            .for_each(|(f, (&a, &b))| *f = (b - a).max(0.0));
+
+        let flux = self.flux.iter();
+
+        let weight = compensated_sum(flux.clone().copied());
 
-        let weight: f32 = flux.clone().sum();
+        let drum_weight = compensated_sum(flux.clone().zip(&self.masks.drum).map(|(d, &w)| d * w));
 
-        let drum_weight: f32 = flux.clone().zip(KICK_MASK).map(|(d, &w)| d * w).sum();
+        let hihat_weight =
+            compensated_sum(flux.clone().zip(&self.masks.hihat).map(|(d, &w)| d * w));
 
-        let hihat_weight: f32 = flux.clone().zip(HIHAT_MASK).map(|(d, &w)| d * w).sum();
+        let note_weight = compensated_sum(flux.clone().zip(&self.masks.note).map(|(d, &w)| d * w));
 
-        let note_weight: f32 = flux.clone().zip(SNARE_MASK).map(|(d, &w)| d * w).sum();
+        self.weights = BandWeights {
+            drum: drum_weight,
+            hihat: hihat_weight,
+            note: note_weight,
+            full: weight,
+        };
 
-        let onset = self.threshold.full.is_above(weight);
+        let full = self.threshold.full.exceeds(weight);
+        let onset = self.cooldown.full.gate(full.is_some());
 
         let index_of_max = freq_bins
             .iter()
@@ -402,24 +642,53 @@ impl SpecFlux {
 
         onsets.push(Onset::Raw(hihat_weight));
 
+        if let Some(coefficients) = timbre {
+            onsets.push(Onset::Timbre(coefficients));
+        }
+
         if onset {
-            onsets.push(Onset::Full(rms));
+            onsets.push(Onset::Full(self.onset_strength(full, rms)));
         }
 
-        if self.threshold.drum.is_above(drum_weight) {
-            onsets.push(Onset::Drum(rms));
+        let drum = self.threshold.drum.exceeds(drum_weight);
+        if self.cooldown.drum.gate(drum.is_some()) {
+            onsets.push(Onset::Drum(self.onset_strength(drum, rms)));
         }
 
-        if self.threshold.hihat.is_above(hihat_weight) {
-            onsets.push(Onset::Hihat(peak));
+        let hihat = self.threshold.hihat.exceeds(hihat_weight);
+        if self.cooldown.hihat.gate(hihat.is_some()) {
+            onsets.push(Onset::Hihat(self.onset_strength(hihat, peak)));
         }
 
-        if self.threshold.note.is_above(note_weight) {
-            onsets.push(Onset::Note(rms, index_of_max as u16));
+        let note = self.threshold.note.exceeds(note_weight);
+        if self.cooldown.note.gate(note.is_some()) {
+            onsets.push(Onset::Note(
+                self.onset_strength(note, rms),
+                index_of_max as u16,
+            ));
         }
 
         onsets
     }
+
+    fn onset_strength(&self, ratio: Option<f32>, level: f32) -> f32 {
+        match (self.strength, ratio) {
+            (OnsetStrength::Threshold, Some(ratio)) => {
+                (ratio / FULL_STRENGTH_RATIO).clamp(0.0, 1.0)
+            }
+            _ => level,
+        }
+    }
+
+    /// Positive spectral flux per mel band of the last processed frame
+    pub fn last_flux(&self) -> &[f32] {
+        &self.flux
+    }
+
+    /// Band weights of the last processed frame
+    pub fn last_weights(&self) -> BandWeights {
+        self.weights
+    }
 }
 
 impl OnsetDetector for SpecFlux {
@@ -1,9 +1,20 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use super::{apply_window_mono, window, WindowType};
 
+/// Value of the past samples the dynamic threshold is relative to
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
+pub enum ThresholdReference {
+    #[default]
+    Max,
+    /// More robust against single outliers than the maximum
+    Median,
+    /// Percentile between 0 and 1
+    Percentile(f32),
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct DynamicSettings {
@@ -11,6 +22,7 @@ pub struct DynamicSettings {
     pub min_intensity: f32,
     pub delta_intensity: f32,
     pub window_type: WindowType,
+    pub reference: ThresholdReference,
 }
 
 impl Default for DynamicSettings {
@@ -20,6 +32,7 @@ impl Default for DynamicSettings {
             min_intensity: 0.2,
             delta_intensity: 0.15,
             window_type: WindowType::Hann,
+            reference: ThresholdReference::Max,
         }
     }
 }
@@ -31,6 +44,7 @@ pub struct Dynamic {
     min_intensity: f32,
     delta_intensity: f32,
     window: Vec<f32>,
+    reference: ThresholdReference,
 }
 
 #[allow(dead_code)]
@@ -45,6 +59,7 @@ impl Dynamic {
             min_intensity,
             delta_intensity,
             window_type,
+            reference,
         } = settings;
         Dynamic {
             past_samples: VecDeque::with_capacity(buffer_size),
@@ -52,6 +67,7 @@ impl Dynamic {
             min_intensity,
             delta_intensity,
             window: window(buffer_size, window_type),
+            reference,
         }
     }
 
@@ -63,15 +79,20 @@ impl Dynamic {
             self.past_samples.push_back(value);
         }
 
-        let max = self
-            .past_samples
-            .iter()
-            .fold(f32::MIN, |a, b| f32::max(a, *b));
+        let max = match self.reference {
+            ThresholdReference::Max => self
+                .past_samples
+                .iter()
+                .fold(f32::MIN, |a, b| f32::max(a, *b)),
+            ThresholdReference::Median => self.percentile(0.5),
+            ThresholdReference::Percentile(p) => self.percentile(p),
+        };
 
         let mut normalized: Vec<f32> = self
             .past_samples
             .iter()
-            .map(|s| s / max)
+            // Samples above a percentile reference would otherwise dominate the sum
+            .map(|s| (s / max).min(1.0))
             .map(|s| s.powi(2))
             .chain(std::iter::repeat(0.0).take(self.buffer_size - self.past_samples.len()))
             .collect();
@@ -85,6 +106,13 @@ impl Dynamic {
     pub fn is_above(&mut self, value: f32) -> bool {
         value > self.get_threshold(value)
     }
+
+    fn percentile(&self, p: f32) -> f32 {
+        let mut sorted: Vec<f32> = self.past_samples.iter().copied().collect();
+        sorted.sort_by(f32::total_cmp);
+        let index = (p.clamp(0.0, 1.0) * (sorted.len() - 1) as f32).round() as usize;
+        sorted[index]
+    }
 }
 
 impl Default for Dynamic {
@@ -93,26 +121,88 @@ impl Default for Dynamic {
     }
 }
 
+/// Frame period of the default audio settings, used when no frame period is known
+pub const DEFAULT_FRAME_PERIOD: Duration = Duration::from_millis(10);
+
+/// Length given either as number of frames or as time, e.g. `3` or `"20ms"`.
+/// Times stay the same when `hop_size` or `sample_rate` change.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum FrameSpan {
+    Frames(usize),
+    Time(Duration),
+}
+
+impl FrameSpan {
+    pub fn frames(&self, frame_period: Duration) -> usize {
+        match self {
+            FrameSpan::Frames(frames) => *frames,
+            FrameSpan::Time(time) => {
+                (time.as_secs_f64() / frame_period.as_secs_f64()).round() as usize
+            }
+        }
+    }
+}
+
+impl Serialize for FrameSpan {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FrameSpan::Frames(frames) => serializer.serialize_u64(*frames as u64),
+            FrameSpan::Time(time) => serializer.serialize_str(&format!("{}ms", time.as_millis())),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameSpan {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Frames(usize),
+            Time(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Frames(frames) => Ok(FrameSpan::Frames(frames)),
+            Raw::Time(time) => parse_duration(&time).map(FrameSpan::Time).ok_or_else(|| {
+                serde::de::Error::custom(format!(
+                    "invalid duration \"{time}\", expected e.g. \"20ms\" or \"0.1s\""
+                ))
+            }),
+        }
+    }
+}
+
+fn parse_duration(time: &str) -> Option<Duration> {
+    let time = time.trim();
+    let (value, scale) = if let Some(ms) = time.strip_suffix("ms") {
+        (ms, 1e-3)
+    } else {
+        (time.strip_suffix('s')?, 1.0)
+    };
+    let seconds = value.trim().parse::<f64>().ok()? * scale;
+    (seconds >= 0.0).then(|| Duration::from_secs_f64(seconds))
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct AdvancedSettings {
-    pub mean_range: usize,
-    pub max_range: usize,
+    pub mean_range: FrameSpan,
+    pub max_range: FrameSpan,
     pub dynamic_threshold: f32,
-    pub threshold_range: usize,
+    pub threshold_range: FrameSpan,
     pub fixed_threshold: f32,
-    pub delay: usize,
+    pub delay: FrameSpan,
 }
 
 impl Default for AdvancedSettings {
     fn default() -> Self {
         AdvancedSettings {
-            mean_range: 6,
-            max_range: 3,
+            mean_range: FrameSpan::Frames(6),
+            max_range: FrameSpan::Frames(3),
             dynamic_threshold: 0.8,
-            threshold_range: 8,
+            threshold_range: FrameSpan::Frames(8),
             fixed_threshold: 0.5,
-            delay: 2,
+            delay: FrameSpan::Frames(2),
         }
     }
 }
@@ -125,7 +215,7 @@ pub struct Advanced {
     threshold_range: usize,
     fixed_threshold: f32,
     delay: usize,
-    delay_slots: VecDeque<bool>,
+    delay_slots: VecDeque<Option<f32>>,
 }
 
 impl Advanced {
@@ -134,23 +224,31 @@ impl Advanced {
     }
 
     pub fn with_settings(settings: AdvancedSettings) -> Self {
-        let len = settings
-            .max_range
-            .max(settings.mean_range)
-            .max(settings.threshold_range);
+        Self::with_settings_timed(settings, DEFAULT_FRAME_PERIOD)
+    }
+
+    /// Converts the time based ranges with the time between two frames
+    pub fn with_settings_timed(settings: AdvancedSettings, frame_period: Duration) -> Self {
+        let mean_range = settings.mean_range.frames(frame_period).max(1);
+        let max_range = settings.max_range.frames(frame_period).max(1);
+        let threshold_range = settings.threshold_range.frames(frame_period).max(1);
+        let delay = settings.delay.frames(frame_period);
+
+        let len = max_range.max(mean_range).max(threshold_range);
         Advanced {
             past_samples: VecDeque::from(vec![0.0; len]),
-            mean_range: settings.mean_range,
-            max_range: settings.max_range,
+            mean_range,
+            max_range,
             dynamic_threshold: settings.dynamic_threshold,
-            threshold_range: settings.threshold_range,
+            threshold_range,
             fixed_threshold: settings.fixed_threshold,
-            delay: settings.delay,
-            delay_slots: VecDeque::from(vec![false; settings.delay + 1]),
+            delay,
+            delay_slots: VecDeque::from(vec![None; delay + 1]),
         }
     }
 
-    pub fn is_above(&mut self, value: f32) -> bool {
+    /// Max, mean and norm of the past values before `value` is added
+    fn push(&mut self, value: f32) -> (f32, f32, f32) {
         let max = self
             .past_samples
             .iter()
@@ -168,9 +266,33 @@ impl Advanced {
         self.past_samples.pop_front();
         self.past_samples.push_back(value);
 
-        let onset = value >= max
-            && value >= mean + norm * self.dynamic_threshold + self.fixed_threshold
-            && !self.delay_slots[0];
+        (max, mean, norm)
+    }
+
+    /// How far `value` exceeds the threshold without `fixed_threshold`,
+    /// `None` if it isn't a local maximum. Used to calibrate `fixed_threshold`.
+    pub fn dynamic_margin(&mut self, value: f32) -> Option<f32> {
+        let (max, mean, norm) = self.push(value);
+        (value >= max).then_some(value - mean - norm * self.dynamic_threshold)
+    }
+
+    pub fn is_above(&mut self, value: f32) -> bool {
+        self.exceeds(value).is_some()
+    }
+
+    /// Ratio of the value to the threshold it crossed, `None` if there is no onset
+    pub fn exceeds(&mut self, value: f32) -> Option<f32> {
+        let (max, mean, norm) = self.push(value);
+        let threshold = mean + norm * self.dynamic_threshold + self.fixed_threshold;
+
+        let onset =
+            (value >= max && value >= threshold && self.delay_slots[0].is_none()).then(|| {
+                if threshold > 0.0 {
+                    value / threshold
+                } else {
+                    f32::INFINITY
+                }
+            });
         self.delay_slots.pop_back();
         self.delay_slots.push_front(onset);
 
@@ -183,3 +305,95 @@ impl Default for Advanced {
         Advanced::with_settings(AdvancedSettings::default())
     }
 }
+
+/// Minimum time between two onsets of the same band
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct CooldownSettings {
+    pub drum: Duration,
+    pub hihat: Duration,
+    pub note: Duration,
+    pub full: Duration,
+}
+
+impl Default for CooldownSettings {
+    fn default() -> Self {
+        Self {
+            drum: Duration::from_millis(50),
+            hihat: Duration::from_millis(30),
+            note: Duration::from_millis(50),
+            full: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Suppresses further onsets of a band for `min_interval` after one was detected.
+/// The interval is counted in frames, so it holds when files are processed faster than real time.
+#[derive(Debug, Clone, Copy)]
+pub struct Cooldown {
+    min_frames: usize,
+    /// Frames since the last onset passed the gate
+    since_fire: Option<usize>,
+}
+
+impl Cooldown {
+    pub fn init(min_interval: Duration, frame_period: Duration) -> Self {
+        Self {
+            min_frames: FrameSpan::Time(min_interval).frames(frame_period),
+            since_fire: None,
+        }
+    }
+
+    /// Called once per frame with whether the band detected an onset
+    pub fn gate(&mut self, onset: bool) -> bool {
+        if let Some(frames) = &mut self.since_fire {
+            *frames += 1;
+        }
+        if !onset
+            || self
+                .since_fire
+                .is_some_and(|frames| frames < self.min_frames)
+        {
+            return false;
+        }
+        self.since_fire = Some(0);
+        true
+    }
+}
+
+pub struct CooldownBank {
+    pub drum: Cooldown,
+    pub hihat: Cooldown,
+    pub note: Cooldown,
+    pub full: Cooldown,
+}
+
+impl CooldownBank {
+    pub fn with_settings(settings: CooldownSettings, frame_period: Duration) -> Self {
+        Self {
+            drum: Cooldown::init(settings.drum, frame_period),
+            hihat: Cooldown::init(settings.hihat, frame_period),
+            note: Cooldown::init(settings.note, frame_period),
+            full: Cooldown::init(settings.full, frame_period),
+        }
+    }
+}
+
+impl Default for CooldownBank {
+    fn default() -> Self {
+        CooldownBank::with_settings(CooldownSettings::default(), DEFAULT_FRAME_PERIOD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_counts_frames() {
+        let mut cooldown = Cooldown::init(Duration::from_millis(50), Duration::from_millis(10));
+        let passed: Vec<bool> = (0..12).map(|_| cooldown.gate(true)).collect();
+        let expected: Vec<bool> = (0..12).map(|frame| frame % 5 == 0).collect();
+        assert_eq!(passed, expected);
+    }
+}
@@ -1,44 +1,80 @@
-use std::{fs::File, io::BufReader};
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::BufReader,
+    time::{Duration, Instant},
+};
 
-use rodio::{Decoder, Source};
+use rodio::{source::UniformSourceIterator, Decoder, Source};
+use tracing::info;
 
 use super::{
-    audioprocessing::{hfc::Hfc, Buffer, ProcessingSettings},
+    audioprocessing::{
+        hfc::Hfc,
+        spectral_flux::{SpecFlux, SpecFluxSettings, ThresholdBankSettings},
+        threshold::{Advanced, AdvancedSettings},
+        Buffer, OnsetDetector, ProcessingSettings,
+    },
+    config::Config,
     lights::{serialize, LightService},
 };
 
-pub fn process_file(filename: &str, settings: ProcessingSettings) {
-    let file = BufReader::new(File::open(filename).unwrap());
+/// Decodes an audio file into interleaved samples at `sample_rate`.
+/// Returns the samples and the number of channels.
+fn decode(filename: &str, sample_rate: u32) -> Result<(Vec<f32>, u16), Box<dyn Error>> {
+    let file = File::open(filename).map_err(|e| format!("Can't open {filename}: {e}"))?;
+    let source = Decoder::new(BufReader::new(file)).map_err(|e| {
+        format!("Can't decode {filename}, supported are WAV, MP3, FLAC and OGG: {e}")
+    })?;
 
-    let source = Decoder::new(file).unwrap();
+    // Mono files stay mono, the detection mixes all channels down anyway
+    let channels = source.channels();
+    if channels == 0 {
+        return Err(format!("{filename} has no audio channels").into());
+    }
+    if source.sample_rate() != sample_rate {
+        info!(
+            "Resampling {filename} from {}Hz to {sample_rate}Hz",
+            source.sample_rate()
+        );
+    }
+
+    // The bin resolution of the detectors depends on the sample rate
+    let samples: Vec<f32> =
+        UniformSourceIterator::new(source.convert_samples::<f32>(), channels, sample_rate)
+            .collect();
+    Ok((samples, channels))
+}
+
+pub fn process_file(filename: &str, settings: ProcessingSettings) -> Result<(), Box<dyn Error>> {
+    let (samples, channels) = decode(filename, settings.sample_rate)?;
 
     let serializer = serialize::OnsetContainer::init(
         &(filename.split('.').next().unwrap().to_owned() + ".cbor"),
         settings.sample_rate as usize,
         settings.hop_size,
+        Duration::ZERO,
     );
 
-    let channels = source.channels();
-    let sample_rate = source.sample_rate();
-
     let ProcessingSettings {
+        sample_rate,
         buffer_size,
         hop_size,
         fft_size,
         ..
     } = settings;
 
+    let mut hfc = Hfc::init(sample_rate as usize, fft_size, hop_size);
+
     let buffer_size = buffer_size * channels as usize;
     let hop_size = hop_size * channels as usize;
 
-    let mut hfc = Hfc::init(sample_rate as usize, fft_size);
-
     let mut lightservices: Vec<Box<dyn LightService + Send>> = vec![Box::new(serializer)];
 
     let mut buffer_detection = Buffer::init(channels, &settings);
-    let samples: Vec<f32> = source.convert_samples().collect();
 
-    let n = samples.len() / hop_size;
+    let n = samples.len().saturating_sub(buffer_size) / hop_size;
 
     (0..n).for_each(|i| {
         buffer_detection.process_raw(&samples[i * hop_size..buffer_size + i * hop_size]);
@@ -50,4 +86,204 @@ pub fn process_file(filename: &str, settings: ProcessingSettings) {
         lightservices.process_onsets(&onsets);
         lightservices.update();
     });
+    Ok(())
+}
+
+/// Margins above the dynamic threshold of every local maximum of one band
+struct BandMargins {
+    threshold: Advanced,
+    margins: Vec<f32>,
+}
+
+impl BandMargins {
+    fn init(settings: AdvancedSettings, frame_period: Duration) -> Self {
+        Self {
+            threshold: Advanced::with_settings_timed(settings, frame_period),
+            margins: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, weight: f32) {
+        if let Some(margin) = self.threshold.dynamic_margin(weight) {
+            self.margins.push(margin);
+        }
+    }
+
+    /// Fixed threshold that lets `count` local maxima through
+    fn fixed_threshold(mut self, count: usize) -> f32 {
+        self.margins.sort_by(|a, b| b.total_cmp(a));
+        match self.margins.get(count.max(1) - 1).or(self.margins.last()) {
+            Some(&margin) => margin.max(0.0),
+            None => 0.0,
+        }
+    }
+}
+
+/// Runs the spectral flux over interleaved `samples` and suggests the fixed thresholds
+/// of every band so that about `onsets_per_second` onsets are detected.
+/// The dynamic thresholds and ranges are kept from `settings`.
+pub fn calibrate(
+    samples: &[f32],
+    channels: u16,
+    processing_settings: ProcessingSettings,
+    settings: SpecFluxSettings,
+    onsets_per_second: f32,
+) -> ThresholdBankSettings {
+    let ProcessingSettings {
+        sample_rate,
+        buffer_size,
+        hop_size,
+        fft_size,
+        ..
+    } = processing_settings;
+
+    let frame_period = Duration::from_secs_f64(hop_size as f64 / sample_rate as f64);
+    let thresholds = settings.threshold_bank_settings;
+    let mut drum = BandMargins::init(thresholds.drum, frame_period);
+    let mut hihat = BandMargins::init(thresholds.hihat, frame_period);
+    let mut note = BandMargins::init(thresholds.note, frame_period);
+    let mut full = BandMargins::init(thresholds.full, frame_period);
+
+    let mut spec_flux = SpecFlux::with_settings(sample_rate, fft_size as u32, hop_size, settings);
+    let mut buffer = Buffer::init(channels, &processing_settings);
+
+    let buffer_size = buffer_size * channels as usize;
+    let hop_size = hop_size * channels as usize;
+    let frames = samples.len().saturating_sub(buffer_size) / hop_size;
+
+    for i in 0..frames {
+        buffer.process_raw(&samples[i * hop_size..buffer_size + i * hop_size]);
+        spec_flux.detect(&buffer.freq_bins, buffer.peak, buffer.rms);
+        let weights = spec_flux.last_weights();
+        drum.push(weights.drum);
+        hihat.push(weights.hihat);
+        note.push(weights.note);
+        full.push(weights.full);
+    }
+
+    let onsets = (frames as f32 * frame_period.as_secs_f32() * onsets_per_second).round() as usize;
+
+    ThresholdBankSettings {
+        drum: AdvancedSettings {
+            fixed_threshold: drum.fixed_threshold(onsets),
+            ..thresholds.drum
+        },
+        hihat: AdvancedSettings {
+            fixed_threshold: hihat.fixed_threshold(onsets),
+            ..thresholds.hihat
+        },
+        note: AdvancedSettings {
+            fixed_threshold: note.fixed_threshold(onsets),
+            ..thresholds.note
+        },
+        full: AdvancedSettings {
+            fixed_threshold: full.fixed_threshold(onsets),
+            ..thresholds.full
+        },
+    }
+}
+
+/// Calibrates the thresholds on an audio file
+pub fn calibrate_file(
+    filename: &str,
+    processing_settings: ProcessingSettings,
+    settings: SpecFluxSettings,
+    onsets_per_second: f32,
+) -> Result<ThresholdBankSettings, Box<dyn Error>> {
+    let (samples, channels) = decode(filename, processing_settings.sample_rate)?;
+
+    Ok(calibrate(
+        &samples,
+        channels,
+        processing_settings,
+        settings,
+        onsets_per_second,
+    ))
+}
+
+/// Thresholds as TOML snippet for the config
+pub fn toml_snippet(thresholds: ThresholdBankSettings) -> Result<String, Box<dyn Error>> {
+    let mut detector = toml::Table::new();
+    detector.insert(
+        "ThresholdBankSettings".to_owned(),
+        toml::Value::try_from(thresholds)?,
+    );
+    let mut snippet = toml::Table::new();
+    snippet.insert("OnsetDetector".to_owned(), toml::Value::Table(detector));
+    Ok(toml::to_string(&snippet)?)
+}
+
+/// Processing time per frame of a file
+#[derive(Debug)]
+pub struct BenchmarkReport {
+    frame_times: Vec<Duration>,
+    audio_duration: Duration,
+}
+
+impl BenchmarkReport {
+    fn percentile(&self, percentile: f32) -> Duration {
+        let index = ((self.frame_times.len() - 1) as f32 * percentile).round() as usize;
+        self.frame_times[index]
+    }
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.frame_times.is_empty() {
+            return write!(f, "File is shorter than one buffer");
+        }
+        let total: Duration = self.frame_times.iter().sum();
+        let frames = self.frame_times.len() as f64;
+        writeln!(f, "Frames:           {}", self.frame_times.len())?;
+        writeln!(
+            f,
+            "Average:          {:?}",
+            total / self.frame_times.len() as u32
+        )?;
+        writeln!(f, "Median:           {:?}", self.percentile(0.5))?;
+        writeln!(f, "95th percentile:  {:?}", self.percentile(0.95))?;
+        writeln!(f, "99th percentile:  {:?}", self.percentile(0.99))?;
+        writeln!(f, "Max:              {:?}", self.percentile(1.0))?;
+        writeln!(f, "Frames/s:         {:.0}", frames / total.as_secs_f64())?;
+        write!(
+            f,
+            "Real-time factor: {:.1}x",
+            self.audio_duration.as_secs_f64() / total.as_secs_f64()
+        )
+    }
+}
+
+/// Measures the time `Buffer::process_raw` and the configured onset detector take for every frame of a file
+pub fn benchmark_file(filename: &str, config: &Config) -> Result<BenchmarkReport, Box<dyn Error>> {
+    let settings = config.audio_processing;
+    let (samples, channels) = decode(filename, settings.sample_rate)?;
+
+    let mut detector = config.initialize_onset_detector();
+    let mut buffer = Buffer::init(channels, &settings);
+
+    let buffer_size = settings.buffer_size * channels as usize;
+    let hop_size = settings.hop_size * channels as usize;
+    let frames = samples.len().saturating_sub(buffer_size) / hop_size;
+
+    let mut frame_times: Vec<Duration> = (0..frames)
+        .map(|i| {
+            let start = Instant::now();
+            buffer.process_raw(&samples[i * hop_size..buffer_size + i * hop_size]);
+            detector.detect_complex(
+                &buffer.complex_bins,
+                &buffer.freq_bins,
+                buffer.peak,
+                buffer.rms,
+            );
+            start.elapsed()
+        })
+        .collect();
+    frame_times.sort();
+
+    Ok(BenchmarkReport {
+        frame_times,
+        audio_duration: Duration::from_secs_f64(
+            samples.len() as f64 / channels as f64 / settings.sample_rate as f64,
+        ),
+    })
 }
@@ -1,35 +1,97 @@
-use std::{error::Error, fmt::Display, fs, net::Ipv4Addr};
+use std::{
+    error::Error, fmt::Display, fs, future::Future, net::Ipv4Addr, sync::Arc, time::Duration,
+};
 
-use log::info;
 use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{interval, timeout_at, Instant},
+};
+use tracing::{debug, info, warn, warn_span, Instrument};
 
 use super::{
+    audiodevices::{ActiveProfile, NormalizedOutput},
     audioprocessing::{
         self,
+        complex_flux::{ComplexFlux, ComplexFluxSettings},
+        cqt::{Cqt, CqtSettings},
         hfc::{Hfc, HfcSettings},
-        spectral_flux::{SpecFlux, SpecFluxSettings},
+        spectral_flux::{SpecFlux, SpecFluxSettings, ThresholdBankSettings},
         ProcessingSettings,
     },
+    keyboard::Controls,
+    learned,
     lights::{
+        artnet::{ArtNetError, ArtNetOutput, ArtNetSettings},
         console::Console,
+        event_sink::{EventSink, EventSinkError, EventSinkSettings},
         hue::{self, HueError, HueSettings},
+        lifx::{Lifx, LifxError, LifxSettings},
+        monitor::Monitor,
+        nanoleaf::{Nanoleaf, NanoleafError, NanoleafSettings},
         serialize,
-        wled::{self, OnsetSettings, SpectrumSettings, WLEDError},
-        LightService,
+        status::{StatusServer, StatusSettings},
+        websocket::{WebSocketError, WebSocketServer, WebSocketSettings},
+        wled::{self, OnsetSettings, SegmentSelector, SpectrumSettings, WLEDError},
+        LightService, Reactions,
     },
+    simulation::SimulationSettings,
 };
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+/// Time all light services together get to connect, without the Hue push link timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How often the `profile_file` is checked for a different profile
+const PROFILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type Connection = JoinHandle<Result<Box<dyn LightService + Send>, LightServiceError>>;
+
+/// Connects a light in its own task. Everything it logs, including the writes of its poller,
+/// is tagged with `name`, the span is enabled at the default level for that reason.
+fn spawn_connection(
+    name: &str,
+    connect: impl Future<Output = Result<Box<dyn LightService + Send>, LightServiceError>>
+        + Send
+        + 'static,
+) -> Connection {
+    tokio::spawn(connect.instrument(warn_span!("light", service = name)))
+}
+pub type ProfileSender = watch::Sender<Option<ActiveProfile>>;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(rename_all = "PascalCase")]
 pub struct Config {
     #[serde(default, rename = "audio_device")]
-    pub audio_device: String,
+    pub audio_device: AudioDevice,
 
     #[serde(default, rename = "console_output")]
     pub console_output: bool,
 
+    /// Full screen terminal display of the spectrum, onsets and thresholds, replaces `console_output`
+    #[serde(default, rename = "monitor")]
+    pub monitor: bool,
+
     #[serde(default, rename = "serialize_onsets")]
     pub serialize_onsets: Option<String>,
+    /// Scale the onset strengths sent to the lights by the long-term loudness, so every song is about equally bright
+    #[serde(default, rename = "normalize_output")]
+    pub normalize_output: bool,
+
+    /// File containing the name of the active profile, an empty or missing file uses no profile
+    #[serde(default, rename = "profile_file")]
+    pub profile_file: Option<String>,
+
+    /// Pause, dim and turn off the lights with the keyboard while syncing
+    #[serde(default, rename = "keyboard_control")]
+    pub keyboard_control: bool,
+
+    /// Format of the log messages, the level is still set with `RUST_LOG`
+    #[serde(default, rename = "log_format")]
+    pub log_format: LogFormat,
+
+    /// Serialized onsets of the same type closer than this are merged into the strongest one
+    #[serde(default)]
+    pub serialize_merge_window: Duration,
 
     #[serde(default, rename = "Audio")]
     pub audio_processing: ProcessingSettings,
@@ -37,11 +99,100 @@ pub struct Config {
     #[serde(default)]
     pub onset_detector: OnsetDetector,
 
+    /// Synthetic signal used with `--simulate`
     #[serde(default)]
+    pub simulation: SimulationSettings,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub hue: Vec<HueSettings>,
 
-    #[serde(default, rename = "WLED")]
+    #[serde(default, rename = "WLED", skip_serializing_if = "Vec::is_empty")]
     pub wled: Vec<WLEDConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nanoleaf: Vec<NanoleafSettings>,
+
+    #[serde(default, rename = "LIFX", skip_serializing_if = "Vec::is_empty")]
+    pub lifx: Vec<LifxSettings>,
+
+    #[serde(default, rename = "ArtNet", skip_serializing_if = "Vec::is_empty")]
+    pub artnet: Vec<ArtNetSettings>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub event_sink: Vec<EventSinkSettings>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub web_socket: Vec<WebSocketSettings>,
+
+    /// HTTP server with the audio levels, onset rates and connected lights as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusSettings>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profile: Vec<Profile>,
+}
+
+/// Format of the log messages written to stderr
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    /// One JSON object per line, including the fields of the spans the message was logged in
+    Json,
+}
+
+/// Settings that replace the ones of the config while the profile is active.
+/// The settings of the light services can't be switched, so any other key is rejected.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Profile {
+    #[serde(rename = "name")]
+    pub name: String,
+    pub onset_detector: Option<OnsetDetector>,
+    /// Onset bands all light services react to
+    pub reactions: Option<Reactions>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(untagged)]
+pub enum AudioDevice {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Default for AudioDevice {
+    fn default() -> Self {
+        Self::Single(String::new())
+    }
+}
+
+impl AudioDevice {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            AudioDevice::Single(name) => vec![name.to_owned()],
+            AudioDevice::Multiple(names) if names.is_empty() => vec![String::new()],
+            AudioDevice::Multiple(names) => names.clone(),
+        }
+    }
+
+    /// Index of the device a light service is assigned to.
+    /// Services without an explicit device are assigned to the first one.
+    fn index_of(&self, name: Option<&str>) -> usize {
+        let Some(name) = name else {
+            return 0;
+        };
+        match self
+            .names()
+            .iter()
+            .position(|d| d.trim().to_lowercase() == name.trim().to_lowercase())
+        {
+            Some(index) => index,
+            None => {
+                warn!("Audio device {name} is not configured, using the first device instead");
+                0
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd)]
@@ -49,11 +200,21 @@ pub struct Config {
 pub enum WLEDConfig {
     Spectrum {
         ip: String,
+        #[serde(default)]
+        audio_device: Option<String>,
+        /// Only draw on this segment, by id or name
+        #[serde(default)]
+        segment: Option<SegmentSelector>,
         #[serde(default, flatten)]
         settings: SpectrumSettings,
     },
     Onset {
         ip: String,
+        #[serde(default)]
+        audio_device: Option<String>,
+        /// Only draw on this segment, by id or name
+        #[serde(default)]
+        segment: Option<SegmentSelector>,
         #[serde(default, flatten)]
         settings: OnsetSettings,
     },
@@ -103,6 +264,8 @@ impl std::error::Error for ConfigError {
 pub enum OnsetDetector {
     SpecFlux(SpecFluxSettings),
     HFC(HfcSettings),
+    CQT(CqtSettings),
+    ComplexFlux(ComplexFluxSettings),
 }
 
 impl Default for OnsetDetector {
@@ -111,20 +274,6 @@ impl Default for OnsetDetector {
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            audio_device: "".to_owned(),
-            console_output: false,
-            serialize_onsets: None,
-            audio_processing: ProcessingSettings::default(),
-            onset_detector: OnsetDetector::default(),
-            hue: Vec::new(),
-            wled: Vec::new(),
-        }
-    }
-}
-
 impl Config {
     pub fn load(file: &str) -> Result<Self, ConfigError> {
         if file.split_terminator('.').last() != Some("toml") {
@@ -136,71 +285,354 @@ impl Config {
         Ok(toml::de::from_str(&contents)?)
     }
 
+    /// Connects to all configured light services and groups them by audio device.
+    /// The outer vector has one entry per device in the order of `audio_device`.
+    /// With `controls` the connected lights can be turned off and dimmed from the keyboard.
     pub async fn initialize_lightservices(
         &self,
-    ) -> Result<Vec<Box<dyn LightService + Send>>, LightServiceError> {
-        let mut lightservices: Vec<Box<dyn LightService + Send>> = Vec::new();
-
-        let mut handles = Vec::new();
+        controls: Option<&Controls>,
+    ) -> Result<Vec<Vec<Box<dyn LightService + Send>>>, LightServiceError> {
+        let mut lightservices: Vec<Vec<Box<dyn LightService + Send>>> = self
+            .audio_device
+            .names()
+            .iter()
+            .map(|_| Vec::new())
+            .collect();
+
+        // Waiting for the push link button of a new bridge or the pairing of new panels is part of connecting
+        let pairing_timeout = self
+            .hue
+            .iter()
+            .map(|settings| settings.push_link_timeout)
+            .chain(
+                self.nanoleaf
+                    .iter()
+                    .map(|settings| settings.pairing_timeout),
+            )
+            .max()
+            .unwrap_or_default();
+        let deadline = Instant::now() + CONNECT_TIMEOUT + pairing_timeout;
+
+        // Every light is connected in its own task, so one slow or unreachable light doesn't hold up the others
+        let mut connections: Vec<(usize, String, Connection)> = Vec::new();
         for settings in &self.hue {
             let settings = settings.clone();
-            let handle = tokio::spawn(async move { hue::connect_with_settings(settings).await });
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            let name = match settings.ip {
+                Some(ip) => format!("Hue bridge {ip}"),
+                None => "Hue bridge".to_owned(),
+            };
+            let handle = spawn_connection(&name, async move {
+                let bridge = hue::connect_with_settings(settings).await?;
+                Ok(Box::new(bridge) as Box<dyn LightService + Send>)
+            });
+            connections.push((device, name, handle));
+        }
+
+        for config in &self.wled {
+            let sample_rate = self.audio_processing.sample_rate as f32;
+            let (name, audio_device, handle) = match config.clone() {
+                WLEDConfig::Spectrum {
+                    ip,
+                    audio_device,
+                    segment,
+                    settings,
+                } => {
+                    let name = format!("WLED strip {ip}");
+                    let handle = spawn_connection(&name, async move {
+                        let strip = wled::LEDStripSpectrum::connect_with_settings(
+                            &ip,
+                            segment.as_ref(),
+                            sample_rate,
+                            settings,
+                        )
+                        .await?;
+                        Ok(Box::new(strip) as Box<dyn LightService + Send>)
+                    });
+                    (name, audio_device, handle)
+                }
+                WLEDConfig::Onset {
+                    ip,
+                    audio_device,
+                    segment,
+                    settings,
+                } => {
+                    let name = format!("WLED strip {ip}");
+                    let handle = spawn_connection(&name, async move {
+                        let strip = wled::LEDStripOnset::connect_with_settings(
+                            &ip,
+                            segment.as_ref(),
+                            settings,
+                        )
+                        .await?;
+                        Ok(Box::new(strip) as Box<dyn LightService + Send>)
+                    });
+                    (name, audio_device, handle)
+                }
+            };
+            let device = self.audio_device.index_of(audio_device.as_deref());
+            connections.push((device, name, handle));
+        }
+
+        for settings in &self.nanoleaf {
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            let sample_rate = self.audio_processing.sample_rate as f32;
+            let settings = settings.clone();
+            let name = format!("Nanoleaf panels {}", settings.ip);
+            let handle = spawn_connection(&name, async move {
+                let panels = Nanoleaf::connect_with_settings(settings, sample_rate).await?;
+                Ok(Box::new(panels) as Box<dyn LightService + Send>)
+            });
+            connections.push((device, name, handle));
+        }
 
-            handles.push(handle);
+        for settings in &self.lifx {
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            let settings = settings.clone();
+            let name = "LIFX bulbs".to_owned();
+            let handle = spawn_connection(&name, async move {
+                let bulbs = Lifx::connect_with_settings(settings).await?;
+                Ok(Box::new(bulbs) as Box<dyn LightService + Send>)
+            });
+            connections.push((device, name, handle));
+        }
+
+        for settings in &self.artnet {
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            let settings = settings.clone();
+            let name = format!("Art-Net output {}", settings.ip);
+            let handle = spawn_connection(&name, async move {
+                let output = ArtNetOutput::connect_with_settings(settings).await?;
+                Ok(Box::new(output) as Box<dyn LightService + Send>)
+            });
+            connections.push((device, name, handle));
         }
 
         if let Some(path) = &self.serialize_onsets {
             let path = if path.is_empty() { "onsets.cbor" } else { path };
-            let serializer = serialize::OnsetContainer::init(
+            let serializer = serialize::OnsetContainer::with_time_source(
                 path,
                 self.audio_processing.sample_rate as usize,
                 self.audio_processing.hop_size,
+                serialize::TimeSource::Monotonic,
+                self.serialize_merge_window,
             );
-            lightservices.push(Box::new(serializer));
+            lightservices[0].push(Box::new(serializer));
             info!("Serializing onsets to {path}");
         }
 
-        if self.console_output {
+        if self.monitor {
+            let thresholds = match &self.onset_detector {
+                OnsetDetector::SpecFlux(settings) => Some(settings.threshold_bank_settings),
+                OnsetDetector::CQT(settings) => Some(settings.threshold_bank_settings),
+                OnsetDetector::ComplexFlux(settings) => Some(settings.threshold_bank_settings),
+                OnsetDetector::HFC(_) => None,
+            };
+            match Monitor::init(
+                self.audio_processing.sample_rate,
+                self.audio_processing.fft_size as u32,
+                thresholds,
+            ) {
+                Ok(monitor) => lightservices[0].push(Box::new(monitor)),
+                Err(e) => {
+                    let e = LightServiceError::Monitor(e);
+                    warn!("{e}, falling back to the console output");
+                    lightservices[0].push(Box::new(Console::default()));
+                }
+            }
+        } else if self.console_output {
             let console = Console::default();
-            lightservices.push(Box::new(console));
+            lightservices[0].push(Box::new(console));
         }
 
-        for config in &self.wled {
-            match config {
-                WLEDConfig::Spectrum { ip, settings } => {
-                    let strip = wled::LEDStripSpectrum::connect_with_settings(
-                        ip,
-                        self.audio_processing.sample_rate as f32,
-                        *settings,
-                    )
-                    .await?;
-                    lightservices.push(Box::new(strip));
+        for settings in &self.event_sink {
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            match EventSink::bind(&settings.address).await {
+                Ok(sink) => lightservices[device].push(Box::new(sink)),
+                Err(e) => warn!("Event sink {} is not used: {e}", settings.address),
+            }
+        }
+
+        for settings in &self.web_socket {
+            let device = self.audio_device.index_of(settings.audio_device.as_deref());
+            match WebSocketServer::bind(
+                settings,
+                self.audio_processing.sample_rate,
+                self.audio_processing.fft_size as u32,
+            )
+            .await
+            {
+                Ok(server) => lightservices[device].push(Box::new(server)),
+                Err(e) => warn!("WebSocket server is not used: {e}"),
+            }
+        }
+
+        let status = match &self.status {
+            Some(settings) => {
+                match StatusServer::bind(settings, &self.audio_device.names()).await {
+                    Ok(server) => Some(server),
+                    Err(e) => {
+                        warn!("Status server is not used: {e}");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        // Only the lights are normalized, the recorded and exported onsets keep their strengths
+        let mut lights: Vec<Vec<Box<dyn LightService + Send>>> =
+            lightservices.iter().map(|_| Vec::new()).collect();
+        for (device, name, mut handle) in connections {
+            match timeout_at(deadline, &mut handle).await {
+                Ok(Ok(Ok(service))) => {
+                    if let Some(status) = &status {
+                        status.register(&name, device, service.last_write());
+                    }
+                    let service = match controls {
+                        Some(controls) => controls.wrap(name, service),
+                        None => service,
+                    };
+                    lights[device].push(service);
+                }
+                Ok(Ok(Err(e))) => {
+                    warn!("{name} is not used: {e}");
+                    if let Some(e) = e.source() {
+                        debug!("{e}");
+                    }
                 }
-                WLEDConfig::Onset { ip, settings } => {
-                    let strip = wled::LEDStripOnset::connect_with_settings(ip, *settings).await?;
-                    lightservices.push(Box::new(strip));
+                Ok(Err(e)) => warn!("{name} is not used: {e}"),
+                Err(_) => {
+                    handle.abort();
+                    warn!("{name} is not used: connecting took too long");
                 }
             }
         }
 
-        for handle in handles.into_iter() {
-            let bridge = handle.await.unwrap()?;
-            lightservices.push(Box::new(bridge))
+        let frame_period = Duration::from_secs_f64(
+            self.audio_processing.hop_size as f64 / self.audio_processing.sample_rate as f64,
+        );
+        for (services, lights) in lightservices.iter_mut().zip(lights) {
+            if self.normalize_output && !lights.is_empty() {
+                services.push(Box::new(NormalizedOutput::init(lights, frame_period)));
+            } else {
+                services.extend(lights);
+            }
+        }
+
+        if lightservices.iter().all(Vec::is_empty) && !self.console_output {
+            return Err(LightServiceError::NoService);
+        }
+
+        if let Some(status) = &status {
+            for (device, services) in lightservices.iter_mut().enumerate() {
+                services.push(Box::new(status.reporter(device)));
+            }
         }
 
         Ok(lightservices)
     }
 
+    /// Switches the running detections to the profile named in `profile_file` whenever it changes,
+    /// and to the next profile on every message of `next_profile`.
+    /// Connected light services are kept, only their reactions are replaced.
+    pub async fn follow_profiles(
+        &self,
+        devices: Vec<(String, ProfileSender)>,
+        mut next_profile: mpsc::UnboundedReceiver<()>,
+    ) {
+        let mut active = String::new();
+        let mut file_content = String::new();
+        let mut timer = interval(PROFILE_POLL_INTERVAL);
+        loop {
+            let name = tokio::select! {
+                _ = timer.tick(), if self.profile_file.is_some() => {
+                    let path = self.profile_file.as_deref().unwrap_or_default();
+                    let name = fs::read_to_string(path)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_owned();
+                    // Only a changed file overrides a profile chosen with the keyboard
+                    if name == file_content {
+                        continue;
+                    }
+                    file_content.clone_from(&name);
+                    name
+                }
+                Some(()) = next_profile.recv() => {
+                    // After the last profile the config is used without a profile
+                    let next = match self.profile.iter().position(|p| p.name == active) {
+                        Some(i) => self.profile.get(i + 1),
+                        None => self.profile.first(),
+                    };
+                    next.map(|p| p.name.clone()).unwrap_or_default()
+                }
+                else => return std::future::pending().await,
+            };
+            if name == active {
+                continue;
+            }
+
+            let (config, reactions) = if name.is_empty() {
+                info!("Leaving profile {active}");
+                (self.clone(), None)
+            } else if let Some(profile) = self.profile.iter().find(|p| p.name == name) {
+                info!("Switching to profile {name}");
+                let mut config = self.clone();
+                if let Some(onset_detector) = &profile.onset_detector {
+                    config.onset_detector = onset_detector.clone();
+                }
+                (config, profile.reactions)
+            } else {
+                warn!("Profile {name} not found in the config");
+                active = name;
+                continue;
+            };
+            active = name;
+
+            for (device, sender) in &devices {
+                let device_config = config.with_learned_thresholds(device);
+                let _ = sender.send(Some(ActiveProfile {
+                    onset_detector: Arc::new(move || device_config.initialize_onset_detector()),
+                    reactions,
+                }));
+            }
+        }
+    }
+
+    /// Config with the thresholds calibrated for `device` in a previous run.
+    /// Thresholds that differ from the defaults were set in the config and are kept.
+    pub fn with_learned_thresholds(&self, device: &str) -> Config {
+        let mut config = self.clone();
+        let Some(thresholds) = learned::load(device).thresholds else {
+            return config;
+        };
+        let configured = match &mut config.onset_detector {
+            OnsetDetector::SpecFlux(settings) => &mut settings.threshold_bank_settings,
+            OnsetDetector::CQT(settings) => &mut settings.threshold_bank_settings,
+            OnsetDetector::ComplexFlux(settings) => &mut settings.threshold_bank_settings,
+            OnsetDetector::HFC(_) => return config,
+        };
+        if *configured == ThresholdBankSettings::default() {
+            *configured = thresholds;
+            info!("Using the thresholds calibrated for {device} in a previous run");
+        } else {
+            info!("Using the thresholds of the config for {device} instead of the calibrated ones");
+        }
+        config
+    }
+
     pub fn initialize_onset_detector(
         &self,
     ) -> Box<dyn audioprocessing::OnsetDetector + Send + 'static> {
         let detector: Box<dyn audioprocessing::OnsetDetector + Send + 'static> =
-            match self.onset_detector {
+            match &self.onset_detector {
                 OnsetDetector::SpecFlux(settings) => {
                     let alg = SpecFlux::with_settings(
                         self.audio_processing.sample_rate,
                         self.audio_processing.fft_size as u32,
-                        settings,
+                        self.audio_processing.hop_size,
+                        settings.clone(),
                     );
                     Box::new(alg)
                 }
@@ -208,7 +640,26 @@ impl Config {
                     let alg = Hfc::with_settings(
                         self.audio_processing.sample_rate as usize,
                         self.audio_processing.fft_size,
-                        settings,
+                        self.audio_processing.hop_size,
+                        settings.clone(),
+                    );
+                    Box::new(alg)
+                }
+                OnsetDetector::CQT(settings) => {
+                    let alg = Cqt::with_settings(
+                        self.audio_processing.sample_rate,
+                        self.audio_processing.fft_size as u32,
+                        self.audio_processing.hop_size,
+                        *settings,
+                    );
+                    Box::new(alg)
+                }
+                OnsetDetector::ComplexFlux(settings) => {
+                    let alg = ComplexFlux::with_settings(
+                        self.audio_processing.sample_rate,
+                        self.audio_processing.fft_size as u32,
+                        self.audio_processing.hop_size,
+                        *settings,
                     );
                     Box::new(alg)
                 }
@@ -216,29 +667,80 @@ impl Config {
         detector
     }
 
-    #[allow(dead_code)]
-    pub fn generate_template(file_path: &str) {
-        let mut template = Config {
+    /// Writes a config with all default values to `file_path`.
+    /// Light services are included as commented out examples.
+    pub fn generate_template(file_path: &str) -> std::io::Result<()> {
+        let template = Config {
             onset_detector: OnsetDetector::SpecFlux(Default::default()),
             ..Default::default()
         };
-        template.wled.push(WLEDConfig::Spectrum {
+
+        let mut services = Config::default();
+        services.wled.push(WLEDConfig::Spectrum {
             ip: "Ip of Strip".to_owned(),
+            audio_device: None,
+            segment: None,
             settings: Default::default(),
         });
-        template.wled.push(WLEDConfig::Onset {
+        services.wled.push(WLEDConfig::Onset {
             ip: "Ip of Strip".to_owned(),
+            audio_device: None,
+            segment: None,
             settings: Default::default(),
         });
-        template.hue.push(HueSettings {
-            ip: Some(Ipv4Addr::new(0, 0, 0, 0)),
+        services.hue.push(HueSettings {
+            ip: Some(Ipv4Addr::new(0, 0, 0, 0).into()),
             area: Some("Area uuid".to_owned()),
             timeout: HueSettings::default().timeout,
             push_link_timeout: HueSettings::default().push_link_timeout,
             ..Default::default()
         });
-        let toml = toml::to_string(&template).unwrap();
-        fs::write(file_path, toml).unwrap();
+        services.nanoleaf.push(NanoleafSettings {
+            ip: "Ip of Nanoleaf controller".to_owned(),
+            ..Default::default()
+        });
+        services.lifx.push(LifxSettings::default());
+        services.artnet.push(ArtNetSettings::default());
+        services.event_sink.push(EventSinkSettings::default());
+        services.web_socket.push(WebSocketSettings::default());
+
+        #[derive(Serialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct LightServices<'a> {
+            hue: &'a [HueSettings],
+            #[serde(rename = "WLED")]
+            wled: &'a [WLEDConfig],
+            nanoleaf: &'a [NanoleafSettings],
+            #[serde(rename = "LIFX")]
+            lifx: &'a [LifxSettings],
+            #[serde(rename = "ArtNet")]
+            artnet: &'a [ArtNetSettings],
+            event_sink: &'a [EventSinkSettings],
+            web_socket: &'a [WebSocketSettings],
+        }
+
+        let mut toml = toml::to_string(&template).unwrap();
+        let light_services = toml::to_string(&LightServices {
+            hue: &services.hue,
+            wled: &services.wled,
+            nanoleaf: &services.nanoleaf,
+            lifx: &services.lifx,
+            artnet: &services.artnet,
+            event_sink: &services.event_sink,
+            web_socket: &services.web_socket,
+        })
+        .unwrap();
+
+        toml.push_str("\n# Uncomment to enable light services\n");
+        for line in light_services.lines() {
+            if line.is_empty() {
+                toml.push('\n');
+            } else {
+                toml.push_str(&format!("# {line}\n"));
+            }
+        }
+
+        fs::write(file_path, toml)
     }
 }
 
@@ -246,6 +748,13 @@ impl Config {
 pub enum LightServiceError {
     Hue(HueError),
     WLED(WLEDError),
+    Nanoleaf(NanoleafError),
+    Lifx(LifxError),
+    ArtNet(ArtNetError),
+    EventSink(EventSinkError),
+    WebSocket(WebSocketError),
+    Monitor(std::io::Error),
+    NoService,
 }
 
 impl From<HueError> for LightServiceError {
@@ -260,11 +769,48 @@ impl From<WLEDError> for LightServiceError {
     }
 }
 
+impl From<NanoleafError> for LightServiceError {
+    fn from(value: NanoleafError) -> Self {
+        Self::Nanoleaf(value)
+    }
+}
+
+impl From<LifxError> for LightServiceError {
+    fn from(value: LifxError) -> Self {
+        Self::Lifx(value)
+    }
+}
+
+impl From<ArtNetError> for LightServiceError {
+    fn from(value: ArtNetError) -> Self {
+        Self::ArtNet(value)
+    }
+}
+
+impl From<EventSinkError> for LightServiceError {
+    fn from(value: EventSinkError) -> Self {
+        Self::EventSink(value)
+    }
+}
+
+impl From<WebSocketError> for LightServiceError {
+    fn from(value: WebSocketError) -> Self {
+        Self::WebSocket(value)
+    }
+}
+
 impl std::error::Error for LightServiceError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             LightServiceError::Hue(e) => Some(e),
             LightServiceError::WLED(e) => Some(e),
+            LightServiceError::Nanoleaf(e) => Some(e),
+            LightServiceError::Lifx(e) => Some(e),
+            LightServiceError::ArtNet(e) => Some(e),
+            LightServiceError::EventSink(e) => Some(e),
+            LightServiceError::WebSocket(e) => Some(e),
+            LightServiceError::Monitor(e) => Some(e),
+            LightServiceError::NoService => None,
         }
     }
 }
@@ -274,6 +820,13 @@ impl Display for LightServiceError {
         match self {
             LightServiceError::Hue(e) => write!(f, "{e}"),
             LightServiceError::WLED(e) => write!(f, "{e}"),
+            LightServiceError::Nanoleaf(e) => write!(f, "{e}"),
+            LightServiceError::Lifx(e) => write!(f, "{e}"),
+            LightServiceError::ArtNet(e) => write!(f, "{e}"),
+            LightServiceError::EventSink(e) => write!(f, "{e}"),
+            LightServiceError::WebSocket(e) => write!(f, "{e}"),
+            LightServiceError::Monitor(_) => write!(f, "Terminal monitor could not be started"),
+            LightServiceError::NoService => write!(f, "No light service could be started"),
         }
     }
 }
@@ -0,0 +1,246 @@
+use std::{
+    io::{self, IsTerminal},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use ratatui::crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use super::{
+    audioprocessing::Onset,
+    lights::{LightService, Reactions, WriteStatus},
+};
+
+/// Change of the master brightness per key press
+const BRIGHTNESS_STEP: f32 = 0.1;
+
+/// Printed when the keyboard control starts
+pub const KEY_BINDINGS: &str = "\
+Space    pause or resume the sync
++ / -    raise or lower the master brightness
+1 - 9    turn the light service with that number on or off
+p        switch to the next profile
+q        quit";
+
+struct ControlState {
+    paused: bool,
+    brightness: f32,
+    /// Name of every controlled light service and whether it's turned on
+    services: Vec<(String, bool)>,
+}
+
+impl Default for ControlState {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            brightness: 1.0,
+            services: Vec::new(),
+        }
+    }
+}
+
+impl ControlState {
+    fn status(&self) -> String {
+        let services: Vec<String> = self
+            .services
+            .iter()
+            .enumerate()
+            .map(|(i, (name, on))| format!("{} {name} {}", i + 1, if *on { "on" } else { "off" }))
+            .collect();
+        format!(
+            "{} | brightness {:.0}% | {}",
+            if self.paused { "paused" } else { "syncing" },
+            self.brightness * 100.0,
+            services.join(", ")
+        )
+    }
+}
+
+/// Pause, master brightness and the light services turned on from the keyboard,
+/// shared by the light services of all audio devices
+#[derive(Clone, Default)]
+pub struct Controls {
+    state: Arc<Mutex<ControlState>>,
+}
+
+impl Controls {
+    /// Lets the keyboard turn `service` on and off and dim it
+    pub fn wrap(
+        &self,
+        name: String,
+        service: Box<dyn LightService + Send>,
+    ) -> Box<dyn LightService + Send> {
+        let mut state = self.state.lock().unwrap();
+        let index = state.services.len();
+        state.services.push((name, true));
+        Box::new(ControlledService {
+            service,
+            index,
+            state: self.state.clone(),
+            scaled: Vec::new(),
+            dimmed: Vec::new(),
+        })
+    }
+
+    /// Reads keys on a separate thread until `q` or CTRL-C is pressed.
+    /// Every `p` sends a request for the next profile to `next_profile`.
+    pub fn listen(&self, next_profile: mpsc::UnboundedSender<()>) -> io::Result<Keyboard> {
+        if !io::stdin().is_terminal() {
+            return Err(io::Error::other("stdin is not a terminal"));
+        }
+        terminal::enable_raw_mode()?;
+
+        // Raw mode doesn't return to the start of the line on its own
+        let state = self.state.clone();
+        println!("{}\r", KEY_BINDINGS.replace('\n', "\r\n"));
+        println!("{}\r", state.lock().unwrap().status());
+
+        let (quit_sender, quit) = oneshot::channel();
+        thread::spawn(move || {
+            loop {
+                let key = match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Error reading the keyboard: {e}");
+                        break;
+                    }
+                };
+                let mut state = state.lock().unwrap();
+                match key.code {
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Char('q') => break,
+                    KeyCode::Char(' ') => state.paused = !state.paused,
+                    KeyCode::Char('+') => {
+                        state.brightness = (state.brightness + BRIGHTNESS_STEP).min(1.0);
+                    }
+                    KeyCode::Char('-') => {
+                        state.brightness = (state.brightness - BRIGHTNESS_STEP).max(0.0);
+                    }
+                    KeyCode::Char('p') => {
+                        let _ = next_profile.send(());
+                        continue;
+                    }
+                    KeyCode::Char(digit @ '1'..='9') => {
+                        let index = digit as usize - '1' as usize;
+                        match state.services.get_mut(index) {
+                            Some((_, on)) => *on = !*on,
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                }
+                println!("{}\r", state.status());
+            }
+            let _ = quit_sender.send(());
+        });
+
+        Ok(Keyboard { quit })
+    }
+}
+
+/// Terminal in raw mode while the keys are read, restored on drop
+pub struct Keyboard {
+    quit: oneshot::Receiver<()>,
+}
+
+impl Keyboard {
+    /// Returns once `q` or CTRL-C was pressed
+    pub async fn quit(&mut self) {
+        if (&mut self.quit).await.is_err() {
+            std::future::pending().await
+        }
+    }
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Drops onsets, spectra and samples while paused or turned off,
+/// so the envelopes of the wrapped service fade out on their own
+struct ControlledService {
+    service: Box<dyn LightService + Send>,
+    index: usize,
+    state: Arc<Mutex<ControlState>>,
+    scaled: Vec<Onset>,
+    dimmed: Vec<f32>,
+}
+
+impl ControlledService {
+    /// Master brightness, `None` while the service doesn't get any input
+    fn gain(&self) -> Option<f32> {
+        let state = self.state.lock().unwrap();
+        (!state.paused && state.services[self.index].1).then_some(state.brightness)
+    }
+}
+
+impl LightService for ControlledService {
+    fn process_onset(&mut self, event: Onset) {
+        self.process_onsets(&[event]);
+    }
+
+    fn process_onsets(&mut self, onsets: &[Onset]) {
+        let Some(gain) = self.gain() else {
+            return;
+        };
+        self.scaled.clear();
+        self.scaled
+            .extend(onsets.iter().map(|onset| onset.map_strength(|s| s * gain)));
+        self.service.process_onsets(&self.scaled);
+    }
+
+    fn process_channel_onsets(&mut self, channel: usize, onsets: &[Onset]) {
+        let Some(gain) = self.gain() else {
+            return;
+        };
+        self.scaled.clear();
+        self.scaled
+            .extend(onsets.iter().map(|onset| onset.map_strength(|s| s * gain)));
+        self.service.process_channel_onsets(channel, &self.scaled);
+    }
+
+    fn process_spectrum(&mut self, freq_bins: &[f32]) {
+        let Some(gain) = self.gain() else {
+            return;
+        };
+        self.dimmed.clear();
+        self.dimmed.extend(freq_bins.iter().map(|bin| bin * gain));
+        self.service.process_spectrum(&self.dimmed);
+    }
+
+    /// The spectrum effects normalize the samples, so they are dimmed through `dim` instead
+    fn process_samples(&mut self, samples: &[f32]) {
+        if let Some(gain) = self.gain() {
+            self.service.dim(gain);
+            self.service.process_samples(samples);
+        }
+    }
+
+    fn update(&mut self) {
+        self.service.update();
+    }
+
+    fn shutdown(&mut self) {
+        self.service.shutdown();
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.service.set_reactions(reactions);
+    }
+
+    fn dim(&mut self, gain: f32) {
+        self.service.dim(gain);
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        self.service.last_write()
+    }
+}
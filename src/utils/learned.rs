@@ -0,0 +1,64 @@
+use std::{collections::HashMap, error::Error, fs::File};
+
+use ciborium::{from_reader, into_writer};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::audioprocessing::spectral_flux::ThresholdBankSettings;
+
+/// Noise floors and calibrated thresholds of all audio devices
+static LEARNED_PATH: &str = "learned.cbor";
+
+/// What was learned about an audio device in previous runs
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Learned {
+    /// Noise floor learned by the adaptive noise gate
+    pub noise_floor: Option<f32>,
+    /// Thresholds suggested by `--calibrate`
+    pub thresholds: Option<ThresholdBankSettings>,
+}
+
+/// Devices are matched like in the config, ignoring case and surrounding whitespace
+fn key(device: &str) -> String {
+    device.trim().to_lowercase()
+}
+
+fn load_all() -> HashMap<String, Learned> {
+    let Ok(file) = File::open(LEARNED_PATH) else {
+        return HashMap::new();
+    };
+    match from_reader(file) {
+        Ok(learned) => learned,
+        Err(e) => {
+            warn!("Ignoring unreadable {LEARNED_PATH}: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Everything learned about `device`, empty if it wasn't used before
+pub fn load(device: &str) -> Learned {
+    load_all().remove(&key(device)).unwrap_or_default()
+}
+
+fn update(device: &str, f: impl FnOnce(&mut Learned)) -> Result<(), Box<dyn Error>> {
+    let mut learned = load_all();
+    f(learned.entry(key(device)).or_default());
+    into_writer(&learned, File::create(LEARNED_PATH)?)?;
+    Ok(())
+}
+
+pub fn save_noise_floor(device: &str, noise_floor: f32) -> Result<(), Box<dyn Error>> {
+    update(device, |learned| learned.noise_floor = Some(noise_floor))?;
+    info!("Saved noise floor of {noise_floor:.4} to {LEARNED_PATH}");
+    Ok(())
+}
+
+pub fn save_thresholds(
+    device: &str,
+    thresholds: ThresholdBankSettings,
+) -> Result<(), Box<dyn Error>> {
+    update(device, |learned| learned.thresholds = Some(thresholds))?;
+    info!("Saved calibrated thresholds to {LEARNED_PATH}");
+    Ok(())
+}
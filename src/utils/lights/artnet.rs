@@ -0,0 +1,305 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use super::{
+    envelope::{DynamicDecay, Envelope, FixedDecay},
+    LightService, Onset, Pollable, PollingHelper, Reactions, WriteStatus,
+};
+
+const ARTNET_PORT: u16 = 6454;
+const OP_DMX: u16 = 0x5000;
+const PROTOCOL_VERSION: u16 = 14;
+const DMX_CHANNELS: usize = 512;
+
+#[derive(Debug)]
+pub enum ArtNetError {
+    Socket(std::io::Error),
+}
+
+impl std::error::Error for ArtNetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ArtNetError::Socket(e) => Some(e),
+        }
+    }
+}
+
+impl Display for ArtNetError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(_) => write!(f, "Binding socket failed"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ArtNetError {
+    fn from(err: std::io::Error) -> Self {
+        ArtNetError::Socket(err)
+    }
+}
+
+/// Envelope that drives a DMX channel
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum DmxSource {
+    Drum,
+    Hihat,
+    Note,
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+pub struct ChannelMapping {
+    /// DMX channel starting at 1
+    pub channel: u16,
+    pub onset: DmxSource,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct ArtNetSettings {
+    #[serde(rename = "ip")]
+    pub ip: Ipv4Addr,
+    #[serde(rename = "universe")]
+    pub universe: u16,
+    #[serde(rename = "audio_device")]
+    pub audio_device: Option<String>,
+    #[serde(rename = "drum_decay_rate")]
+    pub drum_decay_rate: f32,
+    #[serde(rename = "note_decay_rate")]
+    pub note_decay_rate: f32,
+    #[serde(rename = "full_decay_rate")]
+    pub full_decay_rate: f32,
+    pub hihat_decay: Duration,
+    #[serde(rename = "polling_rate")]
+    pub polling_rate: f64,
+    pub channels: Vec<ChannelMapping>,
+    #[serde(flatten)]
+    pub reactions: Reactions,
+}
+
+impl Default for ArtNetSettings {
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Addr::BROADCAST,
+            universe: 0,
+            audio_device: None,
+            drum_decay_rate: 4.0,
+            note_decay_rate: 4.0,
+            full_decay_rate: 2.0,
+            hihat_decay: Duration::from_millis(150),
+            polling_rate: 44.0,
+            channels: vec![
+                ChannelMapping {
+                    channel: 1,
+                    onset: DmxSource::Drum,
+                },
+                ChannelMapping {
+                    channel: 2,
+                    onset: DmxSource::Note,
+                },
+                ChannelMapping {
+                    channel: 3,
+                    onset: DmxSource::Hihat,
+                },
+            ],
+            reactions: Reactions::default(),
+        }
+    }
+}
+
+pub struct ArtNetOutput {
+    polling_helper: PollingHelper,
+    state: Arc<Mutex<ArtNetState>>,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+}
+
+struct ArtNetState {
+    drum: DynamicDecay,
+    hihat: FixedDecay,
+    note: DynamicDecay,
+    full: DynamicDecay,
+    channels: Vec<(usize, DmxSource)>,
+    prefix: Vec<u8>,
+    buffer: BytesMut,
+}
+
+impl ArtNetState {
+    fn with_settings(settings: &ArtNetSettings) -> Self {
+        let mut prefix = BytesMut::from("Art-Net\0");
+        prefix.put_u16_le(OP_DMX);
+        prefix.put_u16(PROTOCOL_VERSION);
+        prefix.put_u8(0); // Sequence, 0 disables reordering on the receiver
+        prefix.put_u8(0); // Physical port
+        prefix.put_u16_le(settings.universe & 0x7FFF); // Sub-Net, Universe and Net
+        prefix.put_u16(DMX_CHANNELS as u16);
+
+        let channels = settings
+            .channels
+            .iter()
+            .filter_map(|mapping| match mapping.channel as usize {
+                channel @ 1..=DMX_CHANNELS => Some((channel - 1, mapping.onset)),
+                channel => {
+                    warn!("DMX channel {channel} is out of range and will be ignored");
+                    None
+                }
+            })
+            .collect();
+
+        ArtNetState {
+            drum: DynamicDecay::init(settings.drum_decay_rate),
+            hihat: FixedDecay::init(settings.hihat_decay),
+            note: DynamicDecay::init(settings.note_decay_rate),
+            full: DynamicDecay::init(settings.full_decay_rate),
+            channels,
+            buffer: BytesMut::with_capacity(prefix.len() + DMX_CHANNELS),
+            prefix: prefix.into(),
+        }
+    }
+
+    fn value(&self, source: DmxSource) -> u8 {
+        let value = match source {
+            DmxSource::Drum => self.drum.get_value(),
+            DmxSource::Hihat => self.hihat.get_value(),
+            DmxSource::Note => self.note.get_value(),
+            DmxSource::Full => self.full.get_value(),
+        };
+        (value.clamp(0.0, 1.0) * u8::MAX as f32) as u8
+    }
+
+    fn trigger(&mut self, event: Onset) {
+        match event {
+            Onset::Full(strength) => self.full.trigger(strength),
+            Onset::Drum(strength) => self.drum.trigger(strength),
+            Onset::Hihat(strength) => self.hihat.trigger(strength),
+            Onset::Note(strength, _) => self.note.trigger(strength),
+            _ => {}
+        }
+    }
+}
+
+impl Pollable for ArtNetState {
+    fn poll(&self) -> Bytes {
+        let mut dmx = [0; DMX_CHANNELS];
+        for (channel, source) in &self.channels {
+            dmx[*channel] = dmx[*channel].max(self.value(*source));
+        }
+
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+        bytes.put_slice(&self.prefix);
+        bytes.put_slice(&dmx);
+
+        bytes.into()
+    }
+
+    fn blackout(&self) -> Option<Bytes> {
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+        bytes.put_slice(&self.prefix);
+        bytes.put_bytes(0, DMX_CHANNELS);
+        Some(bytes.into())
+    }
+}
+
+impl ArtNetOutput {
+    pub async fn connect(ip: Ipv4Addr) -> Result<ArtNetOutput, ArtNetError> {
+        let settings = ArtNetSettings {
+            ip,
+            ..Default::default()
+        };
+        Self::connect_with_settings(settings).await
+    }
+
+    pub async fn connect_with_settings(
+        settings: ArtNetSettings,
+    ) -> Result<ArtNetOutput, ArtNetError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        if settings.ip.is_broadcast() {
+            socket.set_broadcast(true)?;
+        }
+        socket.connect((settings.ip, ARTNET_PORT)).await?;
+        debug!("Bound: {}", socket.local_addr().unwrap());
+
+        let state = Arc::new(Mutex::new(ArtNetState::with_settings(&settings)));
+        let polling_helper = PollingHelper::init(socket, state.clone(), settings.polling_rate);
+
+        info!(
+            "Sending Art-Net universe {} to {}",
+            settings.universe, settings.ip
+        );
+
+        Ok(ArtNetOutput {
+            polling_helper,
+            state,
+            reactions: settings.reactions,
+            reaction_override: None,
+        })
+    }
+}
+
+impl LightService for ArtNetOutput {
+    fn process_onset(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
+        self.state.lock().unwrap().trigger(event);
+    }
+
+    fn shutdown(&mut self) {
+        self.polling_helper.shutdown();
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.polling_helper.last_write())
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lights::DETECTED_ONSETS;
+
+    #[test]
+    fn every_detected_onset_drives_a_channel() {
+        let settings = ArtNetSettings {
+            channels: [
+                DmxSource::Drum,
+                DmxSource::Hihat,
+                DmxSource::Note,
+                DmxSource::Full,
+            ]
+            .into_iter()
+            .zip(1..)
+            .map(|(onset, channel)| ChannelMapping { channel, onset })
+            .collect(),
+            ..Default::default()
+        };
+        for (channel, onset) in DETECTED_ONSETS.into_iter().enumerate() {
+            let mut state = ArtNetState::with_settings(&settings);
+            let before = state.poll();
+            state.trigger(onset);
+            let after = state.poll();
+            let dmx = after.len() - DMX_CHANNELS;
+            assert_eq!(before[dmx + channel], 0);
+            assert!(after[dmx + channel] > 0, "{onset:?} left its channel dark");
+        }
+    }
+}
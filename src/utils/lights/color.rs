@@ -89,11 +89,17 @@ pub fn rgb_to_hsv(rgb: [u16; 3]) -> [f32; 3] {
 }
 
 pub fn hsv_to_rgb(hsv: &[f32; 3]) -> [u16; 3] {
+    // Wrap the hue into 0..360 and treat an undefined hue as red
+    let hue = if hsv[0].is_finite() {
+        hsv[0].rem_euclid(360.0)
+    } else {
+        0.0
+    };
     let c = hsv[2] * hsv[1];
-    let x = c * (1.0 - ((hsv[0] / 60.0) % 2.0 - 1.0).abs());
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
     let m = hsv[2] - c;
 
-    let (r, g, b) = match hsv[0] {
+    let (r, g, b) = match hue {
         h if h < 60.0 => (c, x, 0.0),
         h if h < 120.0 => (x, c, 0.0),
         h if h < 180.0 => (0.0, c, x),
@@ -110,8 +116,15 @@ pub fn hsv_to_rgb(hsv: &[f32; 3]) -> [u16; 3] {
     [r as u16, g as u16, b as u16]
 }
 
+/// Hue takes the shorter way around the color wheel
 pub fn interpolate_hsv(a: &[f32; 3], b: &[f32; 3], t: f32) -> [f32; 3] {
-    let h = a[0] + t * (b[0] - a[0]);
+    let mut delta = b[0] - a[0];
+    if delta > 180.0 {
+        delta -= 360.0;
+    } else if delta < -180.0 {
+        delta += 360.0;
+    }
+    let h = (a[0] + t * delta).rem_euclid(360.0);
     let s = a[1] + t * (b[1] - a[1]);
     let v = a[2] + t * (b[2] - a[2]);
 
@@ -126,6 +139,19 @@ pub fn color_downsample(color: [u16; 3]) -> [u8; 3] {
     ]
 }
 
+/// Downsample that carries the rounding error over to the next color,
+/// so slow fades at low brightness don't show visible steps
+pub fn color_downsample_dithered(color: [u16; 3], error: &mut [f32; 3]) -> [u8; 3] {
+    let mut out = [0; 3];
+    for ((out, &c), error) in out.iter_mut().zip(&color).zip(error.iter_mut()) {
+        let value = c as f32 / u16::MAX as f32 * u8::MAX as f32 + *error;
+        let quantized = value.round().clamp(0.0, u8::MAX as f32);
+        *error = value - quantized;
+        *out = quantized as u8;
+    }
+    out
+}
+
 pub fn color_upsample(color: [u8; 3]) -> [u16; 3] {
     [
         ((color[0] as f64 / u8::MAX as f64) * u16::MAX as f64) as u16,
@@ -135,11 +161,58 @@ pub fn color_upsample(color: [u8; 3]) -> [u16; 3] {
 }
 
 pub fn interpolate_rgb(a: &[u16; 3], b: &[u16; 3], t: f32) -> [u16; 3] {
-    let r = a[0] + ((b[0] - a[0]) as f32 * t) as u16;
-    let g = a[1] + ((b[1] - a[1]) as f32 * t) as u16;
-    let b = a[2] + ((b[2] - a[2]) as f32 * t) as u16;
+    let channel = |a: u16, b: u16| {
+        let value = a as f32 + (b as f32 - a as f32) * t;
+        value.round().clamp(0.0, u16::MAX as f32) as u16
+    };
 
-    [r, g, b]
+    [
+        channel(a[0], b[0]),
+        channel(a[1], b[1]),
+        channel(a[2], b[2]),
+    ]
+}
+
+/// Colors the fullband onsets step through, fading from one to the next
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors: Vec<[u16; 3]>,
+    /// Index into the colors, the fraction is the way to the next color
+    position: f32,
+    steps: u32,
+}
+
+impl Palette {
+    /// `steps` onsets fade from one color to the next, an empty palette only holds `base`
+    pub fn init(colors: Vec<[u16; 3]>, steps: u32, base: [u16; 3]) -> Self {
+        let colors = if colors.is_empty() {
+            vec![base]
+        } else {
+            colors
+        };
+        Self {
+            colors,
+            position: 0.0,
+            steps: steps.max(1),
+        }
+    }
+
+    pub fn first(&self) -> [u16; 3] {
+        self.colors[0]
+    }
+
+    /// Color at the current position, then moves one step towards the next color
+    pub fn advance(&mut self) -> [u16; 3] {
+        let len = self.colors.len();
+        let index = self.position as usize % len;
+        let color = interpolate_rgb(
+            &self.colors[index],
+            &self.colors[(index + 1) % len],
+            self.position.fract(),
+        );
+        self.position = (self.position + 1.0 / self.steps as f32) % len as f32;
+        color
+    }
 }
 
 pub fn color_to_hex(color: &[u16; 3]) -> String {
@@ -163,3 +236,80 @@ pub fn hue_to_color(hue: f32) -> [u16; 3] {
     let hsv = [hue, 1.0, 1.0];
     hsv_to_rgb(&hsv)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every combination of a few levels per channel, including black, white and the primaries
+    fn rgb_grid() -> Vec<[u16; 3]> {
+        let levels = [0, 1, 255, 4096, 30000, 32768, 65534, u16::MAX];
+        let mut grid = Vec::new();
+        for r in levels {
+            for g in levels {
+                for b in levels {
+                    grid.push([r, g, b]);
+                }
+            }
+        }
+        grid
+    }
+
+    fn assert_close(a: [u16; 3], b: [u16; 3], tolerance: u16) {
+        assert!(
+            a.iter().zip(&b).all(|(x, y)| x.abs_diff(*y) <= tolerance),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn color_conversions_round_trip() {
+        for rgb in rgb_grid() {
+            assert_close(hsv_to_rgb(&rgb_to_hsv(rgb)), rgb, 1);
+            // Black has no chromaticity, the rounded matrices are off by up to a quarter percent
+            if rgb != [0, 0, 0] {
+                assert_close(xyb_to_rgb(rgb_to_xyb(rgb)), rgb, 160);
+            }
+        }
+        for channel in 0..=u8::MAX {
+            let rgb = [channel, u8::MAX - channel, channel / 2];
+            assert_eq!(color_downsample(color_upsample(rgb)), rgb);
+            let rgb = rgb.map(u16::from);
+            assert_eq!(hex_to_color(&color_to_hex(&rgb)), rgb);
+        }
+        for hue in (0..360).step_by(15) {
+            assert!((color_to_hue(&hue_to_color(hue as f32)) - hue as f32).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn interpolates_every_channel() {
+        assert_eq!(
+            interpolate_rgb(&[60000, 0, 0], &[0, 0, 60000], 0.5),
+            [30000, 0, 30000]
+        );
+        assert_eq!(
+            interpolate_rgb(&[60000, 0, 0], &[0, 0, 60000], 0.0),
+            [60000, 0, 0]
+        );
+        assert_eq!(
+            interpolate_rgb(&[60000, 0, 0], &[0, 0, 60000], 1.0),
+            [0, 0, 60000]
+        );
+    }
+
+    #[test]
+    fn palette_starts_at_the_first_color() {
+        let (red, blue) = ([u16::MAX, 0, 0], [0, 0, u16::MAX]);
+        let mut palette = Palette::init(vec![red, blue], 2, [0, 0, 0]);
+        assert_eq!(palette.advance(), red);
+        assert_eq!(palette.advance(), interpolate_rgb(&red, &blue, 0.5));
+        assert_eq!(palette.advance(), blue);
+        assert_eq!(palette.advance(), interpolate_rgb(&blue, &red, 0.5));
+        assert_eq!(palette.advance(), red);
+
+        let mut empty = Palette::init(Vec::new(), 1, blue);
+        assert_eq!(empty.advance(), blue);
+        assert_eq!(empty.advance(), blue);
+    }
+}
@@ -1,35 +1,61 @@
 use crate::utils::audioprocessing::Onset;
 
 use super::LightService;
-use colored::{ColoredString, Colorize};
+use colored::Colorize;
+use terminal_size::{terminal_size, Width};
+
+/// Bar width used when stdout isn't a terminal
+const DEFAULT_BAR_WIDTH: usize = 9;
+/// Drum, hihat, fullband, note and atmosphere
+const BANDS: usize = 5;
 
 #[derive(Debug, Default)]
 pub struct Console {
-    output: [ColoredString; 5],
+    strength: [f32; BANDS],
+}
+
+/// Splits the terminal width evenly between the bands
+fn bar_width() -> usize {
+    match terminal_size() {
+        // Every band takes the bar and "  |  ", the line starts with "|  "
+        Some((Width(columns), _)) => {
+            ((columns as usize).saturating_sub(3) / BANDS).saturating_sub(5)
+        }
+        None => DEFAULT_BAR_WIDTH,
+    }
+    .max(1)
 }
 
 impl LightService for Console {
     fn process_onset(&mut self, event: Onset) {
         match event {
-            Onset::Drum(s) => self.output[0] = "■".repeat((s * 9.0).ceil() as usize).bright_red(),
-            Onset::Hihat(s) => self.output[1] = "■".repeat((s * 9.0).ceil() as usize).white(),
-            Onset::Full(s) => self.output[2] = "■".repeat((s * 9.0).ceil() as usize).cyan(),
-            Onset::Note(s, _) => self.output[3] = "■".repeat((s * 9.0).ceil() as usize).blue(),
-            Onset::Atmosphere(s, _) => {
-                self.output[4] = "-".repeat((s * 9.0).ceil() as usize).black();
-            }
+            Onset::Drum(s) => self.strength[0] = s,
+            Onset::Hihat(s) => self.strength[1] = s,
+            Onset::Full(s) => self.strength[2] = s,
+            Onset::Note(s, _) => self.strength[3] = s,
+            Onset::Atmosphere(s, _) => self.strength[4] = s,
             _ => {}
         }
     }
 
     fn update(&mut self) {
+        let width = bar_width();
+        let bar = |s: f32, symbol: &str| {
+            symbol.repeat((s.clamp(0.0, 1.0) * width as f32).ceil() as usize)
+        };
+        let output = [
+            bar(self.strength[0], "■").bright_red(),
+            bar(self.strength[1], "■").white(),
+            bar(self.strength[2], "■").cyan(),
+            bar(self.strength[3], "■").blue(),
+            bar(self.strength[4], "-").black(),
+        ];
+
         print!("|  ");
-        for s in self.output.iter().take(4) {
-            print!("{s:^9}  |  ");
+        for s in &output {
+            print!("{s:^width$}  |  ");
         }
         println!();
-        for s in &mut self.output {
-            *s = "".black();
-        }
+        self.strength = [0.0; BANDS];
     }
 }
@@ -1,4 +1,7 @@
-use std::time::{Duration, Instant};
+use std::{
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
 
 use super::color::{hsv_to_rgb, interpolate_hsv, rgb_to_hsv};
 
@@ -11,30 +14,43 @@ pub trait Envelope {
 pub struct FixedDecay {
     trigger_time: Instant,
     length: Duration,
+    attack: Duration,
     strength: f32,
+    attack_start: f32,
 }
 
 impl FixedDecay {
     pub fn init(decay: std::time::Duration) -> FixedDecay {
+        FixedDecay::with_attack(decay, Duration::ZERO)
+    }
+
+    pub fn with_attack(decay: Duration, attack: Duration) -> FixedDecay {
         FixedDecay {
             trigger_time: Instant::now(),
             length: decay,
+            attack,
             strength: 0.0,
+            attack_start: 0.0,
         }
     }
 }
 
 impl Envelope for FixedDecay {
     fn trigger(&mut self, strength: f32) {
+        self.attack_start = self.get_value();
         self.trigger_time = Instant::now();
         self.strength = strength;
     }
 
     fn get_value(&self) -> f32 {
+        let elapsed = self.trigger_time.elapsed();
+        if elapsed < self.attack {
+            return attack_value(self.attack_start, self.strength, elapsed, self.attack);
+        }
+
         let value = self.strength
             - (self.strength
-                * (self.trigger_time.elapsed().as_millis() as f32
-                    / self.length.as_millis() as f32));
+                * ((elapsed - self.attack).as_millis() as f32 / self.length.as_millis() as f32));
         if value > 0.0 {
             value
         } else {
@@ -47,28 +63,42 @@ impl Envelope for FixedDecay {
 pub struct DynamicDecay {
     trigger_time: Instant,
     decay_per_second: f32,
+    attack: Duration,
     strength: f32,
+    attack_start: f32,
 }
 
 impl DynamicDecay {
     pub fn init(decay_per_second: f32) -> DynamicDecay {
+        DynamicDecay::with_attack(decay_per_second, Duration::ZERO)
+    }
+
+    pub fn with_attack(decay_per_second: f32, attack: Duration) -> DynamicDecay {
         DynamicDecay {
             trigger_time: Instant::now(),
             decay_per_second,
+            attack,
             strength: 0.0,
+            attack_start: 0.0,
         }
     }
 }
 
 impl Envelope for DynamicDecay {
     fn trigger(&mut self, strength: f32) {
+        self.attack_start = self.get_value();
         self.trigger_time = Instant::now();
         self.strength = strength;
     }
 
     fn get_value(&self) -> f32 {
+        let elapsed = self.trigger_time.elapsed();
+        if elapsed < self.attack {
+            return attack_value(self.attack_start, self.strength, elapsed, self.attack);
+        }
+
         let value = self.strength
-            - (self.strength * self.trigger_time.elapsed().as_secs_f32() * self.decay_per_second);
+            - (self.strength * (elapsed - self.attack).as_secs_f32() * self.decay_per_second);
         if value > 0.0 {
             value
         } else {
@@ -77,19 +107,29 @@ impl Envelope for DynamicDecay {
     }
 }
 
+/// Linear ramp from the value at the time of the trigger to the new strength
+fn attack_value(start: f32, strength: f32, elapsed: Duration, attack: Duration) -> f32 {
+    start + (strength - start) * (elapsed.as_secs_f32() / attack.as_secs_f32())
+}
+
+/// Fades from `start_color` at the onset to `end_color` once the envelope has decayed.
+/// `ease` shapes the fade as `t.powf(ease)`, values above 1 hold the start color longer,
+/// values below 1 move to the end color sooner.
 #[allow(dead_code)]
 pub struct Color {
     start_color: [f32; 3],
     end_color: [f32; 3],
+    ease: f32,
     pub envelope: FixedDecay,
 }
 
 #[allow(dead_code)]
 impl Color {
-    pub fn init(from_color: [u16; 3], to_color: [u16; 3], length: Duration) -> Color {
+    pub fn init(from_color: [u16; 3], to_color: [u16; 3], length: Duration, ease: f32) -> Color {
         Color {
             start_color: rgb_to_hsv(from_color),
             end_color: rgb_to_hsv(to_color),
+            ease,
             envelope: FixedDecay::init(length),
         }
     }
@@ -98,13 +138,19 @@ impl Color {
         self.envelope.trigger(strength);
     }
 
+    /// Changes the color the next onsets fade from
+    pub fn set_start_color(&mut self, color: [u16; 3]) {
+        self.start_color = rgb_to_hsv(color);
+    }
+
     pub fn get_color(&self) -> [u16; 3] {
-        let t = self.envelope.strength - self.envelope.get_value();
+        let t = (self.envelope.strength - self.envelope.get_value()).clamp(0.0, 1.0);
+        let t = t.powf(self.ease);
         hsv_to_rgb(&interpolate_hsv(&self.start_color, &self.end_color, t))
     }
 }
 
-#[allow(dead_code)]
+#[derive(Debug)]
 pub struct AnimationHelper<T> {
     animator: fn(u64) -> T,
     time_ref: Instant,
@@ -114,7 +160,6 @@ pub struct AnimationHelper<T> {
     stopped: bool,
 }
 
-#[allow(dead_code)]
 impl<T> AnimationHelper<T> {
     pub fn init(animator: fn(u64) -> T, length: u64, looping: bool) -> AnimationHelper<T> {
         AnimationHelper {
@@ -154,3 +199,91 @@ impl<T> AnimationHelper<T> {
         self.looping = looping;
     }
 }
+
+/// Length of one breath of the idle animation in milliseconds
+const IDLE_PERIOD: u64 = 4000;
+/// Time the idle animation takes to fade in after the timeout
+const IDLE_FADE_IN: Duration = Duration::from_secs(2);
+
+/// Breathing between a fifth and full brightness
+fn breathe(position: u64) -> f32 {
+    let phase = position as f32 / IDLE_PERIOD as f32 * 2.0 * PI;
+    0.6 - 0.4 * phase.cos()
+}
+
+/// Slowly breathing color that takes over after `timeout` without sound
+pub struct Idle {
+    color: [f32; 3],
+    timeout: Duration,
+    silent_since: Option<Instant>,
+    animation: AnimationHelper<f32>,
+}
+
+impl Idle {
+    pub fn init(color: [f32; 3], timeout: Duration) -> Idle {
+        Idle {
+            color,
+            timeout,
+            silent_since: None,
+            animation: AnimationHelper::init(breathe, IDLE_PERIOD, true),
+        }
+    }
+
+    pub fn update(&mut self, silent: bool) {
+        match (silent, self.silent_since) {
+            (true, None) => {
+                self.silent_since = Some(Instant::now());
+                self.animation.start();
+            }
+            (false, Some(_)) => {
+                self.silent_since = None;
+                self.animation.stop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Weight of the idle color between 0 and 1
+    fn weight(&self) -> f32 {
+        let Some(silent_since) = self.silent_since else {
+            return 0.0;
+        };
+        let idle_time = silent_since.elapsed().saturating_sub(self.timeout);
+        if idle_time.is_zero() {
+            return 0.0;
+        }
+        let fade_in = (idle_time.as_secs_f32() / IDLE_FADE_IN.as_secs_f32()).min(1.0);
+        fade_in * self.animation.get_value()
+    }
+
+    /// Blends `color` towards the idle color
+    pub fn blend(&self, color: [f32; 3]) -> [f32; 3] {
+        let weight = self.weight();
+        let mut out = color;
+        out.iter_mut()
+            .zip(self.color)
+            .for_each(|(c, idle)| *c += (idle - *c) * weight);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    #[test]
+    fn color_fades_from_start_to_end() {
+        let (red, blue) = ([u16::MAX, 0, 0], [0, 0, u16::MAX]);
+
+        let mut color = Color::init(red, blue, Duration::from_secs(3600), 2.0);
+        color.trigger(1.0);
+        assert_eq!(color.get_color(), red);
+
+        let mut color = Color::init(red, blue, Duration::from_millis(5), 2.0);
+        color.trigger(1.0);
+        sleep(Duration::from_millis(10));
+        assert_eq!(color.get_color(), blue);
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt::{self, Display, Formatter};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::broadcast::{self, error::RecvError, Sender},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+use super::{LightService, Onset};
+
+/// Frames a slow client may fall behind before it starts missing onsets
+const CLIENT_BUFFER: usize = 256;
+
+#[derive(Debug)]
+pub enum EventSinkError {
+    Socket(std::io::Error),
+}
+
+impl std::error::Error for EventSinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EventSinkError::Socket(e) => Some(e),
+        }
+    }
+}
+
+impl Display for EventSinkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(_) => write!(f, "Binding event sink socket failed"),
+        }
+    }
+}
+
+impl From<std::io::Error> for EventSinkError {
+    fn from(err: std::io::Error) -> Self {
+        EventSinkError::Socket(err)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct EventSinkSettings {
+    /// `ip:port` to listen on with TCP or `unix:path` for a Unix domain socket
+    pub address: String,
+    pub audio_device: Option<String>,
+}
+
+impl Default for EventSinkSettings {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:7000".to_owned(),
+            audio_device: None,
+        }
+    }
+}
+
+/// Onset as an externally tagged JSON object, e.g. `{"Drum":0.5}`
+pub fn onset_to_json(onset: &Onset) -> Value {
+    match onset {
+        Onset::Full(strength) => json!({ "Full": strength }),
+        Onset::Atmosphere(strength, frequency) => json!({ "Atmosphere": [strength, frequency] }),
+        Onset::Note(strength, frequency) => json!({ "Note": [strength, frequency] }),
+        Onset::Drum(strength) => json!({ "Drum": strength }),
+        Onset::Hihat(strength) => json!({ "Hihat": strength }),
+        Onset::Raw(strength) => json!({ "Raw": strength }),
+        Onset::Timbre(coefficients) => json!({ "Timbre": coefficients }),
+        Onset::Pitch(note) => json!({ "Pitch": note }),
+        Onset::Pan(pan) => json!({ "Pan": pan }),
+    }
+}
+
+/// Streams every onset as newline delimited JSON to all connected clients
+pub struct EventSink {
+    tx: Sender<Bytes>,
+    handle: JoinHandle<()>,
+}
+
+impl EventSink {
+    pub async fn bind(address: &str) -> Result<EventSink, EventSinkError> {
+        let (tx, _) = broadcast::channel(CLIENT_BUFFER);
+
+        let handle = match address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                // Remove the socket of a previous run
+                let _ = std::fs::remove_file(path);
+                let listener = tokio::net::UnixListener::bind(path)?;
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        debug!("Event sink client connected");
+                        tokio::spawn(Self::serve(stream, tx.subscribe()));
+                    }
+                })
+            }
+            #[cfg(not(unix))]
+            Some(_) => {
+                return Err(EventSinkError::Socket(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix domain sockets are not supported on this platform",
+                )))
+            }
+            None => {
+                let listener = TcpListener::bind(address).await?;
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Ok((stream, client)) = listener.accept().await {
+                        debug!("Event sink client {client} connected");
+                        tokio::spawn(Self::serve(stream, tx.subscribe()));
+                    }
+                })
+            }
+        };
+
+        info!("Sending onsets to clients of {address}");
+
+        Ok(EventSink { tx, handle })
+    }
+
+    async fn serve(
+        mut stream: impl AsyncWrite + Unpin,
+        mut rx: broadcast::Receiver<Bytes>,
+    ) -> std::io::Result<()> {
+        loop {
+            match rx.recv().await {
+                Ok(line) => {
+                    if let Err(e) = stream.write_all(&line).await {
+                        debug!("Event sink client disconnected: {e}");
+                        return Err(e);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event sink client is too slow, skipped {skipped} onsets");
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Drop for EventSink {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl LightService for EventSink {
+    fn process_onset(&mut self, event: Onset) {
+        // Skip serializing while no client is connected
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let mut line = onset_to_json(&event).to_string();
+        line.push('\n');
+        let _ = self.tx.send(Bytes::from(line));
+    }
+}
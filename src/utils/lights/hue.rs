@@ -1,6 +1,6 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use ciborium::{from_reader, into_writer};
-use log::{debug, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
 use reqwest::{Client, ClientBuilder};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -9,14 +9,18 @@ use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     num::ParseIntError,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::{net::UdpSocket, select};
+use tokio::select;
+use tracing::{debug, info, warn};
 use webrtc_dtls::{cipher_suite::CipherSuiteId, config::Config, conn::DTLSConn};
 
 use super::{
+    color::Palette,
+    connect_udp,
     envelope::{self, Envelope},
-    Closeable, Pollable, PollingHelper, Stream, Writeable,
+    url_host, Closeable, Pollable, PollingHelper, Reactions, Route, Routing, Stream, WriteStatus,
+    Writeable,
 };
 use crate::utils::{audioprocessing::Onset, lights::LightService};
 
@@ -29,7 +33,10 @@ pub enum HueError {
     NoBridgeFound,
     SaveBridgeError(std::io::Error),
     EntertainmentAreaNotFound,
+    EmptyArea(String),
     IPError(std::net::AddrParseError),
+    Socket(std::io::Error),
+    Cancelled,
 }
 
 impl std::error::Error for HueError {
@@ -39,6 +46,7 @@ impl std::error::Error for HueError {
             HueError::Handshake(e) => Some(e),
             HueError::SaveBridgeError(e) => Some(e),
             HueError::IPError(e) => Some(e),
+            HueError::Socket(e) => Some(e),
             _ => None,
         }
     }
@@ -57,7 +65,10 @@ impl Display for HueError {
             Self::NoBridgeFound => write!(f, "No Bridges could be found"),
             Self::SaveBridgeError(_) => write!(f, "Error saving bridges to file"),
             Self::EntertainmentAreaNotFound => write!(f, "Entertainment area could not be found"),
+            Self::EmptyArea(name) => write!(f, "Entertainment area {name} has no lights"),
             Self::IPError(_) => write!(f, "IP address is in the wrong format"),
+            Self::Socket(_) => write!(f, "Socket to the bridge could not be opened"),
+            Self::Cancelled => write!(f, "Pairing was cancelled"),
         }
     }
 }
@@ -97,44 +108,117 @@ impl From<std::net::AddrParseError> for HueError {
     }
 }
 
-impl Writeable for DTLSConn {
+/// DTLS connection to the bridge that is rebuilt when the bridge drops the session
+struct HueStream {
+    connection: Option<DTLSConn>,
+    ip: IpAddr,
+    area_id: String,
+    app_key: String,
+    app_id: String,
+    psk: String,
+    last_attempt: Instant,
+}
+
+impl HueStream {
+    async fn reconnect(&mut self) -> Result<DTLSConn, HueError> {
+        BridgeConnection::start_entertainment_mode(&self.ip, &self.area_id, &self.app_key).await?;
+        let connect = BridgeConnection::dtls_connection(
+            self.app_id.as_bytes(),
+            self.psk.clone(),
+            self.ip,
+            2100,
+        );
+        tokio::time::timeout(RECONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| HueError::TimeOut)?
+    }
+}
+
+impl Writeable for HueStream {
     async fn write_data(&mut self, data: &Bytes) -> std::io::Result<()> {
-        match self.write(data, None).await {
+        if let Some(connection) = &mut self.connection {
+            match connection.write(data, None).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!("Connection to the bridge lost: {e}");
+                    let _ = connection.close().await;
+                    self.connection = None;
+                }
+            }
+        }
+
+        if self.last_attempt.elapsed() < RECONNECT_INTERVAL {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        }
+        self.last_attempt = Instant::now();
+
+        info!("Reconnecting to the bridge");
+        let connection = self
+            .reconnect()
+            .await
+            .map_err(|e| std::io::Error::other(format!("Reconnecting failed: {e}")))?;
+        info!("Connection reestablished");
+        let connection = self.connection.insert(connection);
+        match connection.write(data, None).await {
             Ok(_) => Ok(()),
-            Err(e) => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("DTLS write failed: {e}"),
-            )),
+            Err(e) => Err(std::io::Error::other(format!("DTLS write failed: {e}"))),
         }
     }
 }
 
-impl Closeable for DTLSConn {
+impl Closeable for HueStream {
     async fn close_connection(&mut self) {
-        self.close().await.unwrap();
+        if let Some(connection) = self.connection.take() {
+            if let Err(e) = connection.close().await {
+                debug!("Closing the DTLS connection failed: {e}");
+            }
+        }
     }
 }
 
-impl Stream for DTLSConn {}
+impl Stream for HueStream {}
 
 // TODO: Move save file to a proper permanent location
 static CONFIG_PATH: &str = "hue.cbor";
+/// How often the remaining time is logged while waiting for the push link button
+const PUSH_LINK_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Service Hue bridges announce via mDNS
+const HUE_SERVICE: &str = "_hue._tcp.local.";
+/// Time the mDNS discovery waits for bridges to answer
+const MDNS_BROWSE_TIME: Duration = Duration::from_secs(3);
+/// Minimum time between two attempts to rebuild a dropped connection
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+/// Time the bridge gets to finish the handshake of a rebuilt connection
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BridgeData {
     pub id: String,
-    pub ip: Ipv4Addr,
+    #[serde(deserialize_with = "deserialize_saved_ip")]
+    pub ip: IpAddr,
     pub app_key: String,
     pub app_id: String,
     pub psk: String,
 }
 
+/// Bridges saved before IPv6 support stored their address as [`Ipv4Addr`]
+fn deserialize_saved_ip<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<IpAddr, D::Error> {
+    let value = ciborium::Value::deserialize(deserializer)?;
+    value
+        .deserialized::<Ipv4Addr>()
+        .map(IpAddr::V4)
+        .or_else(|_| value.deserialized::<IpAddr>())
+        .map_err(serde::de::Error::custom)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 struct UnauthenticatedBridge {
     #[serde(rename = "id")]
     _id: String,
     #[serde(rename = "internalipaddress")]
-    ip: Ipv4Addr,
+    ip: IpAddr,
 }
 
 #[derive(Debug, Deserialize)]
@@ -167,8 +251,7 @@ struct _Metadata {
 #[derive(Deserialize, Debug, Clone, Copy)]
 struct EntertainmentChannels {
     channel_id: u8,
-    #[serde(rename = "position")]
-    _position: Point,
+    position: Point,
 }
 
 #[allow(dead_code)]
@@ -191,11 +274,17 @@ struct EntertainmentArea {
 #[serde(default, rename_all = "PascalCase")]
 pub struct HueSettings {
     #[serde(rename = "ip")]
-    pub ip: Option<Ipv4Addr>,
+    pub ip: Option<IpAddr>,
+    /// Name or id of the bridge, only used without `ip`
+    #[serde(rename = "bridge")]
+    pub bridge: Option<String>,
+    /// Name or id of the entertainment area
     #[serde(rename = "area")]
     pub area: Option<String>,
     #[serde(rename = "auth_file")]
     pub auth_file: Option<String>,
+    #[serde(rename = "audio_device")]
+    pub audio_device: Option<String>,
     #[serde(flatten)]
     pub light_settings: LightSettings,
     pub push_link_timeout: Duration,
@@ -206,8 +295,10 @@ impl Default for HueSettings {
     fn default() -> Self {
         Self {
             ip: None,
+            bridge: None,
             area: None,
             auth_file: None,
+            audio_device: None,
             light_settings: Default::default(),
             push_link_timeout: Duration::from_secs(30),
             timeout: Duration::from_secs(2),
@@ -249,7 +340,7 @@ impl BridgeManager {
         candidates
     }
 
-    async fn check_bridge_reachable(&self, ip: &Ipv4Addr) -> bool {
+    async fn check_bridge_reachable(&self, ip: &IpAddr) -> bool {
         let Ok(config) = self.get_bridge_config(*ip).await else {
             return false;
         };
@@ -259,7 +350,56 @@ impl BridgeManager {
         true
     }
 
+    /// Compares the name case insensitive, the id exactly
+    async fn bridge_has_name(&self, ip: IpAddr, name: &str) -> bool {
+        match self.get_bridge_config(ip).await {
+            Ok(config) => config.name.eq_ignore_ascii_case(name) || config.id == name,
+            Err(_) => false,
+        }
+    }
+
+    /// Asks the cloud discovery first and falls back to mDNS if it finds no reachable bridge
     async fn search_bridges(&self) -> Result<Vec<UnauthenticatedBridge>, HueError> {
+        let (candidates, cloud_error) = match self.cloud_discovery().await {
+            Ok(bridges) => (bridges, None),
+            Err(e) => {
+                warn!("Cloud discovery failed: {e}");
+                (Vec::new(), Some(e))
+            }
+        };
+
+        let mut bridges = self.retain_reachable(candidates.clone()).await;
+        if bridges.is_empty() {
+            info!("Searching bridges with mDNS");
+            match mdns_discovery().await {
+                Ok(mut found) => {
+                    found.retain(|bridge| !candidates.iter().any(|c| c.ip == bridge.ip));
+                    bridges = self.retain_reachable(found).await;
+                }
+                Err(e) => warn!("mDNS discovery failed: {e}"),
+            }
+        }
+
+        match cloud_error {
+            Some(e) if bridges.is_empty() => Err(e),
+            _ => Ok(bridges),
+        }
+    }
+
+    async fn retain_reachable(
+        &self,
+        bridges: Vec<UnauthenticatedBridge>,
+    ) -> Vec<UnauthenticatedBridge> {
+        let mut reachable = Vec::new();
+        for bridge in bridges {
+            if self.check_bridge_reachable(&bridge.ip).await {
+                reachable.push(bridge);
+            }
+        }
+        reachable
+    }
+
+    async fn cloud_discovery(&self) -> Result<Vec<UnauthenticatedBridge>, HueError> {
         #[derive(Deserialize, Debug)]
         struct BridgeJson {
             id: String,
@@ -279,24 +419,23 @@ impl BridgeManager {
         let mut bridges: Vec<UnauthenticatedBridge> = Vec::new();
 
         for bridge in local_bridges.into_iter() {
-            if !self
-                .check_bridge_reachable(&bridge.ip_address.parse().unwrap())
-                .await
-            {
+            // The discovery returns IPv4 or IPv6 addresses
+            let Ok(ip) = bridge.ip_address.parse::<IpAddr>() else {
+                warn!(
+                    "Discovered bridge with invalid address {}",
+                    bridge.ip_address
+                );
                 continue;
-            }
-
-            bridges.push(UnauthenticatedBridge {
-                _id: bridge.id,
-                ip: bridge.ip_address.parse().unwrap(),
-            });
+            };
+            bridges.push(UnauthenticatedBridge { _id: bridge.id, ip });
         }
         Ok(bridges)
     }
 
     async fn locate_bridge(
         &self,
-        ip: Option<Ipv4Addr>,
+        ip: Option<IpAddr>,
+        name: Option<&str>,
         timeout: Option<Duration>,
         save_file: &str,
     ) -> Result<BridgeData, HueError> {
@@ -305,6 +444,14 @@ impl BridgeManager {
 
         if let Some(ip) = ip {
             found_bridges.retain(|bridge| bridge.ip == ip);
+        } else if let Some(name) = name {
+            let mut named = Vec::new();
+            for bridge in found_bridges {
+                if self.bridge_has_name(bridge.ip, name).await {
+                    named.push(bridge);
+                }
+            }
+            found_bridges = named;
         } else if found_bridges.len() > 1 {
             warn!("Multiple bridges found");
             for bridge in found_bridges.iter().rev() {
@@ -312,7 +459,7 @@ impl BridgeManager {
                 warn!("Name: {}, IP: {}", config.name, bridge.ip);
             }
             warn!("The first bridge will be selected");
-            warn!("If you want to use a different bridge, please specify it with the given IP");
+            warn!("If you want to use a different bridge, please specify it with the given name or IP");
         }
 
         if !found_bridges.is_empty() {
@@ -322,6 +469,14 @@ impl BridgeManager {
         let mut new_bridges = self.search_bridges().await?;
         if let Some(ip) = ip {
             new_bridges.retain(|bridge| bridge.ip == ip);
+        } else if let Some(name) = name {
+            let mut named = Vec::new();
+            for bridge in new_bridges {
+                if self.bridge_has_name(bridge.ip, name).await {
+                    named.push(bridge);
+                }
+            }
+            new_bridges = named;
         } else if new_bridges.len() > 1 {
             warn!("Multiple bridges found");
             for bridge in new_bridges.iter().rev() {
@@ -329,7 +484,7 @@ impl BridgeManager {
                 warn!("Name: {}, IP: {}", config.name, bridge.ip);
             }
             warn!("The first bridge will be selected");
-            warn!("If you want to use a different bridge, please specify it with the given IP");
+            warn!("If you want to use a different bridge, please specify it with the given name or IP");
         }
 
         let bridge = new_bridges.pop().ok_or(HueError::NoBridgeFound)?;
@@ -345,7 +500,7 @@ impl BridgeManager {
 
     async fn authenticate_bridge(
         &self,
-        ip: Ipv4Addr,
+        ip: IpAddr,
         timeout: Option<Duration>,
     ) -> Result<BridgeData, HueError> {
         #[derive(Serialize, Debug)]
@@ -371,7 +526,10 @@ impl BridgeManager {
             generateclientkey: true,
         };
 
-        warn!("Please press push link button");
+        warn!(
+            "Please press push link button, waiting {}s (cancel with CTRL-C)",
+            timeout.as_secs()
+        );
 
         let mut saved_bridge = BridgeData {
             id: config.id,
@@ -385,7 +543,7 @@ impl BridgeManager {
             _ = async {
                 loop {
                     let response = self.client
-                        .post(format!("https://{}/api", ip))
+                        .post(format!("https://{}/api", url_host(ip)))
                         .json(&params)
                         .send()
                         .await?;
@@ -412,11 +570,26 @@ impl BridgeManager {
             _ = tokio::time::sleep(timeout) => {
                 return Err(HueError::TimeOut);
             }
+            _ = async {
+                let start = tokio::time::Instant::now();
+                let mut progress = tokio::time::interval_at(
+                    start + PUSH_LINK_PROGRESS_INTERVAL,
+                    PUSH_LINK_PROGRESS_INTERVAL,
+                );
+                loop {
+                    progress.tick().await;
+                    let remaining = timeout.saturating_sub(start.elapsed());
+                    warn!("Waiting for push link button… {}s remaining", remaining.as_secs());
+                }
+            } => {}
+            _ = tokio::signal::ctrl_c() => {
+                return Err(HueError::Cancelled);
+            }
         }
 
         let response = self
             .client
-            .get(format!("https://{}/auth/v1", ip))
+            .get(format!("https://{}/auth/v1", url_host(ip)))
             .header("hue-application-key", &saved_bridge.app_key)
             .send()
             .await?;
@@ -450,7 +623,7 @@ impl BridgeManager {
             .client
             .get(format!(
                 "https://{}/clip/v2/resource/entertainment_configuration",
-                &bridge.ip
+                url_host(bridge.ip)
             ))
             .header("hue-application-key", &bridge.app_key)
             .send()
@@ -460,10 +633,10 @@ impl BridgeManager {
         Ok(response.data)
     }
 
-    async fn get_bridge_config(&self, ip: Ipv4Addr) -> Result<BridgeConfig, HueError> {
+    async fn get_bridge_config(&self, ip: IpAddr) -> Result<BridgeConfig, HueError> {
         let response = self
             .client
-            .get(format!("https://{}/api/0/config", ip))
+            .get(format!("https://{}/api/0/config", url_host(ip)))
             .send()
             .await?;
 
@@ -486,36 +659,84 @@ impl BridgeManager {
         area: Option<String>,
         settings: LightSettings,
     ) -> Result<BridgeConnection, HueError> {
-        let mut areas = self.get_entertainment_areas(&bridge).await?;
-
-        if let Some(area) = area {
-            areas.retain(|ent_area| ent_area.id == area);
-        } else if areas.len() > 1 {
-            warn!("Multiple areas found");
-            for area in areas.iter().rev() {
-                warn!("Name: {}, ID: {}", area._metadata._name, area.id);
+        let areas = self.get_entertainment_areas(&bridge).await?;
+        let area = select_area(areas, area)?;
+
+        BridgeConnection::with_settings(bridge, area, settings).await
+    }
+}
+
+/// Area matching the given name or id, or the first one if none is given
+fn select_area(
+    mut areas: Vec<EntertainmentArea>,
+    area: Option<String>,
+) -> Result<EntertainmentArea, HueError> {
+    if let Some(area) = area {
+        areas.retain(|ent_area| {
+            ent_area.id == area || ent_area._metadata._name.eq_ignore_ascii_case(&area)
+        });
+    } else if areas.len() > 1 {
+        warn!("Multiple areas found");
+        for area in areas.iter().rev() {
+            warn!("Name: {}, ID: {}", area._metadata._name, area.id);
+        }
+        warn!("The first area will be selected");
+        warn!("If you want to use a different area, please specify it with the given name or ID");
+    }
+    let area = areas.pop().ok_or(HueError::EntertainmentAreaNotFound)?;
+    if area.channels.is_empty() {
+        return Err(HueError::EmptyArea(area._metadata._name));
+    }
+    Ok(area)
+}
+
+/// Collects the bridges announcing themselves on the local network for [`MDNS_BROWSE_TIME`]
+async fn mdns_discovery() -> Result<Vec<UnauthenticatedBridge>, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(HUE_SERVICE)?;
+
+    let mut bridges = Vec::new();
+    let _ = tokio::time::timeout(MDNS_BROWSE_TIME, async {
+        while let Ok(event) = receiver.recv_async().await {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                let id = info
+                    .get_property_val_str("bridgeid")
+                    .unwrap_or(info.get_fullname());
+                // Bridges are announced repeatedly
+                for &ip in info.get_addresses() {
+                    if bridges.iter().any(|b: &UnauthenticatedBridge| b.ip == ip) {
+                        continue;
+                    }
+                    bridges.push(UnauthenticatedBridge {
+                        _id: id.to_owned(),
+                        ip,
+                    });
+                }
             }
-            warn!("The first area will be selected");
-            warn!("If you want to use a different area, please specify it with the given ID");
         }
-        let area = areas.pop().ok_or(HueError::EntertainmentAreaNotFound)?;
+    })
+    .await;
 
-        BridgeConnection::with_settings(bridge, area, settings).await
+    if let Err(e) = daemon.shutdown() {
+        debug!("Stopping the mDNS daemon failed: {e}");
     }
+    Ok(bridges)
 }
 
 pub async fn connect() -> Result<BridgeConnection, HueError> {
     let manager = BridgeManager::new(HueSettings::default().timeout);
 
-    let bridge = manager.locate_bridge(None, None, CONFIG_PATH).await?;
+    let bridge = manager.locate_bridge(None, None, None, CONFIG_PATH).await?;
 
     manager.start_connection(bridge, None).await
 }
 
-pub async fn connect_by_ip(ip: Ipv4Addr) -> Result<BridgeConnection, HueError> {
+pub async fn connect_by_ip(ip: IpAddr) -> Result<BridgeConnection, HueError> {
     let manager = BridgeManager::new(HueSettings::default().timeout);
 
-    let bridge = manager.locate_bridge(Some(ip), None, CONFIG_PATH).await?;
+    let bridge = manager
+        .locate_bridge(Some(ip), None, None, CONFIG_PATH)
+        .await?;
 
     manager.start_connection(bridge, None).await
 }
@@ -526,6 +747,7 @@ pub async fn connect_with_settings(settings: HueSettings) -> Result<BridgeConnec
     let bridge = manager
         .locate_bridge(
             settings.ip,
+            settings.bridge.as_deref(),
             Some(settings.push_link_timeout),
             &settings.auth_file.unwrap_or(CONFIG_PATH.to_owned()),
         )
@@ -539,12 +761,15 @@ pub async fn connect_with_settings(settings: HueSettings) -> Result<BridgeConnec
 #[allow(dead_code)]
 pub struct BridgeConnection {
     id: String,
-    ip: Ipv4Addr,
+    ip: IpAddr,
     app_key: String,
     app_id: String,
     area: EntertainmentArea,
     polling_helper: PollingHelper,
     state: Arc<Mutex<State>>,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+    routing: Routing,
 }
 
 impl BridgeConnection {
@@ -570,13 +795,23 @@ impl BridgeConnection {
         Self::start_entertainment_mode(&ip, &area.id, &app_key).await?;
 
         info!("Building DTLS connection");
-        let connection =
-            Self::dtls_connection(app_id.as_bytes(), psk.clone(), IpAddr::V4(ip), 2100).await?;
+        let connection = Self::dtls_connection(app_id.as_bytes(), psk.clone(), ip, 2100).await?;
         info!("Connection established");
 
+        let reactions = settings.reactions;
+        let routing = settings.routing;
         let state = Arc::new(Mutex::new(State::with_settings(&area, settings)));
 
-        let polling_helper = PollingHelper::init(connection, state.clone(), 55.0);
+        let stream = HueStream {
+            connection: Some(connection),
+            ip,
+            area_id: area.id.clone(),
+            app_key: app_key.clone(),
+            app_id: app_id.clone(),
+            psk,
+            last_attempt: Instant::now(),
+        };
+        let polling_helper = PollingHelper::init(stream, state.clone(), 55.0);
 
         let bridge = BridgeConnection {
             id,
@@ -586,12 +821,15 @@ impl BridgeConnection {
             area,
             polling_helper,
             state,
+            reactions,
+            reaction_override: None,
+            routing,
         };
         Ok(bridge)
     }
 
     async fn start_entertainment_mode(
-        bridge_ip: &Ipv4Addr,
+        bridge_ip: &IpAddr,
         area_id: &str,
         app_key: &str,
     ) -> Result<reqwest::Response, HueError> {
@@ -599,8 +837,10 @@ impl BridgeConnection {
             .danger_accept_invalid_certs(true)
             .timeout(Duration::from_secs(5))
             .build()?;
-        let url =
-            format!("https://{bridge_ip}/clip/v2/resource/entertainment_configuration/{area_id}");
+        let url = format!(
+            "https://{}/clip/v2/resource/entertainment_configuration/{area_id}",
+            url_host(bridge_ip)
+        );
         Ok(client
             .put(url)
             .header("hue-application-key", app_key)
@@ -623,11 +863,11 @@ impl BridgeConnection {
             ..Default::default()
         };
 
-        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
-        socket
-            .connect(SocketAddr::new(dest_ip, dest_port))
-            .await
-            .unwrap();
+        let socket = Arc::new(
+            connect_udp(SocketAddr::new(dest_ip, dest_port))
+                .await
+                .map_err(HueError::Socket)?,
+        );
         debug!("Bound: {}", socket.local_addr().unwrap());
         Ok(DTLSConn::new(socket, config, true, None).await?)
     }
@@ -635,31 +875,38 @@ impl BridgeConnection {
 
 impl LightService for BridgeConnection {
     fn process_onset(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
+        let Some((route, volume)) = self.routing.route(&event) else {
+            return;
+        };
+        self.state.lock().unwrap().trigger(route, volume);
+    }
+
+    fn process_samples(&mut self, samples: &[f32]) {
         let mut state = self.state.lock().unwrap();
-        match event {
-            Onset::Full(volume) => {
-                if volume > state.fullband.envelope.get_value() {
-                    state.fullband.trigger(volume);
-                }
-            }
-            Onset::Drum(volume) => {
-                if volume > state.drum.get_value() {
-                    state.drum.trigger(volume);
-                }
-            }
-            Onset::Hihat(volume) => {
-                if volume > state.hihat.get_value() {
-                    state.hihat.trigger(volume);
-                }
-            }
-            Onset::Note(volume, _) => {
-                if volume > state.note.get_value() {
-                    state.note.trigger(volume);
-                }
-            }
-            _ => {}
+        if let Some(idle) = &mut state.idle {
+            idle.update(samples.iter().all(|&s| s == 0.0));
         }
     }
+
+    /// One dark packet before the DTLS connection is closed
+    fn shutdown(&mut self) {
+        self.polling_helper.shutdown();
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.polling_helper.last_write())
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
 }
 
 fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
@@ -674,13 +921,22 @@ struct State {
     hihat: envelope::FixedDecay,
     note: envelope::FixedDecay,
     fullband: envelope::Color,
+    palette: Palette,
     prefix: Vec<u8>,
     channels: Vec<u8>,
+    /// Position of every channel from left (0) to right (1)
+    positions: Vec<f32>,
+    spatial: bool,
     color_envelope: bool,
+    smoothing: f32,
+    smoothed: Mutex<Vec<[f32; 3]>>,
+    min_brightness: f32,
+    max_brightness: f32,
+    idle: Option<envelope::Idle>,
     buffer: BytesMut,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct LightSettings {
     pub drum_decay_rate: f32,
@@ -690,8 +946,37 @@ pub struct LightSettings {
     pub hihat_decay: Duration,
     #[serde(rename = "FullbandDecay")]
     pub fullband_decay: Duration,
+    /// Color at the onset and color once the fullband envelope has decayed,
+    /// only used with `color_envelope`
     pub fullband_color: ([u16; 3], [u16; 3]),
+    /// Exponent of the fade between the two fullband colors, 1 fades linearly
+    pub fullband_ease: f32,
+    /// Colors the fullband onsets cycle through instead of the first `fullband_color`,
+    /// empty is a palette of only the first `fullband_color`
+    pub palette: Vec<[u16; 3]>,
+    /// Fullband onsets it takes to fade from one palette color to the next
+    pub palette_steps: u32,
     pub color_envelope: bool,
+    /// Bias the channels on the left of the area towards the drums and the ones on the right towards the hihat
+    pub spatial: bool,
+    /// Time the drum, hihat and note envelopes take to reach their peak after an onset
+    #[serde(rename = "Attack")]
+    pub attack: Duration,
+    /// Coefficient of a one-pole low-pass on the sent colors between 0 and 1, 0 disables it
+    pub smoothing: f32,
+    /// Brightness of the drum, hihat and note envelopes between 0 and 1 when they are fully decayed
+    pub min_brightness: f32,
+    /// Brightness of the drum, hihat and note envelopes between 0 and 1 at their peak
+    pub max_brightness: f32,
+    /// Color the lights slowly breathe in after `idle_timeout` without sound
+    pub idle_color: Option<[u16; 3]>,
+    #[serde(rename = "IdleTimeout")]
+    pub idle_timeout: Duration,
+    #[serde(flatten)]
+    pub reactions: Reactions,
+    /// Envelope every onset band triggers
+    #[serde(rename = "Routing")]
+    pub routing: Routing,
 }
 
 impl Default for LightSettings {
@@ -702,7 +987,19 @@ impl Default for LightSettings {
             hihat_decay: Duration::from_millis(80),
             fullband_decay: Duration::from_millis(250),
             fullband_color: ([u16::MAX, 0, 0], [2, 0, 1]),
+            fullband_ease: 1.0,
+            palette: Vec::new(),
+            palette_steps: 1,
             color_envelope: false,
+            spatial: false,
+            attack: Duration::ZERO,
+            smoothing: 0.0,
+            min_brightness: 0.0,
+            max_brightness: 1.0,
+            idle_color: None,
+            idle_timeout: Duration::from_secs(10),
+            reactions: Reactions::default(),
+            routing: Routing::default(),
         }
     }
 }
@@ -717,50 +1014,204 @@ impl State {
         prefix.extend([2, 0, 0, 0, 0, 0, 0]); // Api Version, empty sequence id, color space = RGB and reserved bytes. See also https://developers.meethue.com/develop/hue-entertainment/hue-entertainment-api/#getting-started-with-streaming-api
         prefix.put(area.id.as_bytes());
 
-        let channels: Vec<_> = area.channels.iter().map(|chan| chan.channel_id).collect();
-        let buffer_size = prefix.len() + 7 * channels.clone().len();
+        let mut area_channels = area.channels.clone();
+        area_channels.sort_by(|a, b| a.position.x.total_cmp(&b.position.x));
+        let channels: Vec<_> = area_channels.iter().map(|chan| chan.channel_id).collect();
+
+        let (left, right) = match (area_channels.first(), area_channels.last()) {
+            (Some(first), Some(last)) => (first.position.x, last.position.x),
+            _ => (0.0, 0.0),
+        };
+        let positions = area_channels
+            .iter()
+            .map(|chan| {
+                if right > left {
+                    (chan.position.x - left) / (right - left)
+                } else {
+                    0.5
+                }
+            })
+            .collect();
+        let buffer_size = prefix.len() + 7 * channels.len();
         State {
-            drum: envelope::DynamicDecay::init(settings.drum_decay_rate),
-            hihat: envelope::FixedDecay::init(settings.hihat_decay),
-            note: envelope::FixedDecay::init(settings.note_decay),
+            drum: envelope::DynamicDecay::with_attack(settings.drum_decay_rate, settings.attack),
+            hihat: envelope::FixedDecay::with_attack(settings.hihat_decay, settings.attack),
+            note: envelope::FixedDecay::with_attack(settings.note_decay, settings.attack),
             fullband: envelope::Color::init(
                 settings.fullband_color.0,
                 settings.fullband_color.1,
                 settings.fullband_decay,
+                settings.fullband_ease,
+            ),
+            palette: Palette::init(
+                settings.palette,
+                settings.palette_steps,
+                settings.fullband_color.0,
             ),
             prefix: prefix.into(),
+            smoothed: Mutex::new(vec![[0.0; 3]; channels.len()]),
             channels,
+            positions,
+            spatial: settings.spatial,
             color_envelope: settings.color_envelope,
+            smoothing: settings.smoothing.clamp(0.0, 0.99),
+            min_brightness: settings.min_brightness.clamp(0.0, 1.0),
+            max_brightness: settings.max_brightness.clamp(0.0, 1.0),
+            idle: settings
+                .idle_color
+                .map(|color| envelope::Idle::init(color.map(f32::from), settings.idle_timeout)),
             buffer: BytesMut::with_capacity(buffer_size),
         }
     }
 }
 
+impl State {
+    /// Triggers the envelope of the route unless it is still brighter than the onset
+    fn trigger(&mut self, route: Route, volume: f32) {
+        match route {
+            Route::Full => {
+                if volume > self.fullband.envelope.get_value() {
+                    self.advance_palette();
+                    self.fullband.trigger(volume);
+                }
+            }
+            Route::Drum => {
+                if volume > self.drum.get_value() {
+                    self.drum.trigger(volume);
+                }
+            }
+            Route::Hihat => {
+                if volume > self.hihat.get_value() {
+                    self.hihat.trigger(volume);
+                }
+            }
+            Route::Note => {
+                if volume > self.note.get_value() {
+                    self.note.trigger(volume);
+                }
+            }
+            Route::Off => {}
+        }
+    }
+
+    /// Moves the onset color one step along the palette
+    fn advance_palette(&mut self) {
+        let color = self.palette.advance();
+        self.fullband.set_start_color(color);
+    }
+
+    /// Maps an envelope value into the configured brightness range
+    fn brightness(&self, value: f32) -> u16 {
+        let range = self.max_brightness - self.min_brightness;
+        ((self.min_brightness + value.clamp(0.0, 1.0) * range) * u16::MAX as f32) as u16
+    }
+
+    fn smooth(&self, channel: usize, color: [f32; 3]) -> [u16; 3] {
+        let mut smoothed = self.smoothed.lock().unwrap();
+        let smoothed = &mut smoothed[channel];
+        smoothed
+            .iter_mut()
+            .zip(color)
+            .for_each(|(s, c)| *s = self.smoothing * *s + (1.0 - self.smoothing) * c);
+        smoothed.map(|c| c as u16)
+    }
+}
+
 impl Pollable for State {
     fn poll(&self) -> Bytes {
         let mut bytes = self.buffer.clone();
         bytes.clear();
         bytes.extend(self.prefix.clone());
-        if self.color_envelope {
-            for id in self.channels.iter() {
-                bytes.put_u8(*id);
-                let color = self.fullband.get_color();
-                bytes.put_u16(color[0]);
-                bytes.put_u16(color[1]);
-                bytes.put_u16(color[2]);
-            }
-        } else {
-            let r = (self.drum.get_value() * u16::MAX as f32) as u16;
-            let white = (self.hihat.get_value() * u16::MAX as f32) as u16 >> 3;
-            let b = (self.note.get_value() * u16::MAX as f32) as u16 >> 1;
-            for id in self.channels.iter() {
-                bytes.put_u8(*id);
-                bytes.put_u16(r.saturating_add(white));
-                bytes.put_u16(white);
-                bytes.put_u16(b.saturating_add(white));
-            }
+        for (i, (id, &position)) in self.channels.iter().zip(&self.positions).enumerate() {
+            let color = if self.color_envelope {
+                self.fullband.get_color().map(f32::from)
+            } else {
+                // Without spatial every channel shows the full mix
+                let (bass, treble) = if self.spatial {
+                    (1.0 - position, position)
+                } else {
+                    (1.0, 1.0)
+                };
+                let r = self.brightness(self.drum.get_value() * bass);
+                let white = self.brightness(self.hihat.get_value() * treble) >> 3;
+                let b = self.brightness(self.note.get_value()) >> 1;
+                [r.saturating_add(white), white, b.saturating_add(white)].map(f32::from)
+            };
+            let color = match &self.idle {
+                Some(idle) => idle.blend(color),
+                None => color,
+            };
+            let color = self.smooth(i, color);
+            bytes.put_u8(*id);
+            bytes.put_u16(color[0]);
+            bytes.put_u16(color[1]);
+            bytes.put_u16(color[2]);
         }
 
         bytes.into()
     }
+
+    fn blackout(&self) -> Option<Bytes> {
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+        bytes.extend(self.prefix.clone());
+        for id in self.channels.iter() {
+            bytes.put_u8(*id);
+            bytes.put_bytes(0, 6);
+        }
+        Some(bytes.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lights::DETECTED_ONSETS;
+
+    fn area(channels: Vec<EntertainmentChannels>) -> EntertainmentArea {
+        EntertainmentArea {
+            id: "area".to_owned(),
+            _metadata: _Metadata {
+                _name: "Living room".to_owned(),
+            },
+            channels,
+        }
+    }
+
+    #[test]
+    fn empty_area_is_rejected() {
+        let state = State::init(&area(Vec::new()));
+        assert_eq!(state.poll().len(), state.prefix.len());
+
+        let err = select_area(vec![area(Vec::new())], None).unwrap_err();
+        assert!(matches!(err, HueError::EmptyArea(name) if name == "Living room"));
+
+        let channel = EntertainmentChannels {
+            channel_id: 0,
+            position: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        let selected = select_area(vec![area(vec![channel])], Some("living room".to_owned()));
+        assert_eq!(selected.unwrap().channels.len(), 1);
+    }
+
+    #[test]
+    fn every_detected_onset_triggers_its_envelope() {
+        for onset in DETECTED_ONSETS {
+            let mut state = State::init(&area(Vec::new()));
+            let (route, volume) = Routing::default().route(&onset).unwrap();
+            state.trigger(route, volume);
+            let value = match route {
+                Route::Drum => state.drum.get_value(),
+                Route::Hihat => state.hihat.get_value(),
+                Route::Note => state.note.get_value(),
+                Route::Full => state.fullband.envelope.get_value(),
+                Route::Off => unreachable!(),
+            };
+            assert!(value > 0.0, "{onset:?} didn't trigger {route:?}");
+        }
+    }
 }
@@ -0,0 +1,363 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use super::{
+    color::rgb_to_hsv,
+    envelope::{Color, DynamicDecay, Envelope, FixedDecay},
+    Closeable, LightService, Onset, Pollable, PollingHelper, Reactions, Stream, WriteStatus,
+    Writeable,
+};
+
+const LIFX_PORT: u16 = 56700;
+const HEADER_SIZE: usize = 36;
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const SET_COLOR: u16 = 102;
+/// Service id of the UDP service in a StateService message
+const UDP_SERVICE: u8 = 1;
+
+#[derive(Debug)]
+pub enum LifxError {
+    Socket(std::io::Error),
+    NoBulbFound,
+}
+
+impl std::error::Error for LifxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LifxError::Socket(e) => Some(e),
+            LifxError::NoBulbFound => None,
+        }
+    }
+}
+
+impl Display for LifxError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(_) => write!(f, "Binding socket failed"),
+            Self::NoBulbFound => write!(f, "No LIFX bulbs could be found"),
+        }
+    }
+}
+
+impl From<std::io::Error> for LifxError {
+    fn from(err: std::io::Error) -> Self {
+        LifxError::Socket(err)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct LifxSettings {
+    /// Bulbs are discovered by broadcast if no ip is given
+    #[serde(rename = "ip")]
+    pub ip: Option<Ipv4Addr>,
+    #[serde(rename = "audio_device")]
+    pub audio_device: Option<String>,
+    #[serde(rename = "drum_decay_rate")]
+    pub drum_decay_rate: f32,
+    pub note_decay: Duration,
+    pub hihat_decay: Duration,
+    pub fullband_decay: Duration,
+    #[serde(rename = "fullband_color")]
+    pub fullband_color: ([u16; 3], [u16; 3]),
+    #[serde(rename = "kelvin")]
+    pub kelvin: u16,
+    /// LIFX bulbs only handle about 20 messages per second
+    #[serde(rename = "polling_rate")]
+    pub polling_rate: f64,
+    /// Smallest change in hue, saturation or brightness between 0 and 1 that is sent to the bulbs
+    #[serde(rename = "min_change")]
+    pub min_change: f32,
+    pub discovery_timeout: Duration,
+    #[serde(flatten)]
+    pub reactions: Reactions,
+}
+
+impl Default for LifxSettings {
+    fn default() -> Self {
+        Self {
+            ip: None,
+            audio_device: None,
+            drum_decay_rate: 4.0,
+            note_decay: Duration::from_millis(200),
+            hihat_decay: Duration::from_millis(150),
+            fullband_decay: Duration::from_millis(500),
+            fullband_color: ([u16::MAX, 0, 0], [2, 0, 1]),
+            kelvin: 3500,
+            polling_rate: 10.0,
+            min_change: 0.02,
+            discovery_timeout: Duration::from_secs(1),
+            reactions: Reactions::default(),
+        }
+    }
+}
+
+/// Socket that only sends non-empty packets, used to skip updates without a noticeable change
+struct LifxSocket(UdpSocket);
+
+impl Writeable for LifxSocket {
+    async fn write_data(&mut self, data: &Bytes) -> std::io::Result<()> {
+        if !data.is_empty() {
+            self.0.send(data).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Closeable for LifxSocket {
+    async fn close_connection(&mut self) {}
+}
+
+impl Stream for LifxSocket {}
+
+pub struct Lifx {
+    bulbs: Vec<(PollingHelper, Arc<Mutex<LifxState>>)>,
+    last_write: WriteStatus,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+}
+
+struct LifxState {
+    drum: DynamicDecay,
+    hihat: FixedDecay,
+    note: FixedDecay,
+    fullband: Color,
+    kelvin: u16,
+    source: u32,
+    transition: u32,
+    min_change: f32,
+    last_color: Mutex<Option<[f32; 3]>>,
+}
+
+impl LifxState {
+    fn with_settings(settings: &LifxSettings) -> Self {
+        LifxState {
+            drum: DynamicDecay::init(settings.drum_decay_rate),
+            hihat: FixedDecay::init(settings.hihat_decay),
+            note: FixedDecay::init(settings.note_decay),
+            fullband: Color::init(
+                settings.fullband_color.0,
+                settings.fullband_color.1,
+                settings.fullband_decay,
+                1.0,
+            ),
+            kelvin: settings.kelvin,
+            source: std::process::id(),
+            transition: (1000.0 / settings.polling_rate) as u32,
+            min_change: settings.min_change,
+            last_color: Mutex::new(None),
+        }
+    }
+
+    /// Hue, saturation and brightness between 0 and 1
+    fn color(&self) -> [f32; 3] {
+        let [h, s, _] = rgb_to_hsv(self.fullband.get_color());
+        let brightness = self
+            .drum
+            .get_value()
+            .max(self.note.get_value())
+            .max(self.hihat.get_value() * 0.5)
+            .clamp(0.0, 1.0);
+        [h / 360.0, s, brightness]
+    }
+
+    fn trigger(&mut self, event: Onset) {
+        match event {
+            Onset::Full(volume) => self.fullband.trigger(volume),
+            Onset::Drum(volume) => self.drum.trigger(volume),
+            Onset::Hihat(volume) => self.hihat.trigger(volume),
+            Onset::Note(volume, _) => self.note.trigger(volume),
+            _ => {}
+        }
+    }
+
+    /// SetColor message with hue, saturation and brightness between 0 and 1
+    fn set_color(&self, color: [f32; 3], transition: u32) -> Bytes {
+        let mut bytes = header(HEADER_SIZE + 13, self.source, SET_COLOR);
+        bytes.put_u8(0);
+        for value in color {
+            bytes.put_u16_le((value * u16::MAX as f32) as u16);
+        }
+        bytes.put_u16_le(self.kelvin);
+        bytes.put_u32_le(transition);
+
+        bytes.into()
+    }
+}
+
+impl Pollable for LifxState {
+    fn poll(&self) -> Bytes {
+        let color = self.color();
+
+        let mut last_color = self.last_color.lock().unwrap();
+        if let Some(last) = *last_color {
+            let hue_change = (color[0] - last[0]).abs();
+            let changed = hue_change.min(1.0 - hue_change) >= self.min_change
+                || (color[1] - last[1]).abs() >= self.min_change
+                || (color[2] - last[2]).abs() >= self.min_change;
+            if !changed {
+                return Bytes::new();
+            }
+        }
+        *last_color = Some(color);
+
+        self.set_color(color, self.transition)
+    }
+
+    /// Turns the brightness of the bulb off and keeps its hue and saturation
+    fn blackout(&self) -> Option<Bytes> {
+        let [hue, saturation, _] = self.color();
+        Some(self.set_color([hue, saturation, 0.0], 0))
+    }
+}
+
+/// LAN protocol header addressed to all devices, without requesting acknowledgements
+fn header(size: usize, source: u32, message_type: u16) -> BytesMut {
+    let mut bytes = BytesMut::with_capacity(size);
+    bytes.put_u16_le(size as u16);
+    bytes.put_u16_le(1024 | 1 << 12 | 1 << 13); // Protocol, addressable and tagged
+    bytes.put_u32_le(source);
+    bytes.put_u64_le(0); // Target
+    bytes.put_slice(&[0; 6]);
+    bytes.put_u8(0); // Response flags
+    bytes.put_u8(0); // Sequence
+    bytes.put_u64_le(0);
+    bytes.put_u16_le(message_type);
+    bytes.put_u16_le(0);
+    bytes
+}
+
+impl Lifx {
+    pub async fn connect() -> Result<Lifx, LifxError> {
+        Self::connect_with_settings(LifxSettings::default()).await
+    }
+
+    pub async fn connect_with_settings(settings: LifxSettings) -> Result<Lifx, LifxError> {
+        let addresses = match settings.ip {
+            Some(ip) => vec![SocketAddr::new(ip.into(), LIFX_PORT)],
+            None => Self::discover(settings.discovery_timeout).await?,
+        };
+        if addresses.is_empty() {
+            return Err(LifxError::NoBulbFound);
+        }
+
+        // All bulbs report to the same status, the last write of any bulb counts
+        let last_write = WriteStatus::default();
+        let mut bulbs = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.connect(address).await?;
+            debug!("Bound: {}", socket.local_addr().unwrap());
+
+            let state = Arc::new(Mutex::new(LifxState::with_settings(&settings)));
+            let polling_helper = PollingHelper::init_with_status(
+                LifxSocket(socket),
+                state.clone(),
+                settings.polling_rate,
+                last_write.clone(),
+            );
+
+            info!("Connected to LIFX bulb at {address}");
+            bulbs.push((polling_helper, state));
+        }
+
+        Ok(Lifx {
+            bulbs,
+            last_write,
+            reactions: settings.reactions,
+            reaction_override: None,
+        })
+    }
+
+    async fn discover(timeout: Duration) -> Result<Vec<SocketAddr>, LifxError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.set_broadcast(true)?;
+        let request = header(HEADER_SIZE, std::process::id(), GET_SERVICE);
+        socket
+            .send_to(&request, (Ipv4Addr::BROADCAST, LIFX_PORT))
+            .await?;
+
+        let mut bulbs: Vec<SocketAddr> = Vec::new();
+        let mut buffer = [0; 128];
+        let _ = tokio::time::timeout(timeout, async {
+            while let Ok((len, address)) = socket.recv_from(&mut buffer).await {
+                // StateService carries the service id and port after the header
+                let message_type = u16::from_le_bytes([buffer[32], buffer[33]]);
+                if len < HEADER_SIZE + 5
+                    || message_type != STATE_SERVICE
+                    || buffer[HEADER_SIZE] != UDP_SERVICE
+                {
+                    continue;
+                }
+                let port = u32::from_le_bytes(buffer[37..41].try_into().unwrap());
+                let address = SocketAddr::new(address.ip(), port as u16);
+                if !bulbs.contains(&address) {
+                    bulbs.push(address);
+                }
+            }
+        })
+        .await;
+
+        Ok(bulbs)
+    }
+}
+
+impl LightService for Lifx {
+    fn process_onset(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
+        for (_, state) in &self.bulbs {
+            state.lock().unwrap().trigger(event);
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for (polling_helper, _) in &mut self.bulbs {
+            polling_helper.shutdown();
+        }
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.last_write.clone())
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lights::DETECTED_ONSETS;
+
+    #[test]
+    fn every_detected_onset_triggers_its_envelope() {
+        for onset in DETECTED_ONSETS {
+            let mut state = LifxState::with_settings(&LifxSettings::default());
+            state.trigger(onset);
+            let value = match onset {
+                Onset::Drum(_) => state.drum.get_value(),
+                Onset::Hihat(_) => state.hihat.get_value(),
+                Onset::Note(..) => state.note.get_value(),
+                _ => state.fullband.envelope.get_value(),
+            };
+            assert!(value > 0.0, "{onset:?} didn't reach the bulb");
+        }
+    }
+}
@@ -1,26 +1,37 @@
 use std::{
+    fmt::Display,
+    net::Ipv6Addr,
     sync::{Arc, Mutex},
     thread::sleep,
 };
 
 use bytes::Bytes;
-use log::{info, trace};
+use serde::{Deserialize, Serialize};
 use tokio::{
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
     select,
     sync::oneshot::{self, Sender},
     task::JoinHandle,
     time,
 };
+use tracing::{info, trace, trace_span, warn, Instrument, Span};
 
 use super::audioprocessing::Onset;
 
+pub mod artnet;
 #[allow(dead_code)]
 pub mod color;
 pub mod console;
 pub mod envelope;
+pub mod event_sink;
 #[allow(dead_code)]
 pub mod hue;
+pub mod lifx;
+pub mod monitor;
+pub mod nanoleaf;
 pub mod serialize;
+pub mod status;
+pub mod websocket;
 #[allow(dead_code)]
 pub mod wled;
 
@@ -32,9 +43,21 @@ pub trait LightService {
             self.process_onset(*onset)
         }
     }
+    /// Onsets detected on a single input channel, only emitted with `per_channel` processing
+    fn process_channel_onsets(&mut self, channel: usize, onsets: &[Onset]) {}
     fn process_spectrum(&mut self, freq_bins: &[f32]) {}
     fn process_samples(&mut self, samples: &[f32]) {}
     fn update(&mut self) {}
+    /// Turns the lights off before the program exits, so they don't freeze on the last frame
+    fn shutdown(&mut self) {}
+    /// Replaces the configured reactions while a profile is active, `None` restores them
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {}
+    /// Scales the brightness of effects drawn from the samples, which don't follow the onset strengths
+    fn dim(&mut self, gain: f32) {}
+    /// Result of the last frame sent to the lights, `None` for services without a poller
+    fn last_write(&self) -> Option<WriteStatus> {
+        None
+    }
 }
 
 impl LightService for [Box<dyn LightService + Send>] {
@@ -44,6 +67,12 @@ impl LightService for [Box<dyn LightService + Send>] {
         }
     }
 
+    fn process_channel_onsets(&mut self, channel: usize, onsets: &[Onset]) {
+        for service in self {
+            service.process_channel_onsets(channel, onsets);
+        }
+    }
+
     fn process_spectrum(&mut self, freq_bins: &[f32]) {
         for service in self {
             service.process_spectrum(freq_bins);
@@ -61,10 +90,121 @@ impl LightService for [Box<dyn LightService + Send>] {
             service.update();
         }
     }
+
+    fn shutdown(&mut self) {
+        for service in self {
+            service.shutdown();
+        }
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        for service in self {
+            service.set_reactions(reactions);
+        }
+    }
+
+    fn dim(&mut self, gain: f32) {
+        for service in self {
+            service.dim(gain);
+        }
+    }
+}
+
+/// Onset bands a light reacts to
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct Reactions {
+    pub react_drum: bool,
+    pub react_hihat: bool,
+    pub react_note: bool,
+    pub react_full: bool,
+}
+
+impl Default for Reactions {
+    fn default() -> Self {
+        Self {
+            react_drum: true,
+            react_hihat: true,
+            react_note: true,
+            react_full: true,
+        }
+    }
+}
+
+impl Reactions {
+    /// Onsets of the other types are always passed on
+    pub fn allows(&self, onset: &Onset) -> bool {
+        match onset {
+            Onset::Drum(_) => self.react_drum,
+            Onset::Hihat(_) => self.react_hihat,
+            Onset::Note(..) => self.react_note,
+            Onset::Full(_) => self.react_full,
+            _ => true,
+        }
+    }
+}
+
+/// Envelope of a light service an onset band triggers
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum Route {
+    Drum,
+    Hihat,
+    Note,
+    Full,
+    /// The onsets don't trigger any envelope
+    Off,
+}
+
+/// Envelope every onset band triggers, by default the one of the same name
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct Routing {
+    pub drum: Route,
+    pub hihat: Route,
+    pub note: Route,
+    pub full: Route,
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Self {
+            drum: Route::Drum,
+            hihat: Route::Hihat,
+            note: Route::Note,
+            full: Route::Full,
+        }
+    }
 }
 
+impl Routing {
+    /// Envelope and strength the onset triggers, `None` if it doesn't trigger any
+    pub fn route(&self, onset: &Onset) -> Option<(Route, f32)> {
+        let (route, strength) = match *onset {
+            Onset::Drum(strength) => (self.drum, strength),
+            Onset::Hihat(strength) => (self.hihat, strength),
+            Onset::Note(strength, _) => (self.note, strength),
+            Onset::Full(strength) => (self.full, strength),
+            _ => return None,
+        };
+        (route != Route::Off).then_some((route, strength))
+    }
+}
+
+/// One onset of every band Hfc, SpecFlux, Cqt and ComplexFlux detect for the lights
+#[cfg(test)]
+pub(crate) const DETECTED_ONSETS: [Onset; 4] = [
+    Onset::Drum(1.0),
+    Onset::Hihat(1.0),
+    Onset::Note(1.0, 440),
+    Onset::Full(1.0),
+];
+
 pub trait Pollable {
     fn poll(&self) -> Bytes;
+    /// Frame with all lights off, sent once by [`PollingHelper::shutdown`]
+    fn blackout(&self) -> Option<Bytes> {
+        None
+    }
 }
 
 pub trait Writeable {
@@ -74,7 +214,7 @@ pub trait Writeable {
     ) -> impl std::future::Future<Output = std::io::Result<()>> + Send;
 }
 
-impl Writeable for tokio::net::UdpSocket {
+impl Writeable for UdpSocket {
     async fn write_data(&mut self, data: &Bytes) -> std::io::Result<()> {
         self.send(data).await?;
         Ok(())
@@ -85,7 +225,7 @@ pub trait Closeable {
     fn close_connection(&mut self) -> impl std::future::Future<Output = ()> + Send;
 }
 
-impl Closeable for tokio::net::UdpSocket {
+impl Closeable for UdpSocket {
     async fn close_connection(&mut self) {
         // UDP socket does not need to be closed
     }
@@ -93,59 +233,149 @@ impl Closeable for tokio::net::UdpSocket {
 
 pub trait Stream: Writeable + Closeable {}
 
-impl Stream for tokio::net::UdpSocket {}
+impl Stream for UdpSocket {}
+
+/// Binds a UDP socket of the address family `addr` resolves to and connects it
+pub async fn connect_udp(addr: impl ToSocketAddrs) -> std::io::Result<UdpSocket> {
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Address could not be resolved",
+        )
+    })?;
+    let local = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Host part of a URL, IPv6 addresses need to be enclosed in brackets
+pub fn url_host(host: impl Display) -> String {
+    let host = host.to_string();
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{host}]")
+    } else {
+        host
+    }
+}
+
+/// Minimum time between two warnings about failed writes of a poller
+const WRITE_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Result of the last frame a poller sent, `None` before the first one
+pub type WriteStatus = Arc<Mutex<Option<Result<(), String>>>>;
 
 #[derive(Debug)]
 pub struct PollingHelper {
-    tx: Option<Sender<()>>,
+    /// Stops the poller, `true` sends a blackout frame before closing the connection
+    tx: Option<Sender<bool>>,
     handle: JoinHandle<()>,
+    last_write: WriteStatus,
 }
 
 type Poll = Arc<Mutex<dyn Pollable + Send + Sync + 'static>>;
 
 impl PollingHelper {
     pub fn init(
+        stream: impl Stream + Send + Sync + 'static,
+        pollable: Poll,
+        polling_frequency: f64,
+    ) -> PollingHelper {
+        Self::init_with_status(stream, pollable, polling_frequency, WriteStatus::default())
+    }
+
+    /// Poller that records the result of every frame in `last_write`, which can be shared by several pollers
+    pub fn init_with_status(
         mut stream: impl Stream + Send + Sync + 'static,
         pollable: Poll,
         polling_frequency: f64,
+        last_write: WriteStatus,
     ) -> PollingHelper {
         let (tx, rx) = oneshot::channel();
         let mut interval =
             time::interval(std::time::Duration::from_secs_f64(1.0 / polling_frequency));
         interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
 
+        let blackout = pollable.clone();
+        let status = last_write.clone();
         let handle = tokio::task::spawn(async move {
             select! {
                 _ = async {
                     interval.tick().await;
+                    let mut last_warning: Option<time::Instant> = None;
                     loop {
                         let bytes = { pollable.clone().lock().unwrap().poll() };
-                        stream.write_data(&bytes).await.unwrap();
+                        // Keep polling, the stream might recover
+                        let result = stream
+                            .write_data(&bytes)
+                            .instrument(trace_span!("write", bytes = bytes.len()))
+                            .await;
+                        if let Err(e) = &result {
+                            if last_warning.is_none_or(|t| t.elapsed() >= WRITE_WARNING_INTERVAL) {
+                                warn!("Sending to the lights failed: {e}");
+                                last_warning = Some(time::Instant::now());
+                            }
+                        }
+                        *status.lock().unwrap() = Some(result.map_err(|e| e.to_string()));
 
                         interval.tick().await;
                     }
                 } => {
                     eprintln!("Never ending loop returned");
                 }
-                _ = rx => {
+                send_blackout = rx => {
+                    if send_blackout.unwrap_or(false) {
+                        let bytes = blackout.lock().unwrap().blackout();
+                        if let Some(bytes) = bytes {
+                            if let Err(e) = stream.write_data(&bytes).await {
+                                warn!("Sending blackout failed: {e}");
+                            }
+                        }
+                    }
                     stream.close_connection().await;
                 }
             }
-        });
+        }
+        // Keeps the span the light connected in, so failed writes name their light
+        .instrument(Span::current()));
 
-        PollingHelper { tx: Some(tx), handle }
+        PollingHelper {
+            tx: Some(tx),
+            handle,
+            last_write,
+        }
     }
 }
 
-impl Drop for PollingHelper {
-    fn drop(&mut self) {
+impl PollingHelper {
+    pub fn last_write(&self) -> WriteStatus {
+        self.last_write.clone()
+    }
+
+    /// Sends the blackout frame of the pollable and stops polling
+    pub fn shutdown(&mut self) {
+        self.stop(true);
+    }
+
+    fn stop(&mut self, blackout: bool) {
+        let Some(tx) = self.tx.take() else {
+            return;
+        };
         info!("Shutting done background poller");
-        if let Some(tx) = self.tx.take() {
-            let _ = tx.send(());
-        }
+        let _ = tx.send(blackout);
         while !self.handle.is_finished() {
             sleep(std::time::Duration::from_nanos(1));
         }
         trace!("Background poller shut down");
     }
 }
+
+impl Drop for PollingHelper {
+    fn drop(&mut self) {
+        self.stop(false);
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::VecDeque,
+    io::{self, stdout, Stdout},
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    backend::CrosstermBackend,
+    buffer::Buffer,
+    crossterm::{
+        cursor::{Hide, Show},
+        execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Widget},
+    Terminal,
+};
+use tracing::warn;
+
+use super::{
+    envelope::{DynamicDecay, Envelope},
+    LightService, Onset,
+};
+use crate::utils::audioprocessing::{
+    spectral_flux::ThresholdBankSettings, MelFilterBank, MelFilterBankSettings,
+};
+
+/// Rows of the spectrogram
+const SPECTROGRAM_BANDS: usize = 48;
+/// Columns of the spectrogram that are kept for wide terminals
+const HISTORY: usize = 512;
+/// Time between two redraws, the audio frames arrive a lot faster
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+/// Decay of the onset meters per second
+const METER_DECAY: f32 = 4.0;
+/// Factor the loudest value of the spectrogram decays by every frame
+const PEAK_DECAY: f32 = 0.999;
+
+/// Full screen terminal display of the spectrogram, the onsets and the thresholds
+pub struct Monitor {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    filter_bank: MelFilterBank,
+    spectrum: Vec<f32>,
+    history: VecDeque<Vec<f32>>,
+    peak: f32,
+    meters: [(&'static str, Color, DynamicDecay); 4],
+    thresholds: Option<ThresholdBankSettings>,
+    last_draw: Instant,
+}
+
+impl Monitor {
+    pub fn init(
+        sample_rate: u32,
+        fft_size: u32,
+        thresholds: Option<ThresholdBankSettings>,
+    ) -> io::Result<Monitor> {
+        execute!(stdout(), EnterAlternateScreen, Hide)?;
+        let terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+
+        Ok(Monitor {
+            terminal,
+            filter_bank: MelFilterBank::with_settings(
+                sample_rate,
+                fft_size,
+                MelFilterBankSettings {
+                    bands: SPECTROGRAM_BANDS,
+                    ..Default::default()
+                },
+            ),
+            spectrum: vec![0.0; SPECTROGRAM_BANDS],
+            history: VecDeque::with_capacity(HISTORY),
+            peak: f32::EPSILON,
+            meters: [
+                ("Drum", Color::Red, DynamicDecay::init(METER_DECAY)),
+                ("Hihat", Color::White, DynamicDecay::init(METER_DECAY)),
+                ("Note", Color::Blue, DynamicDecay::init(METER_DECAY)),
+                ("Full", Color::Cyan, DynamicDecay::init(METER_DECAY)),
+            ],
+            thresholds,
+            last_draw: Instant::now(),
+        })
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let Monitor {
+            terminal,
+            history,
+            peak,
+            meters,
+            thresholds,
+            ..
+        } = self;
+
+        terminal.draw(|frame| {
+            let [spectrogram, bottom] =
+                Layout::vertical([Constraint::Min(5), Constraint::Length(6)]).areas(frame.area());
+            let [onsets, settings] =
+                Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .areas(bottom);
+
+            let block = Block::bordered().title("Spectrum");
+            let inner = block.inner(spectrogram);
+            frame.render_widget(block, spectrogram);
+            frame.render_widget(
+                Spectrogram {
+                    history,
+                    peak: *peak,
+                },
+                inner,
+            );
+
+            let block = Block::bordered().title("Onsets");
+            let inner = block.inner(onsets);
+            frame.render_widget(block, onsets);
+            let rows = Layout::vertical([Constraint::Length(1); 4]).split(inner);
+            for ((name, color, envelope), row) in meters.iter().zip(rows.iter()) {
+                let value = envelope.get_value().clamp(0.0, 1.0);
+                let gauge = Gauge::default()
+                    .gauge_style(Style::default().fg(*color))
+                    .ratio(value as f64)
+                    .label(format!("{name} {value:.2}"));
+                frame.render_widget(gauge, *row);
+            }
+
+            let lines: Vec<Line> = match thresholds {
+                Some(thresholds) => [
+                    ("Drum", thresholds.drum),
+                    ("Hihat", thresholds.hihat),
+                    ("Note", thresholds.note),
+                    ("Full", thresholds.full),
+                ]
+                .iter()
+                .map(|(name, settings)| {
+                    Line::from(format!(
+                        "{name:<6} fixed {:>6.2}  dynamic {:>5.2}",
+                        settings.fixed_threshold, settings.dynamic_threshold
+                    ))
+                })
+                .collect(),
+                None => vec![Line::from("Not available for this detector")],
+            };
+            frame.render_widget(
+                Paragraph::new(lines).block(Block::bordered().title("Thresholds")),
+                settings,
+            );
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), LeaveAlternateScreen, Show);
+    }
+}
+
+impl LightService for Monitor {
+    fn process_onset(&mut self, event: Onset) {
+        let (index, strength) = match event {
+            Onset::Drum(strength) => (0, strength),
+            Onset::Hihat(strength) => (1, strength),
+            Onset::Note(strength, _) => (2, strength),
+            Onset::Full(strength) => (3, strength),
+            _ => return,
+        };
+        self.meters[index].2.trigger(strength);
+    }
+
+    fn process_spectrum(&mut self, freq_bins: &[f32]) {
+        self.filter_bank.filter(freq_bins, &mut self.spectrum);
+        let column: Vec<f32> = self.spectrum.iter().map(|x| x.ln_1p()).collect();
+        let max = column.iter().fold(0.0_f32, |a, &b| a.max(b));
+        self.peak = (self.peak * PEAK_DECAY).max(max).max(f32::EPSILON);
+
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(column);
+    }
+
+    fn update(&mut self) {
+        if self.last_draw.elapsed() < FRAME_INTERVAL {
+            return;
+        }
+        self.last_draw = Instant::now();
+        if let Err(e) = self.draw() {
+            warn!("Drawing the monitor failed: {e}");
+        }
+    }
+}
+
+/// Newest frame on the right, low frequencies at the bottom
+struct Spectrogram<'a> {
+    history: &'a VecDeque<Vec<f32>>,
+    peak: f32,
+}
+
+impl Widget for Spectrogram<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let columns = self.history.iter().rev().take(area.width as usize);
+        for (i, column) in columns.enumerate() {
+            let x = area.right() - 1 - i as u16;
+            for row in 0..area.height {
+                let band = (area.height - 1 - row) as usize * column.len() / area.height as usize;
+                let value = (column[band] / self.peak).clamp(0.0, 1.0);
+                if let Some(cell) = buf.cell_mut((x, area.top() + row)) {
+                    cell.set_char(' ').set_bg(heat(value));
+                }
+            }
+        }
+    }
+}
+
+/// Black over red to yellow
+fn heat(value: f32) -> Color {
+    let red = (value * 2.0).min(1.0);
+    let green = (value * 2.0 - 1.0).max(0.0);
+    Color::Rgb((red * 255.0) as u8, (green * 255.0) as u8, 0)
+}
@@ -0,0 +1,412 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use ciborium::{from_reader, into_writer};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, select};
+use tracing::{debug, info, warn};
+
+use super::{
+    wled::{SpectrumSettings, SpectrumState},
+    LightService, Onset, Pollable, PollingHelper, Reactions, WriteStatus,
+};
+
+static CONFIG_PATH: &str = "nanoleaf.cbor";
+
+const API_PORT: u16 = 16021;
+const STREAMING_PORT: u16 = 60222;
+/// Shape type of the Shapes controller, it has no LEDs of its own
+const CONTROLLER_SHAPE: u16 = 12;
+
+#[derive(Debug)]
+pub enum NanoleafError {
+    Http(reqwest::Error),
+    Socket(std::io::Error),
+    SaveTokenError(std::io::Error),
+    TimeOut,
+    NoPanels,
+}
+
+impl std::error::Error for NanoleafError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NanoleafError::Http(e) => Some(e),
+            NanoleafError::Socket(e) => Some(e),
+            NanoleafError::SaveTokenError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Display for NanoleafError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Http(_) => write!(f, "Nanoleaf controller is not reachable"),
+            Self::Socket(_) => write!(f, "Binding socket failed"),
+            Self::SaveTokenError(_) => write!(f, "Error saving Nanoleaf auth token to file"),
+            Self::TimeOut => write!(f, "Timed out while pairing with Nanoleaf controller"),
+            Self::NoPanels => write!(f, "Nanoleaf controller has no panels"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for NanoleafError {
+    fn from(err: reqwest::Error) -> Self {
+        NanoleafError::Http(err)
+    }
+}
+
+impl From<std::io::Error> for NanoleafError {
+    fn from(err: std::io::Error) -> Self {
+        NanoleafError::Socket(err)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct NanoleafToken {
+    ip: String,
+    auth_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct NanoleafSettings {
+    #[serde(rename = "ip")]
+    pub ip: String,
+    #[serde(rename = "auth_file")]
+    pub auth_file: Option<String>,
+    #[serde(rename = "audio_device")]
+    pub audio_device: Option<String>,
+    /// Spectrum effect settings, one panel takes the place of one LED
+    #[serde(flatten)]
+    pub spectrum: SpectrumSettings,
+    pub pairing_timeout: Duration,
+}
+
+impl Default for NanoleafSettings {
+    fn default() -> Self {
+        Self {
+            ip: String::new(),
+            auth_file: None,
+            audio_device: None,
+            spectrum: SpectrumSettings {
+                leds_per_second: 10.0,
+                polling_rate: 20.0,
+                ..Default::default()
+            },
+            pairing_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Layout {
+    position_data: Vec<Panel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Panel {
+    panel_id: u16,
+    x: i32,
+    y: i32,
+    shape_type: u16,
+}
+
+pub struct Nanoleaf {
+    polling_helper: PollingHelper,
+    state: Arc<Mutex<NanoleafState>>,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+}
+
+struct NanoleafState {
+    spectrum: SpectrumState,
+    panels: Vec<u16>,
+    buffer: BytesMut,
+}
+
+impl Nanoleaf {
+    pub async fn connect(ip: &str, sampling_rate: f32) -> Result<Nanoleaf, NanoleafError> {
+        let settings = NanoleafSettings {
+            ip: ip.to_owned(),
+            ..Default::default()
+        };
+        Self::connect_with_settings(settings, sampling_rate).await
+    }
+
+    pub async fn connect_with_settings(
+        settings: NanoleafSettings,
+        sampling_rate: f32,
+    ) -> Result<Nanoleaf, NanoleafError> {
+        #[derive(Debug, Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Write {
+            command: &'static str,
+            anim_type: &'static str,
+            ext_control_version: &'static str,
+        }
+
+        #[derive(Debug, Serialize)]
+        struct Effect {
+            write: Write,
+        }
+
+        let spectrum = settings.spectrum;
+        let client = Client::builder()
+            .timeout(Duration::from_secs(spectrum.timeout as u64))
+            .build()?;
+
+        let auth_file = settings.auth_file.as_deref().unwrap_or(CONFIG_PATH);
+        let mut tokens = Self::load_tokens(auth_file);
+        let auth_token = match tokens.iter().find(|t| t.ip == settings.ip) {
+            Some(token) => token.auth_token.clone(),
+            None => {
+                let auth_token =
+                    Self::pair(&client, &settings.ip, settings.pairing_timeout).await?;
+                tokens.push(NanoleafToken {
+                    ip: settings.ip.clone(),
+                    auth_token: auth_token.clone(),
+                });
+                Self::save_tokens(&tokens, auth_file)?;
+                auth_token
+            }
+        };
+
+        let url = format!("http://{}:{API_PORT}/api/v1/{auth_token}", settings.ip);
+
+        let layout: Layout = client
+            .get(format!("{url}/panelLayout/layout"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut panels: Vec<Panel> = layout
+            .position_data
+            .into_iter()
+            .filter(|p| p.shape_type != CONTROLLER_SHAPE)
+            .collect();
+        if panels.is_empty() {
+            return Err(NanoleafError::NoPanels);
+        }
+        // Left to right, so the spectrum runs across the panels like on a strip
+        panels.sort_by_key(|p| (p.x, p.y));
+        let panels: Vec<u16> = panels.iter().map(|p| p.panel_id).collect();
+
+        client
+            .put(format!("{url}/effects"))
+            .json(&Effect {
+                write: Write {
+                    command: "display",
+                    anim_type: "extControl",
+                    ext_control_version: "v2",
+                },
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket
+            .connect((settings.ip.as_str(), STREAMING_PORT))
+            .await?;
+        debug!("Bound: {}", socket.local_addr().unwrap());
+
+        info!(
+            "Connected to Nanoleaf at {} with {} panels",
+            settings.ip,
+            panels.len()
+        );
+
+        let state = NanoleafState {
+            spectrum: SpectrumState::init(sampling_rate, panels.len() as u16, 0, false, &spectrum),
+            buffer: BytesMut::with_capacity(2 + panels.len() * 8),
+            panels,
+        };
+        let state = Arc::new(Mutex::new(state));
+
+        let polling_helper = PollingHelper::init(socket, state.clone(), spectrum.polling_rate);
+
+        Ok(Nanoleaf {
+            polling_helper,
+            state,
+            reactions: spectrum.reactions,
+            reaction_override: None,
+        })
+    }
+
+    async fn pair(client: &Client, ip: &str, timeout: Duration) -> Result<String, NanoleafError> {
+        #[derive(Debug, Deserialize)]
+        struct Token {
+            auth_token: String,
+        }
+
+        warn!("Please hold the power button of the Nanoleaf controller for 5-7 seconds");
+
+        select! {
+            token = async {
+                loop {
+                    let response = client
+                        .post(format!("http://{ip}:{API_PORT}/api/v1/new"))
+                        .send()
+                        .await?;
+
+                    // Controller answers with 403 until it is in pairing mode
+                    if response.status().is_success() {
+                        return Ok::<_, reqwest::Error>(response.json::<Token>().await?.auth_token);
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            } => {
+                info!("Paired with Nanoleaf at {ip}");
+                Ok(token?)
+            }
+            _ = tokio::time::sleep(timeout) => {
+                Err(NanoleafError::TimeOut)
+            }
+        }
+    }
+
+    fn load_tokens(path: &str) -> Vec<NanoleafToken> {
+        match File::open(path) {
+            Ok(file) => from_reader(file).unwrap_or_default(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn save_tokens(tokens: &[NanoleafToken], path: &str) -> Result<(), NanoleafError> {
+        let f = File::create(path).map_err(NanoleafError::SaveTokenError)?;
+        into_writer(&tokens, f).map_err(|e| match e {
+            ciborium::ser::Error::Io(e) => NanoleafError::SaveTokenError(e),
+            ciborium::ser::Error::Value(_) => {
+                panic!("Serialization failed, this should be impossible, please report")
+            }
+        })?;
+        info!("Saved Nanoleaf auth token to {path}");
+        Ok(())
+    }
+}
+
+impl NanoleafState {
+    fn trigger(&mut self, event: Onset) {
+        if let Onset::Full(strength) = event {
+            self.spectrum.trigger(strength)
+        }
+    }
+}
+
+impl Pollable for NanoleafState {
+    fn poll(&self) -> Bytes {
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+
+        // External control v2: panel count followed by id, RGBW and transition time per panel
+        bytes.put_u16(self.panels.len() as u16);
+        for (id, color) in self.panels.iter().zip(self.spectrum.led_colors()) {
+            bytes.put_u16(*id);
+            bytes.put_slice(&color);
+            bytes.put_u8(0);
+            bytes.put_u16(1); // In multiples of 100ms
+        }
+
+        bytes.into()
+    }
+
+    fn blackout(&self) -> Option<Bytes> {
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+        bytes.put_u16(self.panels.len() as u16);
+        for id in &self.panels {
+            bytes.put_u16(*id);
+            bytes.put_bytes(0, 4);
+            bytes.put_u16(1);
+        }
+        Some(bytes.into())
+    }
+}
+
+impl LightService for Nanoleaf {
+    fn process_samples(&mut self, samples: &[f32]) {
+        let mut state = self.state.lock().unwrap();
+        state.spectrum.visualize_spectrum(samples);
+    }
+
+    fn process_onset(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
+        self.state.lock().unwrap().trigger(event);
+    }
+
+    fn shutdown(&mut self) {
+        self.polling_helper.shutdown();
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.polling_helper.last_write())
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
+
+    fn dim(&mut self, gain: f32) {
+        self.state.lock().unwrap().spectrum.dim(gain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lights::DETECTED_ONSETS;
+
+    /// Sum of all color channels after showing a 1 kHz tone
+    fn lit(onset: Option<Onset>) -> u32 {
+        let settings = NanoleafSettings::default();
+        let mut state = NanoleafState {
+            spectrum: SpectrumState::init(48000.0, 4, 0, false, &settings.spectrum),
+            panels: vec![1, 2, 3, 4],
+            buffer: BytesMut::new(),
+        };
+        if let Some(onset) = onset {
+            state.trigger(onset);
+        }
+        let tone: Vec<f32> = (0..48000)
+            .map(|i| 0.1 * (i as f32 * 1000.0 * std::f32::consts::TAU / 48000.0).sin())
+            .collect();
+        state.spectrum.visualize_spectrum(&tone);
+        state
+            .spectrum
+            .led_colors()
+            .iter()
+            .flatten()
+            .map(|&c| c as u32)
+            .sum()
+    }
+
+    #[test]
+    fn fullband_onsets_brighten_the_panels() {
+        let idle = lit(None);
+        assert!(idle > 0);
+        for onset in DETECTED_ONSETS {
+            match onset {
+                Onset::Full(_) => assert!(lit(Some(onset)) > idle),
+                // The spectrum follows the samples, only fullband onsets flash it
+                _ => assert_eq!(lit(Some(onset)), idle),
+            }
+        }
+    }
+}
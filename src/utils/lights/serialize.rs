@@ -1,16 +1,38 @@
-use std::{collections::HashMap, fs::File};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    time::{Duration, Instant},
+};
 
-use ciborium::into_writer;
+use ciborium::{from_reader, into_writer};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
 
 use super::{LightService, Onset};
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeSource {
+    /// Count processed frames, exact when processing files
+    #[default]
+    FrameCount,
+    /// Milliseconds since creation, follows real time under load or buffer underruns
+    Monotonic,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct OnsetContainer {
     #[serde(skip_serializing, skip_deserializing)]
     filename: String,
     #[serde(skip_serializing, skip_deserializing)]
     time: u128,
+    #[serde(skip_serializing, skip_deserializing)]
+    start: Option<Instant>,
+    /// Onsets of the same type closer than this many milliseconds are merged
+    #[serde(skip_serializing, skip_deserializing)]
+    merge_window: u128,
     time_interval: u32,
     pub data: HashMap<String, Vec<(u128, Onset)>>,
     pub raw: Vec<f32>,
@@ -18,17 +40,30 @@ pub struct OnsetContainer {
 
 impl LightService for OnsetContainer {
     fn process_onset(&mut self, event: Onset) {
-        match event {
-            Onset::Full(_) => self.data.get_mut("Full").unwrap().push((self.time, event)),
-            Onset::Atmosphere(_, _) => self
-                .data
-                .get_mut("Atmosphere")
-                .unwrap()
-                .push((self.time, event)),
-            Onset::Note(_, _) => self.data.get_mut("Note").unwrap().push((self.time, event)),
-            Onset::Drum(_) => self.data.get_mut("Drum").unwrap().push((self.time, event)),
-            Onset::Hihat(_) => self.data.get_mut("Hihat").unwrap().push((self.time, event)),
-            Onset::Raw(value) => self.raw.push(value),
+        let time = self.timestamp();
+        let (key, strength) = match event {
+            Onset::Full(strength) => ("Full", strength),
+            Onset::Atmosphere(strength, _) => ("Atmosphere", strength),
+            Onset::Note(strength, _) => ("Note", strength),
+            Onset::Drum(strength) => ("Drum", strength),
+            Onset::Hihat(strength) => ("Hihat", strength),
+            Onset::Raw(value) => {
+                self.raw.push(value);
+                return;
+            }
+            Onset::Timbre(_) | Onset::Pitch(_) | Onset::Pan(_) => return,
+        };
+
+        let onsets = self.data.get_mut(key).unwrap();
+        match onsets.last_mut() {
+            // Keep only the strongest onset of a burst
+            Some((last_time, last)) if time - *last_time < self.merge_window => {
+                if strength > strength_of(last) {
+                    *last_time = time;
+                    *last = event;
+                }
+            }
+            _ => onsets.push((time, event)),
         }
     }
 
@@ -44,7 +79,44 @@ impl OnsetContainer {
         Ok(())
     }
 
-    pub fn init(filename: &str, sample_rate: usize, hop_size: usize) -> OnsetContainer {
+    /// Loads a saved container, it is not written back to disk when dropped
+    pub fn load(filename: &str) -> Result<OnsetContainer, Box<dyn Error>> {
+        let f = File::open(filename)?;
+        let mut container: OnsetContainer = from_reader(f)?;
+        for (label, onsets) in container.data.iter_mut() {
+            for (_, onset) in onsets.iter_mut() {
+                if let Some(restored) = restore(label, onset) {
+                    *onset = restored;
+                }
+            }
+        }
+        Ok(container)
+    }
+
+    /// Onsets of the same type closer than `merge_window` are merged into the strongest one,
+    /// a zero window keeps every onset
+    pub fn init(
+        filename: &str,
+        sample_rate: usize,
+        hop_size: usize,
+        merge_window: Duration,
+    ) -> OnsetContainer {
+        Self::with_time_source(
+            filename,
+            sample_rate,
+            hop_size,
+            TimeSource::FrameCount,
+            merge_window,
+        )
+    }
+
+    pub fn with_time_source(
+        filename: &str,
+        sample_rate: usize,
+        hop_size: usize,
+        time_source: TimeSource,
+        merge_window: Duration,
+    ) -> OnsetContainer {
         let data: HashMap<String, Vec<(u128, Onset)>> = HashMap::from([
             ("Full".to_string(), Vec::new()),
             ("Atmosphere".to_string(), Vec::new()),
@@ -56,18 +128,234 @@ impl OnsetContainer {
         OnsetContainer {
             filename: filename.to_string(),
             time: 0,
+            start: match time_source {
+                TimeSource::FrameCount => None,
+                TimeSource::Monotonic => Some(Instant::now()),
+            },
+            merge_window: merge_window.as_millis(),
             time_interval: ((hop_size as f64 / sample_rate as f64) * 1000.0) as u32,
             data,
             raw,
         }
     }
+
+    /// Writes one `time_ms,label,strength` row per onset ordered by time, e.g. for `mir_eval`
+    pub fn export_csv(&self, path: &str) -> std::io::Result<()> {
+        let mut f = BufWriter::new(File::create(path)?);
+        writeln!(f, "time_ms,label,strength")?;
+        for (time, label, onset) in self.sorted_onsets() {
+            writeln!(f, "{time},{label},{}", strength_of(onset))?;
+        }
+        f.flush()
+    }
+
+    /// Writes a JAMS file with one `onset` annotation per band, times are in seconds
+    pub fn export_jams(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let onsets = self.sorted_onsets();
+        let duration = onsets
+            .last()
+            .map_or(0.0, |(time, _, _)| *time as f64 / 1000.0);
+
+        let mut labels: Vec<&String> = self.data.keys().collect();
+        labels.sort();
+        let annotations: Vec<_> = labels
+            .into_iter()
+            .map(|label| {
+                let data: Vec<_> = onsets
+                    .iter()
+                    .filter(|(_, l, _)| l == label)
+                    .map(|(time, _, onset)| {
+                        json!({
+                            "time": *time as f64 / 1000.0,
+                            "duration": 0.0,
+                            "value": label,
+                            "confidence": strength_of(onset),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "namespace": "onset",
+                    "annotation_metadata": {
+                        "annotation_tools": "MusicSync",
+                        "data_source": "program",
+                    },
+                    "sandbox": { "band": label },
+                    "time": 0.0,
+                    "duration": duration,
+                    "data": data,
+                })
+            })
+            .collect();
+
+        let jams = json!({
+            "file_metadata": {
+                "duration": duration,
+                "jams_version": "0.3.4",
+            },
+            "annotations": annotations,
+            "sandbox": {},
+        });
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &jams)?;
+        Ok(())
+    }
+
+    /// All onsets with their time and band ordered by time
+    fn sorted_onsets(&self) -> Vec<(u128, &str, &Onset)> {
+        let mut onsets: Vec<(u128, &str, &Onset)> = self
+            .data
+            .iter()
+            .flat_map(|(label, onsets)| {
+                onsets
+                    .iter()
+                    .map(move |(time, onset)| (*time, label.as_str(), onset))
+            })
+            .collect();
+        onsets.sort_by_key(|(time, label, _)| (*time, *label));
+        onsets
+    }
+
+    /// Milliseconds between two frames
+    pub fn time_interval(&self) -> u32 {
+        self.time_interval
+    }
+
+    /// Current time in milliseconds according to the selected time source
+    fn timestamp(&self) -> u128 {
+        match self.start {
+            Some(start) => start.elapsed().as_millis(),
+            None => self.time,
+        }
+    }
+}
+
+fn strength_of(onset: &Onset) -> f32 {
+    match *onset {
+        Onset::Full(strength)
+        | Onset::Atmosphere(strength, _)
+        | Onset::Note(strength, _)
+        | Onset::Drum(strength)
+        | Onset::Hihat(strength)
+        | Onset::Raw(strength) => strength,
+        Onset::Timbre(_) | Onset::Pitch(_) | Onset::Pan(_) => 0.0,
+    }
+}
+
+/// Onsets are stored untagged, so e.g. a drum is read back as a fullband onset.
+/// The band is only known from the key the onsets are stored under.
+fn restore(label: &str, onset: &Onset) -> Option<Onset> {
+    let strength = strength_of(onset);
+    let value = match *onset {
+        Onset::Atmosphere(_, value) | Onset::Note(_, value) => value,
+        _ => 0,
+    };
+    Some(match label {
+        "Full" => Onset::Full(strength),
+        "Atmosphere" => Onset::Atmosphere(strength, value),
+        "Note" => Onset::Note(strength, value),
+        "Drum" => Onset::Drum(strength),
+        "Hihat" => Onset::Hihat(strength),
+        _ => return None,
+    })
 }
 
 impl Drop for OnsetContainer {
     fn drop(&mut self) {
+        if self.filename.is_empty() {
+            return;
+        }
         match self.save() {
             Ok(_) => println!("Saved to {}", self.filename),
             Err(e) => println!("Error saving to {}: {}", self.filename, e),
         }
     }
 }
+
+/// Feeds a serialized onset stream to the light services at the pacing it was recorded with
+pub async fn replay(
+    path: &str,
+    lightservices: &mut [Box<dyn LightService + Send>],
+) -> Result<(), Box<dyn Error>> {
+    let container = OnsetContainer::load(path)?;
+    let time_interval = container.time_interval.max(1) as u128;
+
+    let mut onsets: Vec<(u128, Onset)> = container.data.values().flatten().copied().collect();
+    onsets.sort_by_key(|(time, _)| *time);
+
+    let frames = onsets
+        .last()
+        .map_or(0, |(time, _)| (time / time_interval + 1) as usize)
+        .max(container.raw.len());
+    info!("Replaying {frames} frames from {path}");
+
+    let mut interval = tokio::time::interval(Duration::from_millis(time_interval as u64));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let mut remaining = onsets.as_slice();
+    let mut frame_onsets = Vec::new();
+    for frame in 0..frames {
+        interval.tick().await;
+
+        frame_onsets.clear();
+        if let Some(raw) = container.raw.get(frame) {
+            frame_onsets.push(Onset::Raw(*raw));
+        }
+        let frame_end = (frame as u128 + 1) * time_interval;
+        let count = remaining
+            .iter()
+            .take_while(|(time, _)| *time < frame_end)
+            .count();
+        frame_onsets.extend(remaining[..count].iter().map(|(_, onset)| *onset));
+        remaining = &remaining[count..];
+
+        lightservices.process_onsets(&frame_onsets);
+        lightservices.update();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct Recorder(Arc<Mutex<Vec<Onset>>>);
+
+    impl LightService for Recorder {
+        fn process_onset(&mut self, event: Onset) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_keeps_the_onset_bands() {
+        let path = std::env::temp_dir().join("music_sync_replay_round_trip.cbor");
+        let path = path.to_str().unwrap();
+        {
+            let mut container = OnsetContainer::init(path, 48000, 480, Duration::ZERO);
+            container.process_onsets(&[Onset::Drum(0.5), Onset::Hihat(0.25)]);
+            container.update();
+            container.process_onsets(&[Onset::Note(0.75, 3), Onset::Full(1.0)]);
+            container.update();
+        }
+
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mut services: Vec<Box<dyn LightService + Send>> =
+            vec![Box::new(Recorder(recorded.clone()))];
+        replay(path, &mut services).await.unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut replayed: Vec<String> = recorded
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|onset| format!("{onset:?}"))
+            .collect();
+        replayed.sort();
+        assert_eq!(
+            replayed,
+            ["Drum(0.5)", "Full(1.0)", "Hihat(0.25)", "Note(0.75, 3)"]
+        );
+    }
+}
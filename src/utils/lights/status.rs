@@ -0,0 +1,299 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, info};
+
+use super::{LightService, Onset, WriteStatus};
+
+/// Onsets of the last seconds the onset rates are averaged over
+const ONSET_RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Audio stream without samples for longer than this is reported as stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum StatusError {
+    Socket(std::io::Error),
+}
+
+impl std::error::Error for StatusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StatusError::Socket(e) => Some(e),
+        }
+    }
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(_) => write!(f, "Binding status server failed"),
+        }
+    }
+}
+
+impl From<std::io::Error> for StatusError {
+    fn from(err: std::io::Error) -> Self {
+        StatusError::Socket(err)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct StatusSettings {
+    pub address: String,
+}
+
+impl Default for StatusSettings {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8081".to_owned(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DeviceStatus {
+    name: String,
+    rms: f32,
+    peak: f32,
+    last_samples: Option<Instant>,
+    /// Time and band of the onsets in the `ONSET_RATE_WINDOW`
+    onsets: VecDeque<(Instant, &'static str)>,
+}
+
+impl DeviceStatus {
+    fn to_json(&self) -> Value {
+        let stream = match self.last_samples {
+            None => "waiting",
+            Some(time) if time.elapsed() > STALL_TIMEOUT => "stalled",
+            Some(_) => "running",
+        };
+        let rate = |band: &str| {
+            let count = self
+                .onsets
+                .iter()
+                .filter(|(time, b)| *b == band && time.elapsed() <= ONSET_RATE_WINDOW)
+                .count();
+            count as f32 / ONSET_RATE_WINDOW.as_secs_f32()
+        };
+        json!({
+            "name": self.name,
+            "stream": stream,
+            "seconds_since_audio": self.last_samples.map(|time| time.elapsed().as_secs_f32()),
+            "rms": self.rms,
+            "peak": self.peak,
+            "onsets_per_second": {
+                "drum": rate("drum"),
+                "hihat": rate("hihat"),
+                "note": rate("note"),
+                "full": rate("full"),
+            },
+        })
+    }
+}
+
+struct Light {
+    name: String,
+    device: usize,
+    last_write: Option<WriteStatus>,
+}
+
+impl Light {
+    fn to_json(&self, devices: &[DeviceStatus]) -> Value {
+        let last_write = self
+            .last_write
+            .as_ref()
+            .and_then(|status| status.lock().unwrap().clone())
+            .map(|result| match result {
+                Ok(()) => json!({ "ok": true }),
+                Err(e) => json!({ "ok": false, "error": e }),
+            });
+        json!({
+            "name": self.name,
+            "audio_device": devices[self.device].name,
+            "last_write": last_write,
+        })
+    }
+}
+
+#[derive(Default)]
+struct State {
+    devices: Vec<DeviceStatus>,
+    lights: Vec<Light>,
+}
+
+/// Serves the audio levels, onset rates and connected lights as JSON at `/status`.
+/// Every audio device reports to it through its own [`StatusReporter`].
+pub struct StatusServer {
+    state: Arc<Mutex<State>>,
+    task: Arc<ServerTask>,
+}
+
+impl StatusServer {
+    pub async fn bind(
+        settings: &StatusSettings,
+        devices: &[String],
+    ) -> Result<StatusServer, StatusError> {
+        let listener = TcpListener::bind(&settings.address).await?;
+        let state = Arc::new(Mutex::new(State {
+            devices: devices
+                .iter()
+                .map(|name| DeviceStatus {
+                    name: name.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            lights: Vec::new(),
+        }));
+
+        let clients = state.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, client)) = listener.accept().await {
+                debug!("Status request from {client}");
+                tokio::spawn(Self::serve(stream, clients.clone()));
+            }
+        });
+
+        info!(
+            "Status server listening on http://{}/status",
+            settings.address
+        );
+
+        Ok(StatusServer {
+            state,
+            task: Arc::new(ServerTask(handle)),
+        })
+    }
+
+    /// Lists a connected light in the status, with the result of its last write if it has a poller
+    pub fn register(&self, name: &str, device: usize, last_write: Option<WriteStatus>) {
+        self.state.lock().unwrap().lights.push(Light {
+            name: name.to_owned(),
+            device,
+            last_write,
+        });
+    }
+
+    /// Light service that reports the audio of `device`, keeps the server running while it exists
+    pub fn reporter(&self, device: usize) -> StatusReporter {
+        StatusReporter {
+            state: self.state.clone(),
+            device,
+            _task: self.task.clone(),
+        }
+    }
+
+    async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+        let mut reader = BufReader::new(stream);
+        let request = timeout(REQUEST_TIMEOUT, async {
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).await?;
+            // The headers aren't needed, but closing with unread data would reset the connection
+            let mut header = String::new();
+            while reader.read_line(&mut header).await? > 2 {
+                header.clear();
+            }
+            Ok::<_, std::io::Error>(request_line)
+        })
+        .await;
+        let request_line = match request {
+            Ok(Ok(line)) => line,
+            Ok(Err(e)) => {
+                debug!("Reading status request failed: {e}");
+                return;
+            }
+            Err(_) => return,
+        };
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/status")) => {
+                let state = state.lock().unwrap();
+                let body = json!({
+                    "audio_devices": state.devices.iter().map(DeviceStatus::to_json).collect::<Vec<_>>(),
+                    "lights": state
+                        .lights
+                        .iter()
+                        .map(|light| light.to_json(&state.devices))
+                        .collect::<Vec<_>>(),
+                });
+                ("200 OK", body)
+            }
+            _ => (
+                "404 Not Found",
+                json!({ "error": "Only GET /status exists" }),
+            ),
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        if let Err(e) = reader.get_mut().write_all(response.as_bytes()).await {
+            debug!("Sending status failed: {e}");
+        }
+    }
+}
+
+struct ServerTask(JoinHandle<()>);
+
+impl Drop for ServerTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Measures the audio and onsets of one device for the [`StatusServer`]
+pub struct StatusReporter {
+    state: Arc<Mutex<State>>,
+    device: usize,
+    _task: Arc<ServerTask>,
+}
+
+impl LightService for StatusReporter {
+    fn process_onset(&mut self, event: Onset) {
+        let band = match event {
+            Onset::Drum(_) => "drum",
+            Onset::Hihat(_) => "hihat",
+            Onset::Note(_, _) => "note",
+            Onset::Full(_) => "full",
+            _ => return,
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let device = &mut state.devices[self.device];
+        device.onsets.push_back((now, band));
+        while device
+            .onsets
+            .front()
+            .is_some_and(|(time, _)| now - *time > ONSET_RATE_WINDOW)
+        {
+            device.onsets.pop_front();
+        }
+    }
+
+    fn process_samples(&mut self, samples: &[f32]) {
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+        let peak = samples.iter().fold(0.0_f32, |max, s| max.max(s.abs()));
+        let mut state = self.state.lock().unwrap();
+        let device = &mut state.devices[self.device];
+        device.rms = rms;
+        device.peak = peak;
+        device.last_samples = Some(Instant::now());
+    }
+}
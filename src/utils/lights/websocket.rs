@@ -0,0 +1,199 @@
+use std::fmt::{self, Display, Formatter};
+
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError, Sender},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use super::{event_sink::onset_to_json, LightService, Onset};
+use crate::utils::audioprocessing::{MelFilterBank, MelFilterBankSettings};
+
+/// Frames a slow client may fall behind before it starts missing frames
+const CLIENT_BUFFER: usize = 64;
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Socket(std::io::Error),
+}
+
+impl std::error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebSocketError::Socket(e) => Some(e),
+        }
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Socket(_) => write!(f, "Binding WebSocket server failed"),
+        }
+    }
+}
+
+impl From<std::io::Error> for WebSocketError {
+    fn from(err: std::io::Error) -> Self {
+        WebSocketError::Socket(err)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default)]
+pub struct WebSocketSettings {
+    pub address: String,
+    /// Sends the mel spectrum with every frame, this needs a lot more bandwidth
+    pub include_spectrum: bool,
+    pub spectrum_bands: usize,
+    pub audio_device: Option<String>,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8080".to_owned(),
+            include_spectrum: false,
+            spectrum_bands: 64,
+            audio_device: None,
+        }
+    }
+}
+
+/// Broadcasts the onsets and optionally the mel spectrum of every frame as JSON
+pub struct WebSocketServer {
+    tx: Sender<String>,
+    handle: JoinHandle<()>,
+    filter_bank: Option<MelFilterBank>,
+    spectrum: Vec<f32>,
+    onsets: Vec<Onset>,
+}
+
+impl WebSocketServer {
+    pub async fn bind(
+        settings: &WebSocketSettings,
+        sample_rate: u32,
+        fft_size: u32,
+    ) -> Result<WebSocketServer, WebSocketError> {
+        let (tx, _) = broadcast::channel(CLIENT_BUFFER);
+        let listener = TcpListener::bind(&settings.address).await?;
+
+        let clients = tx.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, client)) = listener.accept().await {
+                debug!("WebSocket client {client} connected");
+                tokio::spawn(Self::serve(stream, clients.subscribe()));
+            }
+        });
+
+        let filter_bank = settings.include_spectrum.then(|| {
+            MelFilterBank::with_settings(
+                sample_rate,
+                fft_size,
+                MelFilterBankSettings {
+                    bands: settings.spectrum_bands,
+                    ..Default::default()
+                },
+            )
+        });
+
+        info!("WebSocket server listening on {}", settings.address);
+
+        Ok(WebSocketServer {
+            tx,
+            handle,
+            filter_bank,
+            spectrum: vec![0.0; settings.spectrum_bands],
+            onsets: Vec::new(),
+        })
+    }
+
+    async fn serve(stream: TcpStream, mut rx: broadcast::Receiver<String>) {
+        let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                debug!("WebSocket handshake failed: {e}");
+                return;
+            }
+        };
+
+        loop {
+            match rx.recv().await {
+                Ok(frame) => {
+                    if let Err(e) = websocket.send(Message::text(frame)).await {
+                        debug!("WebSocket client disconnected: {e}");
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client is too slow, skipped {skipped} frames");
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+impl Drop for WebSocketServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl LightService for WebSocketServer {
+    fn process_onset(&mut self, event: Onset) {
+        self.onsets.push(event);
+    }
+
+    fn process_spectrum(&mut self, freq_bins: &[f32]) {
+        if let Some(filter_bank) = &self.filter_bank {
+            filter_bank.filter(freq_bins, &mut self.spectrum);
+        }
+    }
+
+    /// Sends the onsets collected since the last frame
+    fn update(&mut self) {
+        if self.tx.receiver_count() > 0 {
+            let onsets: Vec<_> = self.onsets.iter().map(onset_to_json).collect();
+            let frame = if self.filter_bank.is_some() {
+                json!({ "onsets": onsets, "spectrum": self.spectrum })
+            } else {
+                json!({ "onsets": onsets })
+            };
+            let _ = self.tx.send(frame.to_string());
+        }
+        self.onsets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn broadcasts_all_onsets_of_a_frame() {
+        let settings = WebSocketSettings {
+            address: "127.0.0.1:0".to_owned(),
+            ..Default::default()
+        };
+        let server = WebSocketServer::bind(&settings, 48000, 2048).await.unwrap();
+        let mut rx = server.tx.subscribe();
+        let mut services: Vec<Box<dyn LightService + Send>> = vec![Box::new(server)];
+
+        services.process_onsets(&[Onset::Drum(0.5), Onset::Hihat(0.25)]);
+        services.update();
+        let frame: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(frame["onsets"].as_array().unwrap().len(), 2);
+
+        services.update();
+        let frame: Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert!(frame["onsets"].as_array().unwrap().is_empty());
+    }
+}
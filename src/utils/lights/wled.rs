@@ -3,21 +3,33 @@ use std::{
     fmt::Display,
     io,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use biquad::{Biquad, Coefficients, DirectForm2Transposed, ToHertz, Type, Q_BUTTERWORTH_F32};
 use bytes::{BufMut, Bytes, BytesMut};
-use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
 
 use super::{
-    color::{color_downsample, color_upsample, hsv_to_rgb, rgb_to_hsv},
-    envelope::{DynamicDecay, Envelope, FixedDecay},
-    LightService, Onset, Pollable, PollingHelper,
+    color::{
+        color_downsample, color_downsample_dithered, color_to_hue, color_upsample, hsv_to_rgb,
+        rgb_to_hsv, Palette,
+    },
+    connect_udp,
+    envelope::{AnimationHelper, DynamicDecay, Envelope, FixedDecay, Idle},
+    url_host, Closeable, LightService, Onset, Pollable, PollingHelper, Reactions, Route, Routing,
+    Stream, WriteStatus, Writeable,
 };
 
+/// Most LEDs a single DRGB packet can hold
+const DRGB_MAX_LEDS: u16 = 490;
+/// Most LEDs a single DRGBW packet can hold
+const DRGBW_MAX_LEDS: u16 = 367;
+/// Most LEDs a single DNRGB packet can hold
+const DNRGB_MAX_LEDS: u16 = 489;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct LEDStrip {
@@ -33,6 +45,7 @@ pub struct LEDStrip {
 pub enum WLEDError {
     Http(reqwest::Error),
     Socket(io::Error),
+    UnknownSegment(String),
 }
 
 impl From<reqwest::Error> for WLEDError {
@@ -52,6 +65,7 @@ impl std::error::Error for WLEDError {
         match self {
             WLEDError::Http(e) => Some(e),
             WLEDError::Socket(e) => Some(e),
+            WLEDError::UnknownSegment(_) => None,
         }
     }
 }
@@ -61,6 +75,9 @@ impl Display for WLEDError {
         match self {
             WLEDError::Http(_) => write!(f, "LED strip is not reachable"),
             WLEDError::Socket(_) => write!(f, "Binding socket failed"),
+            WLEDError::UnknownSegment(segment) => {
+                write!(f, "Segment {segment} not found on LED strip")
+            }
         }
     }
 }
@@ -71,13 +88,238 @@ pub struct LEDStripOnset {
     strip: LEDStrip,
     polling_helper: PollingHelper,
     state: Arc<Mutex<OnsetState>>,
+    channel: Option<usize>,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+    routing: Routing,
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Deserialize)]
 struct Segment {
+    id: usize,
     start: usize,
     stop: usize,
+    #[serde(default, rename = "n")]
+    name: Option<String>,
+}
+
+/// Segment of the strip the effect is drawn on, either by id or by name
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(untagged)]
+pub enum SegmentSelector {
+    Id(usize),
+    Name(String),
+}
+
+impl SegmentSelector {
+    fn matches(&self, segment: &Segment) -> bool {
+        match self {
+            SegmentSelector::Id(id) => segment.id == *id,
+            SegmentSelector::Name(name) => segment.name.as_deref() == Some(name.as_str()),
+        }
+    }
+}
+
+impl Display for SegmentSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegmentSelector::Id(id) => write!(f, "{id}"),
+            SegmentSelector::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
+
+/// LEDs the effect is drawn on, the whole strip if no segment is selected
+async fn driven_segment(
+    client: &reqwest::Client,
+    ip: &str,
+    led_count: u16,
+    selector: Option<&SegmentSelector>,
+) -> Result<Segment, WLEDError> {
+    #[derive(Debug, Deserialize)]
+    struct State {
+        seg: Vec<Segment>,
+    }
+
+    let Some(selector) = selector else {
+        return Ok(Segment {
+            id: 0,
+            start: 0,
+            stop: led_count as usize,
+            name: None,
+        });
+    };
+
+    let url = format!("http://{}/json/state", url_host(ip));
+    let state: State = client.get(&url).send().await?.json().await?;
+    let segment = state
+        .seg
+        .into_iter()
+        .find(|segment| selector.matches(segment))
+        .ok_or_else(|| WLEDError::UnknownSegment(selector.to_string()))?;
+    info!(
+        "Using segment {selector} with LEDs {} to {}",
+        segment.start, segment.stop
+    );
+    Ok(segment)
+}
+
+/// Falls back to the default crossovers if the configured ones can't split the spectrum into three bands
+fn crossovers(low: f32, high: f32, sampling_frequency: f32) -> (f32, f32) {
+    let nyquist = sampling_frequency / 2.0;
+    if 0.0 < low && low < high && high < nyquist {
+        return (low, high);
+    }
+    let default = SpectrumSettings::default();
+    warn!(
+        "Crossovers {low}Hz and {high}Hz must be increasing and below {nyquist}Hz, \
+        using {}Hz and {}Hz instead",
+        default.low_end_crossover, default.high_end_crossover
+    );
+    (default.low_end_crossover, default.high_end_crossover)
+}
+
+/// Packets of the UDP realtime protocol.
+/// DNRGB is used to start at another LED than the first or if the LEDs don't fit into one packet,
+/// it has no white channel.
+#[derive(Debug)]
+struct Realtime {
+    rgbw: bool,
+    dnrgb: bool,
+    timeout: u8,
+    start: u16,
+    leds_per_packet: u16,
+}
+
+impl Realtime {
+    fn init(led_count: u16, start: u16, rgbw: bool, timeout: u8) -> Self {
+        let max_leds = if rgbw { DRGBW_MAX_LEDS } else { DRGB_MAX_LEDS };
+        let dnrgb = start > 0 || led_count > max_leds;
+        if dnrgb && led_count > DNRGB_MAX_LEDS {
+            let packets = led_count.div_ceil(DNRGB_MAX_LEDS);
+            warn!(
+                "{led_count} LEDs don't fit into one packet, they are split into {packets} packets"
+            );
+        }
+        if rgbw && led_count > max_leds {
+            warn!("White LEDs are not used for more than {max_leds} LEDs");
+        }
+        Realtime {
+            rgbw: rgbw && !dnrgb,
+            dnrgb,
+            timeout,
+            start,
+            leds_per_packet: if dnrgb {
+                DNRGB_MAX_LEDS
+            } else {
+                led_count.max(1)
+            },
+        }
+    }
+
+    fn channels(&self) -> usize {
+        3 + usize::from(self.rgbw)
+    }
+
+    fn header(&self, offset: u16) -> Vec<u8> {
+        match (self.dnrgb, self.rgbw) {
+            (false, false) => vec![0x02, self.timeout],
+            (false, true) => vec![0x03, self.timeout],
+            (true, _) => {
+                let [high, low] = (self.start + offset).to_be_bytes();
+                vec![0x04, self.timeout, high, low]
+            }
+        }
+    }
+
+    /// Size of a full packet, longer data is split into packets of this size
+    fn packet_size(&self) -> usize {
+        self.header(0).len() + self.leds_per_packet as usize * self.channels()
+    }
+
+    /// Writes `leds` with `channels` bytes per LED into as many packets as needed
+    fn put(&self, bytes: &mut BytesMut, leds: &[u8]) {
+        let packet_leds = self.leds_per_packet as usize * self.channels();
+        for (i, chunk) in leds.chunks(packet_leds).enumerate() {
+            bytes.put_slice(&self.header(i as u16 * self.leds_per_packet));
+            bytes.put_slice(chunk);
+        }
+    }
+
+    /// All `led_count` LEDs off
+    fn blackout(&self, buffer: &BytesMut, led_count: u16) -> Bytes {
+        let mut bytes = buffer.clone();
+        bytes.clear();
+        self.put(&mut bytes, &vec![0; led_count as usize * self.channels()]);
+        bytes.into()
+    }
+}
+
+/// Sends the polled data as consecutive realtime packets
+struct WledSocket {
+    socket: UdpSocket,
+    packet_size: usize,
+}
+
+impl Writeable for WledSocket {
+    async fn write_data(&mut self, data: &Bytes) -> std::io::Result<()> {
+        for packet in data.chunks(self.packet_size) {
+            self.socket.send(packet).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Closeable for WledSocket {
+    async fn close_connection(&mut self) {}
+}
+
+impl Stream for WledSocket {}
+
+/// Time the ambient effect takes to fade in after the timeout
+const AMBIENT_FADE_IN: Duration = Duration::from_secs(2);
+/// Factor the loudest bar decays by every frame, sets the full height of the bars
+const BAR_PEAK_DECAY: f32 = 0.995;
+
+/// Rainbow slowly moving across the strip while no onsets are detected
+#[derive(Debug)]
+struct Ambient {
+    animation: AnimationHelper<u64>,
+    period: u64,
+    timeout: Duration,
+    brightness: f32,
+}
+
+impl Ambient {
+    fn init(settings: &OnsetSettings) -> Self {
+        let period = (settings.ambient_period.as_millis() as u64).max(1);
+        let mut animation = AnimationHelper::init(|position| position, period, true);
+        animation.start();
+        Ambient {
+            animation,
+            period,
+            timeout: settings.ambient_timeout,
+            brightness: settings.ambient_brightness.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Colors of `led_count` LEDs, black until `timeout` has passed since the last onset
+    fn colors(&self, led_count: usize, last_onset: Instant) -> Vec<[u8; 3]> {
+        let idle_time = last_onset.elapsed().saturating_sub(self.timeout);
+        let fade_in = (idle_time.as_secs_f32() / AMBIENT_FADE_IN.as_secs_f32()).min(1.0);
+        if fade_in == 0.0 {
+            return vec![[0; 3]; led_count];
+        }
+
+        let offset = self.animation.get_value() as f32 / self.period as f32 * 360.0;
+        (0..led_count)
+            .map(|i| {
+                let hue = offset + i as f32 / led_count as f32 * 360.0;
+                color_downsample(hsv_to_rgb(&[hue, 1.0, self.brightness * fade_in]))
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -88,10 +330,54 @@ struct OnsetState {
     drum_envelope: DynamicDecay,
     note_envelope: DynamicDecay,
     hihat_envelope: FixedDecay,
-    prefix: Vec<u8>,
+    mix_mode: MixMode,
+    ambient: Option<Ambient>,
+    last_onset: Instant,
+    realtime: Realtime,
     buffer: BytesMut,
 }
 
+/// How overlapping drum, note and hihat colors are combined
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum MixMode {
+    #[default]
+    RgbAdd,
+    /// Keeps the hue of the most saturated and brightest contribution
+    HsvMax,
+    MaxChannel,
+}
+
+impl MixMode {
+    fn mix(&self, colors: &[[u8; 3]]) -> [u8; 3] {
+        match self {
+            MixMode::RgbAdd => colors.iter().fold([0; 3], |acc, c| {
+                [
+                    acc[0].saturating_add(c[0]),
+                    acc[1].saturating_add(c[1]),
+                    acc[2].saturating_add(c[2]),
+                ]
+            }),
+            MixMode::MaxChannel => colors.iter().fold([0; 3], |acc, c| {
+                [acc[0].max(c[0]), acc[1].max(c[1]), acc[2].max(c[2])]
+            }),
+            MixMode::HsvMax => {
+                let hsv: Vec<[f32; 3]> = colors
+                    .iter()
+                    .map(|c| rgb_to_hsv(color_upsample(*c)))
+                    .collect();
+                let brightness = hsv.iter().map(|c| c[2]).fold(0.0, f32::max);
+                let Some(dominant) = hsv
+                    .iter()
+                    .max_by(|a, b| (a[1] * a[2]).total_cmp(&(b[1] * b[2])))
+                else {
+                    return [0; 3];
+                };
+                color_downsample(hsv_to_rgb(&[dominant[0], dominant[1], brightness]))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct OnsetSettings {
@@ -103,6 +389,23 @@ pub struct OnsetSettings {
     pub brightness: f32,
     pub timeout: u8,
     pub polling_rate: f64,
+    /// React to the onsets of a single input channel instead of the mixed down signal,
+    /// requires `per_channel` in the audio processing settings
+    pub channel: Option<usize>,
+    pub mix_mode: MixMode,
+    /// Show a moving rainbow after `ambient_timeout` without onsets
+    pub ambient: bool,
+    pub ambient_brightness: f32,
+    #[serde(rename = "AmbientTimeout")]
+    pub ambient_timeout: Duration,
+    /// Time the rainbow takes to move once across the strip
+    #[serde(rename = "AmbientPeriod")]
+    pub ambient_period: Duration,
+    #[serde(flatten)]
+    pub reactions: Reactions,
+    /// Envelope every onset band triggers
+    #[serde(rename = "Routing")]
+    pub routing: Routing,
 }
 
 impl Default for OnsetSettings {
@@ -115,27 +418,46 @@ impl Default for OnsetSettings {
             brightness: 1.0,
             timeout: 2,
             polling_rate: 50.0,
+            channel: None,
+            mix_mode: MixMode::RgbAdd,
+            ambient: false,
+            ambient_brightness: 0.3,
+            ambient_timeout: Duration::from_secs(5),
+            ambient_period: Duration::from_secs(10),
+            reactions: Reactions::default(),
+            routing: Routing::default(),
         }
     }
 }
 
 impl OnsetState {
-    pub fn init(led_count: u16, rgbw: bool, brightness: f32, timeout: u8) -> Self {
-        let prefix = if rgbw {
-            vec![0x03, timeout]
-        } else {
-            vec![0x02, timeout]
+    fn trigger(&mut self, route: Route, strength: f32) {
+        // The strip has no fullband envelope
+        match route {
+            Route::Drum => self.drum_envelope.trigger(strength),
+            Route::Hihat => self.hihat_envelope.trigger(strength),
+            Route::Note => self.note_envelope.trigger(strength),
+            Route::Full | Route::Off => return,
         };
-        let channels = 3 + usize::from(rgbw);
-        let buffer = BytesMut::with_capacity(prefix.len() + led_count as usize * channels);
+        self.last_onset = Instant::now();
+    }
+
+    pub fn init(led_count: u16, start_led: u16, rgbw: bool, settings: &OnsetSettings) -> Self {
+        let realtime = Realtime::init(led_count, start_led, rgbw, settings.timeout);
+        let rgbw = realtime.rgbw;
+        let packets = led_count.div_ceil(realtime.leds_per_packet).max(1) as usize;
+        let buffer = BytesMut::with_capacity(packets * realtime.packet_size());
         OnsetState {
             led_count,
             rgbw,
-            drum_envelope: DynamicDecay::init(2.0),
-            note_envelope: DynamicDecay::init(4.0),
-            hihat_envelope: FixedDecay::init(Duration::from_millis(200)),
-            prefix,
-            brightness,
+            drum_envelope: DynamicDecay::init(settings.drum_decay_rate),
+            note_envelope: DynamicDecay::init(settings.note_decay_rate),
+            hihat_envelope: FixedDecay::init(settings.hihat_decay),
+            mix_mode: settings.mix_mode,
+            ambient: settings.ambient.then(|| Ambient::init(settings)),
+            last_onset: Instant::now(),
+            realtime,
+            brightness: settings.brightness,
             buffer,
         }
     }
@@ -146,8 +468,6 @@ impl Pollable for OnsetState {
         let mut bytes = self.buffer.clone();
         bytes.clear();
 
-        bytes.put_slice(&self.prefix);
-
         let red = self.drum_envelope.get_value() * self.led_count as f32 * 0.5;
         let blue = self.note_envelope.get_value() * self.led_count as f32 * 0.5;
         let white = self.hihat_envelope.get_value() * self.led_count as f32 * 0.2;
@@ -157,6 +477,10 @@ impl Pollable for OnsetState {
         } else {
             vec![vec![0, 0, 0]; self.led_count as usize / 2]
         };
+        let ambient = match &self.ambient {
+            Some(ambient) => ambient.colors(colors.len(), self.last_onset),
+            None => vec![[0; 3]; colors.len()],
+        };
 
         for (i, color) in &mut colors.iter_mut().enumerate() {
             let r =
@@ -169,29 +493,36 @@ impl Pollable for OnsetState {
                 .round() as u8;
 
             if self.rgbw {
-                *color = vec![r, 0, b, w];
+                let [r, g, b] = self.mix_mode.mix(&[ambient[i], [r, 0, 0], [0, 0, b]]);
+                *color = vec![r, g, b, w];
             } else {
-                *color = vec![r.saturating_add(w), w, b.saturating_add(w)];
+                *color = self
+                    .mix_mode
+                    .mix(&[ambient[i], [r, 0, 0], [0, 0, b], [w, w, w]])
+                    .to_vec();
             }
         }
         let mut reversed = colors.clone();
         reversed.reverse();
         reversed.extend(colors);
-        for colors in reversed {
-            bytes.put_slice(&colors);
-        }
+        self.realtime.put(&mut bytes, &reversed.concat());
 
         bytes.into()
     }
+
+    fn blackout(&self) -> Option<Bytes> {
+        Some(self.realtime.blackout(&self.buffer, self.led_count))
+    }
 }
 
 impl LEDStripOnset {
     pub async fn connect(ip: &str) -> Result<LEDStripOnset, WLEDError> {
-        Self::connect_with_settings(ip, OnsetSettings::default()).await
+        Self::connect_with_settings(ip, None, OnsetSettings::default()).await
     }
 
     pub async fn connect_with_settings(
         ip: &str,
+        segment: Option<&SegmentSelector>,
         settings: OnsetSettings,
     ) -> Result<LEDStripOnset, WLEDError> {
         #[derive(Debug, Serialize, Deserialize)]
@@ -210,21 +541,27 @@ impl LEDStripOnset {
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(settings.timeout as u64))
             .build()?;
-        let url = format!("http://{}/json/info", ip);
+        let url = format!("http://{}/json/info", url_host(ip));
         let resp = client.get(&url).send().await?;
         let info: Info = resp.json().await?;
         info!("Found strip {}", info.name);
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect((ip, info.udpport)).await?;
+        let segment = driven_segment(&client, ip, info.leds.count, segment).await?;
+        let segment_leds = (segment.stop - segment.start) as u16;
+
+        let socket = connect_udp((ip, info.udpport)).await?;
         debug!("Bound: {}", socket.local_addr().unwrap());
 
-        let state = OnsetState::init(
-            info.leds.count,
-            info.leds.rgbw && settings.white_led,
-            1.0,
-            settings.timeout,
-        );
+        let white_led = info.leds.rgbw && settings.white_led;
+        if white_led && segment.start > 0 {
+            warn!("White LEDs are not used for segments that don't start at the first LED");
+        }
+
+        let state = OnsetState::init(segment_leds, segment.start as u16, white_led, &settings);
+        let socket = WledSocket {
+            socket,
+            packet_size: state.realtime.packet_size(),
+        };
 
         let state = Arc::new(Mutex::new(state));
 
@@ -238,33 +575,58 @@ impl LEDStripOnset {
                 led_count: info.leds.count,
                 ip: ip.to_string(),
                 port: info.udpport,
-                segments: vec![Segment {
-                    start: 0,
-                    stop: info.leds.count as usize,
-                }],
+                segments: vec![segment],
                 rgbw: info.leds.rgbw,
             },
             polling_helper,
             state,
+            channel: settings.channel,
+            reactions: settings.reactions,
+            reaction_override: None,
+            routing: settings.routing,
         })
     }
+
+    fn trigger(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
+        let Some((route, strength)) = self.routing.route(&event) else {
+            return;
+        };
+        self.state.lock().unwrap().trigger(route, strength);
+    }
 }
 
 impl LightService for LEDStripOnset {
     fn process_onset(&mut self, event: Onset) {
-        let mut state = self.state.lock().unwrap();
-        match event {
-            Onset::Drum(strength) => {
-                state.drum_envelope.trigger(strength);
-            }
-            Onset::Hihat(strength) => {
-                state.hihat_envelope.trigger(strength);
-            }
-            Onset::Note(strength, _) => {
-                state.note_envelope.trigger(strength);
+        if self.channel.is_none() {
+            self.trigger(event);
+        }
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
+
+    fn shutdown(&mut self) {
+        self.polling_helper.shutdown();
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.polling_helper.last_write())
+    }
+
+    fn process_channel_onsets(&mut self, channel: usize, onsets: &[Onset]) {
+        if self.channel == Some(channel) {
+            for onset in onsets {
+                self.trigger(*onset);
             }
-            _ => {}
-        };
+        }
     }
 }
 
@@ -272,13 +634,69 @@ pub struct LEDStripSpectrum {
     strip: LEDStrip,
     polling_helper: PollingHelper,
     state: Arc<Mutex<SpectrumState>>,
+    reactions: Reactions,
+    reaction_override: Option<Reactions>,
+}
+
+/// Where the newest colors of the spectrum enter the strip
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum LedOrder {
+    /// At the first LED, moving to the end
+    Forward,
+    /// At the last LED, moving to the start
+    Reverse,
+    /// In the middle, moving outwards
+    #[default]
+    Center,
+    /// At both ends, moving to the middle
+    Mirror,
 }
 
+/// How the spectrum is drawn onto the strip
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd)]
+pub enum SpectrumMode {
+    /// The color of low, mid and high frequencies enters the strip and moves along it
+    #[default]
+    #[serde(alias = "Scrolling")]
+    Scroll,
+    /// Every band is a bar of its own color on a part of the strip, like a spectrum analyzer
+    Bars,
+}
+
+impl SpectrumSettings {
+    /// `order`, or the one of the old `center` flag if a config still sets it
+    fn led_order(&self) -> LedOrder {
+        let Some(center) = self.center else {
+            return self.order;
+        };
+        let order = if center {
+            LedOrder::Center
+        } else {
+            LedOrder::Forward
+        };
+        warn!("center is replaced by order, use order = \"{order:?}\" instead");
+        order
+    }
+}
+
+/// Frequency band of the bar mode
 #[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+pub struct SpectrumBand {
+    /// Upper edge of the band in Hz, the lower edge is the one of the previous band
+    pub max_frequency: f32,
+    pub color: [u8; 3],
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, PartialOrd)]
 #[serde(default)]
 pub struct SpectrumSettings {
+    /// Drive the white LEDs of RGBW strips with the luminance of the color
+    pub white_led: bool,
     pub leds_per_second: f64,
-    pub center: bool,
+    pub order: LedOrder,
+    /// Replaced by `order`, `true` is read as "Center" and `false` as "Forward"
+    #[serde(skip_serializing)]
+    pub center: Option<bool>,
     pub master_brightness: f32,
     pub min_brightness: f32,
     pub low_end_crossover: f32,
@@ -286,13 +704,33 @@ pub struct SpectrumSettings {
     pub polling_rate: f64,
     pub timeout: u8,
     pub onset_decay_rate: f32,
+    /// Spread the rounding error to the following LEDs to avoid banding in slow fades
+    pub dither: bool,
+    /// Color the LEDs slowly breathe in after `idle_timeout` without sound
+    pub idle_color: Option<[u8; 3]>,
+    #[serde(rename = "IdleTimeout")]
+    pub idle_timeout: Duration,
+    pub mode: SpectrumMode,
+    /// Bands of the bar mode from low to high, the last one reaches up to the Nyquist frequency
+    #[serde(rename = "Band")]
+    pub bands: Vec<SpectrumBand>,
+    /// Colors the fullband onsets cycle through, the spectrum is rotated by the hue
+    /// between the current and the first color
+    pub palette: Vec<[u16; 3]>,
+    /// Fullband onsets it takes to fade from one palette color to the next
+    pub palette_steps: u32,
+    /// Only the fullband onsets brighten the spectrum
+    #[serde(flatten)]
+    pub reactions: Reactions,
 }
 
 impl Default for SpectrumSettings {
     fn default() -> Self {
         Self {
+            white_led: false,
             leds_per_second: 100.0,
-            center: true,
+            order: LedOrder::Center,
+            center: None,
             master_brightness: 1.2,
             min_brightness: 0.25,
             low_end_crossover: 240.0,
@@ -300,17 +738,82 @@ impl Default for SpectrumSettings {
             polling_rate: 50.0,
             timeout: 2,
             onset_decay_rate: 6.0,
+            dither: false,
+            idle_color: None,
+            idle_timeout: Duration::from_secs(10),
+            mode: SpectrumMode::Scroll,
+            bands: default_bands(),
+            palette: vec![[u16::MAX, 0, 0]],
+            palette_steps: 1,
+            reactions: Reactions::default(),
         }
     }
 }
 
+fn default_bands() -> Vec<SpectrumBand> {
+    [
+        (60.0, [255, 0, 0]),
+        (250.0, [255, 96, 0]),
+        (500.0, [255, 255, 0]),
+        (2_000.0, [0, 255, 0]),
+        (6_000.0, [0, 64, 255]),
+        (20_000.0, [160, 0, 255]),
+    ]
+    .into_iter()
+    .map(|(max_frequency, color)| SpectrumBand {
+        max_frequency,
+        color,
+    })
+    .collect()
+}
+
+/// Low-pass at the upper edge of every band but the last, which is open to the top.
+/// Invalid edges are replaced by the ones of the default bands.
+fn band_filters(
+    bands: &[SpectrumBand],
+    sampling_frequency: f32,
+) -> Vec<DirectForm2Transposed<f32>> {
+    let nyquist = sampling_frequency / 2.0;
+    let edges: Vec<f32> = bands[..bands.len().saturating_sub(1)]
+        .iter()
+        .map(|band| band.max_frequency)
+        .collect();
+    let valid = edges.iter().all(|&edge| 0.0 < edge && edge < nyquist)
+        && edges.windows(2).all(|pair| pair[0] < pair[1]);
+    let edges = if valid {
+        edges
+    } else {
+        warn!("Band edges must be increasing and below {nyquist}Hz, using the default bands");
+        let defaults = default_bands();
+        defaults[..defaults.len() - 1]
+            .iter()
+            .map(|band| band.max_frequency)
+            .collect()
+    };
+    edges
+        .into_iter()
+        .map(|edge| {
+            DirectForm2Transposed::<f32>::new(
+                Coefficients::<f32>::from_params(
+                    Type::LowPass,
+                    sampling_frequency.hz(),
+                    edge.hz(),
+                    Q_BUTTERWORTH_F32,
+                )
+                .unwrap(),
+            )
+        })
+        .collect()
+}
+
 impl LEDStripSpectrum {
     pub async fn connect(ip: &str, sampling_rate: f32) -> Result<LEDStripSpectrum, WLEDError> {
-        Self::connect_with_settings(ip, sampling_rate, SpectrumSettings::default()).await
+        Self::connect_with_settings(ip, None, sampling_rate, SpectrumSettings::default()).await
     }
 
     pub async fn connect_with_settings(
         ip: &str,
+        segment: Option<&SegmentSelector>,
         sampling_rate: f32,
         settings: SpectrumSettings,
     ) -> Result<LEDStripSpectrum, WLEDError> {
@@ -330,29 +833,33 @@ impl LEDStripSpectrum {
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(settings.timeout as u64))
             .build()?;
-        let url = format!("http://{}/json/info", ip);
+        let url = format!("http://{}/json/info", url_host(ip));
         let resp = client.get(&url).send().await?;
         let info: Info = resp.json().await?;
         info!("Found strip {}", info.name);
 
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect((ip, info.udpport)).await?;
+        let segment = driven_segment(&client, ip, info.leds.count, segment).await?;
+        let segment_leds = (segment.stop - segment.start) as u16;
+
+        let socket = connect_udp((ip, info.udpport)).await?;
         debug!("Bound: {}", socket.local_addr().unwrap());
 
-        let samples_per_led = (sampling_rate as f64 / settings.leds_per_second).round() as u32;
+        let white_led = info.leds.rgbw && settings.white_led;
+        if white_led && segment.start > 0 {
+            warn!("White LEDs are not used for segments that don't start at the first LED");
+        }
 
         let state = SpectrumState::init(
             sampling_rate,
-            info.leds.count,
-            settings.master_brightness,
-            settings.min_brightness,
-            samples_per_led,
-            settings.onset_decay_rate,
-            settings.low_end_crossover,
-            settings.high_end_crossover,
-            settings.center,
-            settings.timeout,
+            segment_leds,
+            segment.start as u16,
+            white_led,
+            &settings,
         );
+        let socket = WledSocket {
+            socket,
+            packet_size: state.realtime.packet_size(),
+        };
 
         let state = Arc::new(Mutex::new(state));
 
@@ -366,14 +873,13 @@ impl LEDStripSpectrum {
                 led_count: info.leds.count,
                 ip: ip.to_string(),
                 port: info.udpport,
-                segments: vec![Segment {
-                    start: 0,
-                    stop: info.leds.count as usize,
-                }],
+                segments: vec![segment],
                 rgbw: info.leds.rgbw,
             },
             polling_helper,
             state,
+            reactions: settings.reactions,
+            reaction_override: None,
         })
     }
 }
@@ -385,25 +891,61 @@ impl LightService for LEDStripSpectrum {
     }
 
     fn process_onset(&mut self, event: Onset) {
+        if !self
+            .reaction_override
+            .unwrap_or(self.reactions)
+            .allows(&event)
+        {
+            return;
+        }
         let mut state = self.state.lock().unwrap();
         if let Onset::Full(strength) = event {
-            state.envelope.trigger(strength)
+            state.trigger(strength)
         }
     }
+
+    fn shutdown(&mut self) {
+        self.polling_helper.shutdown();
+    }
+
+    fn last_write(&self) -> Option<WriteStatus> {
+        Some(self.polling_helper.last_write())
+    }
+
+    fn set_reactions(&mut self, reactions: Option<Reactions>) {
+        self.reaction_override = reactions;
+    }
+
+    fn dim(&mut self, gain: f32) {
+        self.state.lock().unwrap().dim(gain);
+    }
 }
 
 pub struct SpectrumState {
     sample_buffer: VecDeque<f32>,
     colors: VecDeque<[u8; 3]>,
-    prefix: Vec<u8>,
+    realtime: Realtime,
     led_count: u16,
-    center: bool,
+    rgbw: bool,
+    order: LedOrder,
     master_brightness: f32,
+    dimming: f32,
     min_brightness: f32,
     samples_per_led: u32,
     low_pass_filter: DirectForm2Transposed<f32>,
     high_pass_filter: DirectForm2Transposed<f32>,
+    mode: SpectrumMode,
+    band_filters: Vec<DirectForm2Transposed<f32>>,
+    band_colors: Vec<[u8; 3]>,
+    band_levels: Vec<f32>,
+    /// Slowly decaying level of the loudest band, a bar at this level fills its part of the strip
+    bar_peak: f32,
     envelope: DynamicDecay,
+    palette: Palette,
+    /// Degrees the hue of every color is rotated by
+    hue_shift: f32,
+    dither_error: Option<[f32; 3]>,
+    idle: Option<Idle>,
     buffer: BytesMut,
 }
 
@@ -411,16 +953,18 @@ impl SpectrumState {
     pub fn init(
         sampling_frequency: f32,
         led_count: u16,
-        master_brightness: f32,
-        min_brightness: f32,
-        samples_per_led: u32,
-        onset_decay_rate: f32,
-        low_end_crossover: f32,
-        high_end_crossover: f32,
-        center: bool,
-        timeout: u8,
+        start_led: u16,
+        rgbw: bool,
+        settings: &SpectrumSettings,
     ) -> Self {
-        let prefix = vec![0x02, timeout];
+        let realtime = Realtime::init(led_count, start_led, rgbw, settings.timeout);
+        let rgbw = realtime.rgbw;
+        let samples_per_led = (sampling_frequency as f64 / settings.leds_per_second).round() as u32;
+        let (low_end_crossover, high_end_crossover) = crossovers(
+            settings.low_end_crossover,
+            settings.high_end_crossover,
+            sampling_frequency,
+        );
         let low_pass = DirectForm2Transposed::<f32>::new(
             Coefficients::<f32>::from_params(
                 Type::LowPass,
@@ -439,24 +983,121 @@ impl SpectrumState {
             )
             .unwrap(),
         );
-        let bytes = BytesMut::with_capacity(prefix.len() + led_count as usize * 3);
+        let bands = if settings.bands.is_empty() {
+            default_bands()
+        } else {
+            settings.bands.clone()
+        };
+        let band_filters = band_filters(&bands, sampling_frequency);
+        let band_colors: Vec<[u8; 3]> = if band_filters.len() + 1 == bands.len() {
+            bands.iter().map(|band| band.color).collect()
+        } else {
+            default_bands().iter().map(|band| band.color).collect()
+        };
+        let packets = led_count.div_ceil(realtime.leds_per_packet).max(1) as usize;
+        let bytes = BytesMut::with_capacity(packets * realtime.packet_size());
         Self {
             sample_buffer: VecDeque::new(),
             colors: VecDeque::from(vec![[0, 0, 0]; led_count as usize]),
-            prefix,
+            realtime,
             led_count,
-            center,
-            master_brightness,
-            min_brightness,
+            rgbw,
+            order: settings.led_order(),
+            master_brightness: settings.master_brightness,
+            dimming: 1.0,
+            min_brightness: settings.min_brightness,
             samples_per_led,
             low_pass_filter: low_pass,
             high_pass_filter: high_pass,
-            envelope: DynamicDecay::init(onset_decay_rate),
+            mode: settings.mode,
+            band_filters,
+            band_levels: vec![0.0; band_colors.len()],
+            band_colors,
+            bar_peak: 0.0,
+            envelope: DynamicDecay::init(settings.onset_decay_rate),
+            palette: Palette::init(
+                settings.palette.clone(),
+                settings.palette_steps,
+                [u16::MAX, 0, 0],
+            ),
+            hue_shift: 0.0,
+            dither_error: settings.dither.then_some([0.0; 3]),
+            idle: settings
+                .idle_color
+                .map(|color| Idle::init(color.map(f32::from), settings.idle_timeout)),
             buffer: bytes,
         }
     }
 
     pub fn visualize_spectrum(&mut self, samples: &[f32]) {
+        if let Some(idle) = &mut self.idle {
+            idle.update(samples.iter().all(|&s| s == 0.0));
+        }
+        match self.mode {
+            SpectrumMode::Scroll => self.scroll_spectrum(samples),
+            SpectrumMode::Bars => self.draw_bars(samples),
+        }
+    }
+
+    fn brightness(&self) -> f32 {
+        // Set a minimum quarter brightness
+        ((self.envelope.get_value() * (1.0 - self.min_brightness)) + self.min_brightness)
+            * self.master_brightness
+            * self.dimming
+    }
+
+    /// Splits `samples` into the bands and draws one bar per band, from low to high
+    fn draw_bars(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+
+        self.measure_bands(samples);
+        let loudest = self.band_levels.iter().fold(0.0_f32, |max, &l| max.max(l));
+        self.bar_peak = (self.bar_peak * BAR_PEAK_DECAY).max(loudest);
+        let brightness = self.brightness().clamp(0.0, 1.0);
+
+        let bands = self.band_levels.len();
+        let led_count = self.led_count as usize;
+        for (i, (level, color)) in self.band_levels.iter().zip(&self.band_colors).enumerate() {
+            let (start, end) = (i * led_count / bands, (i + 1) * led_count / bands);
+            let height = if self.bar_peak > f32::EPSILON {
+                level / self.bar_peak
+            } else {
+                0.0
+            };
+            let lit = start + (height * (end - start) as f32).round() as usize;
+            let lit_color = self
+                .shift_hue(*color)
+                .map(|c| (c as f32 * brightness).round() as u8);
+            for led in start..end {
+                self.colors[led] = if led < lit { lit_color } else { [0, 0, 0] };
+            }
+        }
+    }
+
+    /// RMS level of every band of `samples`, the bands are the differences of the low-passes
+    fn measure_bands(&mut self, samples: &[f32]) {
+        self.band_levels.fill(0.0);
+        for &sample in samples {
+            let mut below = 0.0;
+            for (i, level) in self.band_levels.iter_mut().enumerate() {
+                let low_passed = match self.band_filters.get_mut(i) {
+                    Some(filter) => filter.run(sample),
+                    None => sample,
+                };
+                let band = low_passed - below;
+                *level += band * band;
+                below = low_passed;
+            }
+        }
+        for level in self.band_levels.iter_mut() {
+            *level = (*level / samples.len() as f32).sqrt();
+        }
+    }
+
+    /// Moves the color of the low, mid and high frequencies along the strip
+    fn scroll_spectrum(&mut self, samples: &[f32]) {
         self.sample_buffer.extend(samples);
         let n = self.sample_buffer.len() / self.samples_per_led as usize;
         self.sample_buffer.make_contiguous();
@@ -486,20 +1127,22 @@ impl SpectrumState {
 
             let max = low_weight.max(mid_weight.max(highs_weight));
 
-            let brightness = ((self.envelope.get_value() * (1.0 - self.min_brightness))
-                + self.min_brightness)
-                * self.master_brightness; // Set a minimum quarter brightness
+            let brightness = self.brightness();
 
-            let rgb = [
-                (low_weight / max * 255.0 * brightness) as u8,
-                (mid_weight / max * 255.0 * brightness) as u8,
-                (highs_weight / max * 255.0 * brightness) as u8,
-            ];
+            // Silence has no color, dividing by it would only produce NaNs
+            let rgb = if max > f32::EPSILON {
+                let rgb = [low_weight, mid_weight, highs_weight]
+                    .map(|w| ((w / max * brightness).clamp(0.0, 1.0) * u16::MAX as f32) as u16);
 
-            let rgb = color_upsample(rgb);
-            let [h, _, v] = rgb_to_hsv(rgb);
-            let rgb = hsv_to_rgb(&[h, 1.0, v]);
-            let rgb = color_downsample(rgb);
+                let [h, _, v] = rgb_to_hsv(rgb);
+                hsv_to_rgb(&[h + self.hue_shift, 1.0, v])
+            } else {
+                [0, 0, 0]
+            };
+            let rgb = match &mut self.dither_error {
+                Some(error) => color_downsample_dithered(rgb, error),
+                None => color_downsample(rgb),
+            };
 
             self.colors.pop_front();
             self.colors.push_back(rgb);
@@ -509,35 +1152,235 @@ impl SpectrumState {
     }
 }
 
-impl Pollable for SpectrumState {
-    fn poll(&self) -> Bytes {
-        let mut bytes = self.buffer.clone();
-        bytes.clear();
-        bytes.put_slice(&self.prefix);
+impl SpectrumState {
+    pub fn trigger(&mut self, strength: f32) {
+        self.envelope.trigger(strength);
+        let color = self.palette.advance();
+        self.hue_shift = color_to_hue(&color) - color_to_hue(&self.palette.first());
+    }
 
-        if !self.center {
-            for color in self.colors.iter().rev() {
-                bytes.put_slice(color);
-            }
-        } else {
-            for color in self
+    /// Rotates the hue of a band color by the one of the palette
+    fn shift_hue(&self, color: [u8; 3]) -> [u8; 3] {
+        if self.hue_shift == 0.0 {
+            return color;
+        }
+        let [h, s, v] = rgb_to_hsv(color_upsample(color));
+        color_downsample(hsv_to_rgb(&[h + self.hue_shift, s, v]))
+    }
+
+    /// Scales the brightness on top of `master_brightness`
+    pub fn dim(&mut self, gain: f32) {
+        self.dimming = gain;
+    }
+
+    /// Colors in the order of the LEDs, the newest colors are placed according to `order`
+    pub fn led_colors(&self) -> Vec<[u8; 3]> {
+        let first_half = (self.led_count / 2 + self.led_count % 2) as usize;
+        let second_half = (self.led_count / 2) as usize;
+        let colors: Vec<&[u8; 3]> = match self.order {
+            // Bars always go from the low to the high frequencies
+            _ if self.mode == SpectrumMode::Bars => match self.order {
+                LedOrder::Reverse => self.colors.iter().rev().collect(),
+                _ => self.colors.iter().collect(),
+            },
+            LedOrder::Forward => self.colors.iter().rev().collect(),
+            LedOrder::Reverse => self.colors.iter().collect(),
+            LedOrder::Center => self
                 .colors
                 .iter()
                 .rev()
-                .take((self.led_count / 2 + self.led_count % 2) as usize)
+                .take(first_half)
                 .rev()
                 .chain(
                     self.colors
                         .iter()
                         .rev()
                         .skip((self.led_count % 2) as usize)
-                        .take((self.led_count / 2) as usize),
+                        .take(second_half),
                 )
-            {
-                bytes.put_slice(color);
+                .collect(),
+            LedOrder::Mirror => self
+                .colors
+                .iter()
+                .rev()
+                .take(first_half)
+                .chain(self.colors.iter().rev().take(second_half).rev())
+                .collect(),
+        };
+
+        match &self.idle {
+            Some(idle) => colors
+                .into_iter()
+                .map(|c| idle.blend(c.map(f32::from)).map(|c| c.round() as u8))
+                .collect(),
+            None => colors.into_iter().copied().collect(),
+        }
+    }
+}
+
+impl Pollable for SpectrumState {
+    fn poll(&self) -> Bytes {
+        let mut bytes = self.buffer.clone();
+        bytes.clear();
+
+        let mut leds = Vec::with_capacity(self.led_count as usize * self.realtime.channels());
+        for color in self.led_colors() {
+            leds.extend_from_slice(&color);
+            if self.rgbw {
+                let [r, g, b] = color.map(f32::from);
+                leds.push((0.2126 * r + 0.7152 * g + 0.0722 * b).round() as u8);
             }
         }
+        self.realtime.put(&mut bytes, &leds);
 
         bytes.into()
     }
+
+    fn blackout(&self) -> Option<Bytes> {
+        Some(self.realtime.blackout(&self.buffer, self.led_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::lights::DETECTED_ONSETS;
+
+    /// Red channel of every LED in the sent packet, the newest color has the highest value
+    fn led_order(led_count: u16, order: LedOrder) -> Vec<u8> {
+        let settings = SpectrumSettings {
+            order,
+            ..Default::default()
+        };
+        let mut state = SpectrumState::init(48000.0, led_count, 0, false, &settings);
+        state.colors = (1..=led_count as u8).map(|i| [i, 0, 0]).collect();
+        let bytes = state.poll();
+        bytes[bytes.len() - 3 * led_count as usize..]
+            .iter()
+            .step_by(3)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn led_orders_of_an_odd_strip() {
+        assert_eq!(led_order(5, LedOrder::Forward), [5, 4, 3, 2, 1]);
+        assert_eq!(led_order(5, LedOrder::Reverse), [1, 2, 3, 4, 5]);
+        assert_eq!(led_order(5, LedOrder::Center), [3, 4, 5, 4, 3]);
+        assert_eq!(led_order(5, LedOrder::Mirror), [5, 4, 3, 4, 5]);
+    }
+
+    #[test]
+    fn led_orders_of_an_even_strip() {
+        assert_eq!(led_order(4, LedOrder::Forward), [4, 3, 2, 1]);
+        assert_eq!(led_order(4, LedOrder::Reverse), [1, 2, 3, 4]);
+        assert_eq!(led_order(4, LedOrder::Center), [3, 4, 4, 3]);
+        assert_eq!(led_order(4, LedOrder::Mirror), [4, 3, 3, 4]);
+    }
+
+    #[test]
+    fn silence_is_black() {
+        for mode in [SpectrumMode::Scroll, SpectrumMode::Bars] {
+            let settings = SpectrumSettings {
+                mode,
+                ..Default::default()
+            };
+            let mut state = SpectrumState::init(48000.0, 10, 0, false, &settings);
+            state.colors = vec![[255, 255, 255]; 10].into();
+            state.visualize_spectrum(&vec![0.0; 480 * 10]);
+            assert!(state.colors.iter().all(|&color| color == [0, 0, 0]));
+        }
+    }
+
+    #[test]
+    fn spectrum_state_follows_the_settings() {
+        let settings = SpectrumSettings {
+            leds_per_second: 300.0,
+            order: LedOrder::Mirror,
+            master_brightness: 0.8,
+            min_brightness: 0.5,
+            timeout: 5,
+            mode: SpectrumMode::Bars,
+            ..Default::default()
+        };
+        let state = SpectrumState::init(48000.0, 10, 0, false, &settings);
+        assert_eq!(state.samples_per_led, 160);
+        assert_eq!(state.order, LedOrder::Mirror);
+        assert_eq!(state.mode, SpectrumMode::Bars);
+        assert_eq!(state.realtime.timeout, 5);
+        assert!((state.brightness() - 0.4).abs() < 1e-6);
+
+        assert_eq!(crossovers(100.0, 4000.0, 48000.0), (100.0, 4000.0));
+        assert_eq!(crossovers(100.0, 30000.0, 48000.0), (240.0, 2400.0));
+        assert_eq!(crossovers(4000.0, 100.0, 48000.0), (240.0, 2400.0));
+    }
+
+    #[test]
+    fn onset_state_follows_the_settings() {
+        let settings = OnsetSettings {
+            drum_decay_rate: 1000.0,
+            hihat_decay: Duration::from_millis(1),
+            brightness: 0.5,
+            timeout: 7,
+            mix_mode: MixMode::MaxChannel,
+            ..Default::default()
+        };
+        let mut state = OnsetState::init(10, 0, false, &settings);
+        assert_eq!(state.brightness, 0.5);
+        assert_eq!(state.mix_mode, MixMode::MaxChannel);
+        assert_eq!(state.realtime.timeout, 7);
+
+        state.drum_envelope.trigger(1.0);
+        state.hihat_envelope.trigger(1.0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(state.drum_envelope.get_value(), 0.0);
+        assert_eq!(state.hihat_envelope.get_value(), 0.0);
+    }
+
+    #[test]
+    fn every_detected_onset_reaches_the_strips() {
+        // Fullband onsets light the onset strip once they are routed to one of its envelopes
+        let routing = Routing {
+            full: Route::Drum,
+            ..Default::default()
+        };
+        for onset in DETECTED_ONSETS {
+            let mut state = OnsetState::init(10, 0, false, &OnsetSettings::default());
+            let (route, strength) = routing.route(&onset).unwrap();
+            state.trigger(route, strength);
+            let value = state
+                .drum_envelope
+                .get_value()
+                .max(state.hihat_envelope.get_value())
+                .max(state.note_envelope.get_value());
+            assert!(value > 0.0, "{onset:?} left the onset strip dark");
+
+            // The spectrum follows the samples, only fullband onsets flash it
+            if let Onset::Full(strength) = onset {
+                let settings = SpectrumSettings::default();
+                let mut state = SpectrumState::init(48000.0, 10, 0, false, &settings);
+                let idle = state.brightness();
+                state.trigger(strength);
+                assert!(state.brightness() > idle);
+            }
+        }
+    }
+
+    #[test]
+    fn scrolling_is_read_as_scroll() {
+        let settings: SpectrumSettings = toml::from_str("mode = \"Scrolling\"").unwrap();
+        assert_eq!(settings.mode, SpectrumMode::Scroll);
+        let settings: SpectrumSettings = toml::from_str("mode = \"Scroll\"").unwrap();
+        assert_eq!(settings.mode, SpectrumMode::Scroll);
+    }
+
+    #[test]
+    fn old_center_flag_sets_the_order() {
+        let settings: SpectrumSettings = toml::from_str("center = false").unwrap();
+        assert_eq!(settings.led_order(), LedOrder::Forward);
+        let settings: SpectrumSettings = toml::from_str("center = true").unwrap();
+        assert_eq!(settings.led_order(), LedOrder::Center);
+        let settings: SpectrumSettings = toml::from_str("order = \"Mirror\"").unwrap();
+        assert_eq!(settings.led_order(), LedOrder::Mirror);
+    }
 }
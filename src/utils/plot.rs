@@ -9,21 +9,37 @@ use plotters::{
     style::{AsRelative, Color, Palette, Palette99, BLACK, RED, WHITE},
 };
 
-use super::audioprocessing::Onset;
+use super::{audioprocessing::Onset, lights::serialize::OnsetContainer};
 
-const TIME_WINDOW: u128 = 10000;
+/// Milliseconds from the start of a capture that are plotted by default
+pub const DEFAULT_TIME_WINDOW: u128 = 10000;
+
+pub fn plot_container(
+    container: &OnsetContainer,
+    time_window: u128,
+    file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    plot(
+        &container.data,
+        &container.raw,
+        container.time_interval(),
+        time_window,
+        file,
+    )
+}
 
 pub fn plot(
     onsets: &HashMap<String, Vec<(u128, Onset)>>,
     raw_data: &[f32],
     time_resolution: u32,
+    time_window: u128,
     file: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let root = BitMapBackend::new(&file, (1920, 1080)).into_drawing_area();
 
     root.fill(&WHITE)?;
 
-    let max = (raw_data.len() as u128 * time_resolution as u128).min(TIME_WINDOW);
+    let max = (raw_data.len() as u128 * time_resolution as u128).min(time_window);
 
     let mut circle_chart = ChartBuilder::on(&root)
         .set_label_area_size(LabelAreaPosition::Bottom, (4).percent())
@@ -58,16 +74,17 @@ pub fn plot(
             (
                 key.to_string(),
                 vec.iter()
-                    .filter(|(t, _)| *t < TIME_WINDOW)
+                    .filter(|(t, _)| *t < time_window)
                     .filter(|(t, _)| *t > 20) // Start is usually a unwanted click
                     .map(|(_, event)| event)
-                    .map(|event| match event {
+                    .filter_map(|event| match event {
                         Onset::Full(y)
                         | Onset::Atmosphere(y, _)
                         | Onset::Note(y, _)
                         | Onset::Drum(y)
                         | Onset::Hihat(y)
-                        | Onset::Raw(y) => *y,
+                        | Onset::Raw(y) => Some(*y),
+                        Onset::Timbre(_) | Onset::Pitch(_) | Onset::Pan(_) => None,
                     })
                     .fold(f32::EPSILON, f32::max),
             )
@@ -80,16 +97,17 @@ pub fn plot(
             .draw_series({
                 onsets[key]
                     .iter()
-                    .map(|(time, event)| match event {
+                    .filter_map(|(time, event)| match event {
                         Onset::Full(y)
                         | Onset::Atmosphere(y, _)
                         | Onset::Note(y, _)
                         | Onset::Drum(y)
                         | Onset::Hihat(y)
-                        | Onset::Raw(y) => (*time, *y),
+                        | Onset::Raw(y) => Some((*time, *y)),
+                        Onset::Timbre(_) | Onset::Pitch(_) | Onset::Pan(_) => None,
                     })
                     .map(|(time, y)| (time, y / data_max[key]))
-                    .filter(|(t, _)| *t < TIME_WINDOW)
+                    .filter(|(t, _)| *t < time_window)
                     .filter(|(t, _)| *t > 20) // Start is usually a unwanted click
                     .flat_map(|(t, v)| {
                         [
@@ -117,7 +135,7 @@ pub fn plot(
                 .iter()
                 .enumerate()
                 .map(|(t, y)| ((t as u32 * time_resolution + 20) as u128, y / raw_max * 0.5))
-                .filter(|(t, _)| *t < TIME_WINDOW),
+                .filter(|(t, _)| *t < time_window),
             &RED.mix(0.8),
         ))?
         .label("Onset function")
@@ -0,0 +1,150 @@
+use std::{f32::consts::PI, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, MissedTickBehavior};
+
+use super::{
+    audiodevices::monitor_callback,
+    audioprocessing::{OnsetDetector, ProcessingSettings},
+    lights::LightService,
+};
+
+/// Frequency of the sine in a click
+const CLICK_FREQUENCY: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, PartialOrd)]
+pub enum Signal {
+    /// Short decaying sine blips like a metronome
+    #[default]
+    Clicks,
+    /// Decaying white noise bursts
+    NoiseBursts,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct SimulationSettings {
+    #[serde(rename = "signal")]
+    pub signal: Signal,
+    /// Bursts per minute
+    #[serde(rename = "bpm")]
+    pub bpm: f32,
+    #[serde(rename = "amplitude")]
+    pub amplitude: f32,
+    #[serde(rename = "channels")]
+    pub channels: u16,
+    /// Print the time of every synthetic burst to compare it with the detected onsets
+    #[serde(rename = "print_onsets")]
+    pub print_onsets: bool,
+    pub burst_length: Duration,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            signal: Signal::Clicks,
+            bpm: 120.0,
+            amplitude: 0.5,
+            channels: 2,
+            print_onsets: true,
+            burst_length: Duration::from_millis(30),
+        }
+    }
+}
+
+/// Xorshift generator, good enough for white noise
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+struct SignalGenerator {
+    settings: SimulationSettings,
+    sample_rate: f32,
+    period: usize,
+    burst_length: usize,
+    position: usize,
+    noise: Noise,
+}
+
+impl SignalGenerator {
+    fn init(settings: SimulationSettings, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
+        Self {
+            settings,
+            sample_rate,
+            period: ((60.0 / settings.bpm.max(1.0)) * sample_rate) as usize,
+            burst_length: ((settings.burst_length.as_secs_f32() * sample_rate) as usize).max(1),
+            position: 0,
+            noise: Noise(0x9E37_79B9),
+        }
+    }
+
+    /// Fills `out` with interleaved samples
+    fn fill(&mut self, out: &mut [f32]) {
+        let channels = self.settings.channels.max(1) as usize;
+        for frame in out.chunks_mut(channels) {
+            let offset = self.position % self.period;
+            if offset == 0 && self.settings.print_onsets {
+                println!(
+                    "Synthetic onset at {:.3}s",
+                    self.position as f32 / self.sample_rate
+                );
+            }
+
+            let sample = if offset < self.burst_length {
+                let envelope = 1.0 - offset as f32 / self.burst_length as f32;
+                let value = match self.settings.signal {
+                    Signal::Clicks => {
+                        (2.0 * PI * CLICK_FREQUENCY * offset as f32 / self.sample_rate).sin()
+                    }
+                    Signal::NoiseBursts => self.noise.next(),
+                };
+                value * envelope * envelope * self.settings.amplitude
+            } else {
+                0.0
+            };
+            frame.fill(sample);
+            self.position += 1;
+        }
+    }
+}
+
+/// Feeds a synthetic signal to the onset detection in real time, no audio device is needed
+pub async fn simulate<D: OnsetDetector + Send + 'static>(
+    settings: SimulationSettings,
+    processing_settings: ProcessingSettings,
+    onset_detector: impl Fn() -> D,
+    lightservices: Vec<Box<dyn LightService + Send>>,
+) {
+    let channels = settings.channels.max(1);
+    let mut process = monitor_callback(
+        channels,
+        processing_settings,
+        onset_detector,
+        lightservices,
+        None,
+        None,
+    );
+    let mut generator = SignalGenerator::init(settings, processing_settings.sample_rate);
+
+    let hop_size = processing_settings.hop_size;
+    let mut samples = vec![0.0; hop_size * channels as usize];
+
+    let mut timer = interval(Duration::from_secs_f64(
+        hop_size as f64 / processing_settings.sample_rate as f64,
+    ));
+    timer.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    loop {
+        timer.tick().await;
+        generator.fill(&mut samples);
+        process(&samples);
+    }
+}